@@ -0,0 +1,80 @@
+//! Matching of white triplets at shorter IPv6 prefixes, for providers whose retries come from
+//! other addresses of a /48 than the first attempt.
+
+use crate::entry_id::EntryId;
+use crate::{mask_ip, App, Key, ListingStatus};
+
+/// The id a triplet entry is indexed with at the prefix length, which is shorter than the one of
+/// the entry id, None for other entries and IPv4 senders.
+fn aggregate_id(id: &EntryId, prefix: u8) -> Option<EntryId> {
+    match id {
+        EntryId::Triplet {
+            ip,
+            sender_email,
+            recipient_email,
+        } if ip.is_ipv6() => Some(EntryId::Triplet {
+            ip: mask_ip(*ip, prefix),
+            sender_email: sender_email.clone(),
+            recipient_email: recipient_email.clone(),
+        }),
+        _ => None,
+    }
+}
+
+impl App {
+    /// Index the white entry with the id at every aggregation prefix.
+    pub(crate) fn index_aggregates(&mut self, id: &EntryId) {
+        for prefix in self.config.data.ipv6_aggregate_prefixes.clone() {
+            if let Some(aggregate_id) = aggregate_id(id, prefix) {
+                self.white_aggregates
+                    .insert((prefix, aggregate_id), id.clone());
+            }
+        }
+    }
+
+    /// Index all white entries again, which drops the ones that were removed or changed.
+    pub(crate) fn rebuild_aggregates(&mut self) {
+        self.white_aggregates.clear();
+        if self.config.data.ipv6_aggregate_prefixes.is_empty() {
+            return;
+        }
+        let white = self
+            .triplets
+            .iter()
+            .filter(|(_, entry)| entry.listing_status == ListingStatus::White)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in white {
+            self.index_aggregates(&id);
+        }
+    }
+
+    /// For a triplet without a listed entry of its own, the id of a white entry of the same
+    /// sender and recipient within the first aggregation prefix that has one.
+    pub(crate) fn aggregate_match(&self, key: &Key) -> Option<EntryId> {
+        if self.white_aggregates.is_empty() {
+            return None;
+        }
+        let id = key.id(&self.config.data);
+        if self
+            .get_entry(key)
+            .is_some_and(|entry| entry.listing_status != ListingStatus::Grey)
+        {
+            return None;
+        }
+        self.config
+            .data
+            .ipv6_aggregate_prefixes
+            .iter()
+            .find_map(|prefix| {
+                let id = self
+                    .white_aggregates
+                    .get(&(*prefix, aggregate_id(&id, *prefix)?))?;
+                // The index is only rebuilt on prune, the entry may have changed meanwhile
+                self.triplets
+                    .get(id)
+                    .filter(|entry| entry.listing_status == ListingStatus::White)
+                    .map(|_| id.clone())
+            })
+    }
+}
@@ -0,0 +1,389 @@
+//! Command line client for the greylistd socket, like the greylist tool of the Python package.
+
+use anyhow::anyhow;
+use greylistd::config::Config;
+use greylistd::parser::Request;
+use greylistd::App;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: greylist [--config <path>] [--offline [--data-dir <dir>]] <command> [<options>] [<arguments>]
+
+Commands are sent to greylistd as given, e.g.:
+  greylist add --black 192.0.2.1 sender@example.org recipient@example.com
+  greylist check --white 192.0.2.1 sender@example.org recipient@example.com
+  greylist list --white
+  greylist stats
+
+Checks with a status like --white exit with 0 for true and 1 for false.
+
+  greylist stats --servers <socket or tcp://host:port>,...
+
+queries the statistics of several daemons and prints their sum.
+
+  greylist --offline [--data-dir <dir>] list --white
+
+answers check, status, list, stats, mrtg, subnet and dump from the data files while
+greylistd is down, without changing them. --data-dir reads them from another directory,
+e.g. a copy of the data directory.";
+
+/// Exit code for errors, checks with a status exit with 0 or 1 for their answer.
+const EXIT_ERROR: u8 = 2;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("greylist: {}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run() -> Result<ExitCode, anyhow::Error> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut config_path = PathBuf::from("/etc/greylistd/config");
+    let mut offline = false;
+    let mut data_dir = None;
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--config") => {
+                args.next();
+                config_path = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow!("Missing value for --config\n{}", USAGE))?;
+            }
+            Some("--offline") => {
+                args.next();
+                offline = true;
+            }
+            Some("--data-dir") => {
+                args.next();
+                data_dir = Some(
+                    args.next()
+                        .map(PathBuf::from)
+                        .ok_or_else(|| anyhow!("Missing value for --data-dir\n{}", USAGE))?,
+                );
+            }
+            Some("-h" | "--help") => {
+                println!("{}", USAGE);
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ => break,
+        }
+    }
+    if data_dir.is_some() && !offline {
+        return Err(anyhow!("--data-dir requires --offline\n{}", USAGE));
+    }
+    if args.peek().is_none() {
+        return Err(anyhow!("Missing command\n{}", USAGE));
+    }
+
+    let args = args.collect::<Vec<_>>();
+    if let ["stats", "--servers", servers] = args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        return merged_stats(servers);
+    }
+    let request = args
+        .join(" ")
+        .parse::<Request>()
+        .map_err(|e| anyhow!("{}\n{}", e, USAGE))?;
+    let config = Config::load(&config_path)?;
+    let answer = if offline {
+        offline_answer(config, data_dir.as_deref(), &request)?
+    } else {
+        daemon_answer(config, &request)?
+    };
+    let answer = answer.as_str();
+    println!("{}", answer);
+
+    if request.is_query() {
+        return match answer {
+            "true" => Ok(ExitCode::SUCCESS),
+            "false" => Ok(ExitCode::FAILURE),
+            _ => Ok(ExitCode::from(EXIT_ERROR)),
+        };
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Send the request to the daemon and return its answer, without the banner and response code.
+fn daemon_answer(config: Config, request: &Request) -> Result<String, anyhow::Error> {
+    let answer = send(&Server::Unix(config.socket.path), request)?;
+    let answer = match answer.split_once('\n') {
+        Some((banner, answer)) if config.socket.banner && banner.starts_with("greylistd ") => {
+            answer
+        }
+        _ => &answer,
+    };
+    let mut answer = answer.trim_end();
+    if config.socket.response_codes {
+        let (code, rest) = answer.split_once(' ').unwrap_or((answer, ""));
+        match code.parse::<u16>() {
+            // An unseen entry is a regular answer, like without response codes
+            Ok(200 | 404) => answer = rest,
+            Ok(_) => return Err(anyhow!("{}", rest)),
+            Err(_) => return Err(anyhow!("Invalid answer: {}", answer)),
+        }
+    }
+    Ok(answer.to_string())
+}
+
+/// Answer the request from the data files. The configured files are refused while the daemon
+/// still answers on its socket: it keeps the changes since its last save in memory and replaces
+/// the files when saving, so the answer could be outdated or mix two saves.
+fn offline_answer(
+    mut config: Config,
+    data_dir: Option<&Path>,
+    request: &Request,
+) -> Result<String, anyhow::Error> {
+    let configured_dir = config.data_dir().to_path_buf();
+    if let Some(dir) = data_dir {
+        config.override_data_dir(dir);
+    }
+    let same_dir = configured_dir.canonicalize().ok() == config.data_dir().canonicalize().ok();
+    if same_dir && UnixStream::connect(&config.socket.path).is_ok() {
+        return Err(anyhow!(
+            "greylistd is running on {} and may be writing {}, send the request without --offline",
+            config.socket.path.display(),
+            configured_dir.display()
+        ));
+    }
+    let mut answer = Vec::new();
+    App::offline(config, request, &mut answer)?;
+    Ok(String::from_utf8_lossy(&answer).trim_end().to_string())
+}
+
+/// A daemon to send requests to, by its UNIX domain socket or as `tcp://host:port`.
+enum Server {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl Server {
+    fn parse(server: &str) -> Server {
+        match server.strip_prefix("tcp://") {
+            Some(address) => Server::Tcp(address.to_string()),
+            None => Server::Unix(PathBuf::from(server)),
+        }
+    }
+}
+
+impl Display for Server {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Server::Unix(path) => write!(f, "{}", path.display()),
+            Server::Tcp(address) => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+/// The part of the `stats --json` answer that is summed over several daemons.
+#[derive(Default, Deserialize)]
+struct Stats {
+    uptime: u64,
+    white: ListCount,
+    grey: ListCount,
+    black: ListCount,
+    quarantine: ListCount,
+    became_whitelisted: u32,
+    expired_from_greylist: u32,
+    would_pass: u32,
+    quarantined: u32,
+    blacklisted: u32,
+}
+
+#[derive(Default, Deserialize)]
+struct ListCount {
+    items: u32,
+    requests: u32,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        for (sum, count) in [
+            (&mut self.white, &other.white),
+            (&mut self.grey, &other.grey),
+            (&mut self.black, &other.black),
+            (&mut self.quarantine, &other.quarantine),
+        ] {
+            sum.items += count.items;
+            sum.requests += count.requests;
+        }
+        self.became_whitelisted += other.became_whitelisted;
+        self.expired_from_greylist += other.expired_from_greylist;
+        self.would_pass += other.would_pass;
+        self.quarantined += other.quarantined;
+        self.blacklisted += other.blacklisted;
+    }
+}
+
+/// Query the statistics of every server and print their sum, followed by the lists of each
+/// server. Servers that can't be queried are reported and left out of the sum.
+fn merged_stats(servers: &str) -> Result<ExitCode, anyhow::Error> {
+    let request = "stats --json".parse::<Request>()?;
+    let servers = servers
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        .map(Server::parse)
+        .collect::<Vec<_>>();
+    if servers.is_empty() {
+        return Err(anyhow!("Missing servers\n{}", USAGE));
+    }
+    let results = servers
+        .iter()
+        .map(|server| {
+            let answer = send(server, &request)?;
+            let answer = json_answer(&answer)?;
+            serde_json::from_str::<Stats>(answer).map_err(|e| anyhow!("Invalid statistics: {}", e))
+        })
+        .collect::<Vec<_>>();
+    let mut total = Stats::default();
+    let mut failed = 0;
+    for (server, result) in servers.iter().zip(&results) {
+        match result {
+            Ok(stats) => total.add(stats),
+            Err(e) => {
+                eprintln!("greylist: {}: {}", server, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed == servers.len() {
+        return Err(anyhow!("No server could be queried"));
+    }
+
+    println!(
+        "Statistics of {} of {} servers",
+        servers.len() - failed,
+        servers.len()
+    );
+    println!();
+    for (name, count) in [
+        ("whitelisted", &total.white),
+        ("greylisted", &total.grey),
+        ("blacklisted", &total.black),
+        ("quarantined", &total.quarantine),
+    ] {
+        println!(
+            "{} items, matching {} requests, are currently {}",
+            count.items, count.requests, name
+        );
+    }
+    println!();
+    let white = total.became_whitelisted;
+    let expired = total.expired_from_greylist;
+    let previous_grey = white.saturating_add(expired);
+    if previous_grey == 0 {
+        println!("No greylisted items have been whitelisted or expired yet");
+    } else {
+        println!("Of {} items that were initially greylisted:", previous_grey);
+        println!(
+            " - {} ({:.1}%) became whitelisted",
+            white,
+            white as f64 * 100.0 / previous_grey as f64
+        );
+        println!(
+            " - {} ({:.1}%) expired from the greylist",
+            expired,
+            expired as f64 * 100.0 / previous_grey as f64
+        );
+    }
+    println!();
+    println!(
+        "{} checks found greylisted items that an update would have whitelisted",
+        total.would_pass
+    );
+    println!("{} items were put into quarantine", total.quarantined);
+    println!("{} items were put on the blacklist", total.blacklisted);
+    println!();
+    for (server, result) in servers.iter().zip(&results) {
+        match result {
+            Ok(stats) => println!(
+                "{}: {} white, {} grey, {} black, {} quarantined, up {}s",
+                server,
+                stats.white.items,
+                stats.grey.items,
+                stats.black.items,
+                stats.quarantine.items,
+                stats.uptime
+            ),
+            Err(_) => println!("{}: failed", server),
+        }
+    }
+
+    Ok(if failed > 0 {
+        ExitCode::from(EXIT_ERROR)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// The JSON document of an answer, without the banner and response code the server may send.
+fn json_answer(answer: &str) -> Result<&str, anyhow::Error> {
+    let answer = match answer.split_once('\n') {
+        Some((banner, answer)) if banner.starts_with("greylistd ") => answer,
+        _ => answer,
+    };
+    let answer = answer.trim();
+    if answer.starts_with('{') {
+        return Ok(answer);
+    }
+    match answer.split_once(' ') {
+        Some(("200", json)) => Ok(json),
+        Some((code, rest)) if code.parse::<u16>().is_ok() => Err(anyhow!("{}", rest)),
+        _ => Err(anyhow!("Invalid answer: {}", answer)),
+    }
+}
+
+/// Send the request and read the answer, until the daemon closes the connection.
+fn send(server: &Server, request: &Request) -> Result<String, anyhow::Error> {
+    match server {
+        Server::Unix(socket) => {
+            let mut stream = connect(socket)?;
+            stream.write_all(request.to_string().as_bytes())?;
+            stream.shutdown(Shutdown::Write)?;
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer)?;
+            Ok(answer)
+        }
+        Server::Tcp(address) => {
+            let mut stream = TcpStream::connect(address)
+                .map_err(|e| anyhow!("Failed to connect to {}: {}", address, e))?;
+            stream.write_all(request.to_string().as_bytes())?;
+            stream.shutdown(Shutdown::Write)?;
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer)?;
+            Ok(answer)
+        }
+    }
+}
+
+fn connect(socket: &Path) -> Result<UnixStream, anyhow::Error> {
+    UnixStream::connect(socket).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => anyhow!(
+            "The socket {} doesn't exist, is greylistd running?",
+            socket.display()
+        ),
+        ErrorKind::ConnectionRefused => anyhow!(
+            "Connection to {} refused, is greylistd running?",
+            socket.display()
+        ),
+        ErrorKind::PermissionDenied => anyhow!(
+            "Permission denied for {}, run greylist as root or the greylistd user",
+            socket.display()
+        ),
+        _ => anyhow!("Failed to connect to {}: {}", socket.display(), e),
+    })
+}
@@ -0,0 +1,91 @@
+//! Deferral of periodic saves while requests arrive in bursts, so writing the data files doesn't
+//! compete with mail delivery. The deferred save happens as soon as the rate drops again, or
+//! after saveMaxDelay at the latest.
+
+use crate::App;
+use crossbeam_channel::{never, tick, Receiver};
+use std::time::{Duration, Instant};
+
+/// Requests are counted per second over this many seconds.
+const WINDOW_SECONDS: usize = 10;
+
+/// How often a deferred save checks whether the burst is over.
+const QUIET_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of requests in each of the last seconds.
+pub(crate) struct RequestRate {
+    start: Instant,
+    buckets: [u32; WINDOW_SECONDS],
+    /// Second since start of the last counted request
+    last: u64,
+}
+
+impl RequestRate {
+    pub(crate) fn new() -> RequestRate {
+        RequestRate {
+            start: Instant::now(),
+            buckets: [0; WINDOW_SECONDS],
+            last: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, now: Instant) {
+        let second = self.advance(now);
+        self.buckets[second as usize % WINDOW_SECONDS] += 1;
+    }
+
+    /// Average requests per second over the window.
+    pub(crate) fn per_second(&mut self, now: Instant) -> u32 {
+        self.advance(now);
+        self.buckets.iter().sum::<u32>() / WINDOW_SECONDS as u32
+    }
+
+    /// Clear the buckets of the seconds that passed since the last request.
+    fn advance(&mut self, now: Instant) -> u64 {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        let passed = second.saturating_sub(self.last);
+        for offset in 1..=passed.min(WINDOW_SECONDS as u64) {
+            self.buckets[(self.last + offset) as usize % WINDOW_SECONDS] = 0;
+        }
+        self.last = self.last.max(second);
+        second
+    }
+}
+
+impl App {
+    /// Fires while a save is deferred or may be, never if saves aren't deferred while busy.
+    pub(crate) fn quiet_timer(&self) -> Receiver<Instant> {
+        if self.config.data.save_busy_rate.is_some() {
+            tick(QUIET_CHECK_INTERVAL)
+        } else {
+            never()
+        }
+    }
+
+    /// Whether a periodic save should be put off, as requests arrive faster than saveBusyRate
+    /// and the save hasn't been deferred for saveMaxDelay yet.
+    pub(crate) fn defer_save(&mut self) -> bool {
+        let Some(busy_rate) = self.config.data.save_busy_rate else {
+            return false;
+        };
+        let now = Instant::now();
+        let rate = self.request_rate.per_second(now);
+        match self.deferred_save {
+            _ if rate < busy_rate => false,
+            Some(since) if now.duration_since(since) >= self.config.data.save_max_delay => {
+                log::info!(
+                    "Saving despite {} requests/s, saves were deferred for {}s",
+                    rate,
+                    now.duration_since(since).as_secs()
+                );
+                false
+            }
+            Some(_) => true,
+            None => {
+                log::info!("Deferring saves while busy with {} requests/s", rate);
+                self.deferred_save = Some(now);
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::entry_id::Fnv;
+use crate::Triplet;
+use std::net::IpAddr;
+
+/// Number of index bits, the counter has 2^12 registers for a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Approximate count of distinct values in constant memory (HyperLogLog).
+pub(crate) struct DistinctCounter {
+    registers: Vec<u8>,
+}
+
+impl DistinctCounter {
+    pub(crate) fn new() -> DistinctCounter {
+        DistinctCounter {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, value: &[u8]) {
+        let mut hasher = Fnv::new();
+        hasher.write(value);
+        let hash = mix(hasher.finish());
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The sentinel bit limits the rank to the remaining bits of the hash
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-i32::from(*rank)))
+            .sum::<f64>();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small counts
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+/// Finalizer of splitmix64, FNV alone doesn't spread short inputs over all bits.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Approximate numbers of distinct sender IPs, sender domains and recipients in updates.
+pub(crate) struct Diversity {
+    pub(crate) sender_ips: DistinctCounter,
+    pub(crate) sender_domains: DistinctCounter,
+    pub(crate) recipients: DistinctCounter,
+}
+
+impl Diversity {
+    pub(crate) fn new() -> Diversity {
+        Diversity {
+            sender_ips: DistinctCounter::new(),
+            sender_domains: DistinctCounter::new(),
+            recipients: DistinctCounter::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, triplet: &Triplet) {
+        match triplet.sender_ip {
+            IpAddr::V4(ip) => self.sender_ips.insert(&ip.octets()),
+            IpAddr::V6(ip) => self.sender_ips.insert(&ip.octets()),
+        }
+        if let Some((_, domain)) = triplet
+            .sender_email
+            .as_deref()
+            .and_then(|sender| sender.rsplit_once('@'))
+        {
+            self.sender_domains.insert(domain.as_bytes());
+        }
+        self.recipients.insert(triplet.recipient_email.as_bytes());
+    }
+}
@@ -0,0 +1,114 @@
+//! Numeric response codes of protocol version 3, so clients can tell the outcome of a request
+//! without matching the English answer texts.
+//!
+//! Answers are prefixed with the code, e.g. `200 white` or `404 unseen`. Failed requests
+//! additionally get a fixed token before their message, e.g.
+//! `400 bad-triplet Invalid IP address in triplet: x`.
+
+use std::io::Write;
+
+/// The outcome of a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ResponseCode {
+    /// The request was answered
+    Ok,
+    /// The entry of the key isn't stored
+    Unseen,
+    /// The triplet or network of the request is invalid
+    BadTriplet,
+    /// The request couldn't be parsed
+    BadRequest,
+    /// The request selects a custom list that isn't configured
+    UnknownList,
+    /// The request contains binary data
+    Binary,
+    /// The request is longer than allowed
+    TooLong,
+    /// The command isn't allowed for the client
+    Denied,
+    /// The request was refused as it would invalidate the stored data
+    Refused,
+    /// The command failed, e.g. a reload with an invalid configuration
+    Failed,
+    /// The data is still being loaded
+    Unavailable,
+    /// Too many connections are waiting to be handled
+    Busy,
+}
+
+impl ResponseCode {
+    pub(crate) fn code(&self) -> u16 {
+        match self {
+            ResponseCode::Ok => 200,
+            ResponseCode::Unseen => 404,
+            ResponseCode::BadTriplet
+            | ResponseCode::BadRequest
+            | ResponseCode::UnknownList
+            | ResponseCode::Binary => 400,
+            ResponseCode::TooLong => 413,
+            ResponseCode::Denied => 403,
+            ResponseCode::Refused => 409,
+            ResponseCode::Failed => 500,
+            ResponseCode::Unavailable | ResponseCode::Busy => 503,
+        }
+    }
+
+    /// The token written after the code of failed requests.
+    fn token(&self) -> Option<&'static str> {
+        match self {
+            ResponseCode::Ok => None,
+            ResponseCode::Unseen => Some("unseen"),
+            ResponseCode::BadTriplet => Some("bad-triplet"),
+            ResponseCode::BadRequest => Some("bad-request"),
+            ResponseCode::UnknownList => Some("unknown-list"),
+            ResponseCode::Binary => Some("binary"),
+            ResponseCode::TooLong => Some("too-long"),
+            ResponseCode::Denied => Some("denied"),
+            ResponseCode::Refused => Some("refused"),
+            ResponseCode::Failed => Some("failed"),
+            ResponseCode::Unavailable => Some("unavailable"),
+            ResponseCode::Busy => Some("busy"),
+        }
+    }
+}
+
+/// How a request was handled.
+pub(crate) struct Handled {
+    pub(crate) code: ResponseCode,
+    /// Whether the configuration and data are reloaded after answering
+    pub(crate) reload: bool,
+}
+
+impl Handled {
+    pub(crate) fn answered(code: ResponseCode) -> Handled {
+        Handled {
+            code,
+            reload: false,
+        }
+    }
+}
+
+/// Write the answer, prefixed with its code if response codes are enabled. The token of failed
+/// requests isn't repeated if the answer is just the token.
+pub(crate) fn write_answer(
+    writer: &mut impl Write,
+    code: Option<ResponseCode>,
+    answer: &[u8],
+) -> Result<(), anyhow::Error> {
+    let Some(code) = code else {
+        writer.write_all(answer)?;
+        return Ok(());
+    };
+    write!(writer, "{}", code.code())?;
+    if let Some(token) = code.token() {
+        write!(writer, " {}", token)?;
+        if answer == token.as_bytes() {
+            return Ok(());
+        }
+    }
+    if !answer.is_empty() {
+        writer.write_all(b" ")?;
+        writer.write_all(answer)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Format a timestamp in the local timezone as "YYYY-MM-DD HH:MM:SS".
+pub(crate) fn format_local_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let t = secs as libc::time_t;
+    // SAFETY: localtime_r only writes to the provided tm struct, which is valid for writes.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Format a duration for humans, e.g. "3 days, 2 hours, 5 minutes".
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let parts = [
+        (secs / 86400, "day"),
+        (secs / 3600 % 24, "hour"),
+        (secs / 60 % 60, "minute"),
+        (secs % 60, "second"),
+    ];
+    let parts = parts
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{} {}{}", value, unit, if *value == 1 { "" } else { "s" }))
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        "0 seconds".to_string()
+    } else {
+        parts.join(", ")
+    }
+}
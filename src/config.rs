@@ -1,25 +1,286 @@
-use crate::serde_utils::{deserialize_bool, deserialize_duration_seconds};
-use serde::Deserialize;
-use serde_ini::from_read;
+use crate::serde_utils::{
+    deserialize_bool, deserialize_duration_seconds, deserialize_list,
+    deserialize_optional_duration_seconds, parse_duration,
+};
+use crate::{email_key, ListingStatus, Network};
+use anyhow::anyhow;
+use log::LevelFilter;
+use serde::{de, Deserialize, Deserializer};
+use serde_ini::Deserializer as IniDeserializer;
+use serde_plain::derive_deserialize_from_fromstr;
+use std::ffi::CString;
 use std::fs::File;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
-#[derive(Deserialize)]
+/// Name the file and the key a parse error occurred at.
+fn parse_error(
+    path: &Path,
+    error: serde_path_to_error::Error<serde_ini::de::Error>,
+) -> anyhow::Error {
+    // serde_ini displays its errors in debug format
+    let message = match error.inner() {
+        serde_ini::de::Error::Custom(message) => message.clone(),
+        inner => inner.to_string(),
+    };
+    if error.path().iter().next().is_none() {
+        anyhow!("Invalid configuration {}: {}", path.display(), message)
+    } else {
+        anyhow!(
+            "Invalid configuration {}, key {}: {}",
+            path.display(),
+            error.path(),
+            message
+        )
+    }
+}
+
+macro_rules! diff_fields {
+    ($changes:ident, $old:expr, $new:expr, $section:literal, { $($field:ident => $key:literal),* $(,)? }) => {
+        $(
+            if $old.$field != $new.$field {
+                $changes.push(ConfigChange {
+                    key: concat!($section, ".", $key),
+                    old: format!("{:?}", $old.$field),
+                    new: format!("{:?}", $new.$field),
+                });
+            }
+        )*
+    };
+}
+
+#[derive(Clone, Deserialize)]
 pub struct Config {
     pub(crate) timeouts: Timeouts,
     pub socket: Socket,
     pub(crate) data: Data,
+    #[serde(default)]
+    pub(crate) notify: Notify,
+    #[serde(default)]
+    pub(crate) policy: Policy,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub(crate) postfix: Postfix,
+    #[serde(default)]
+    pub(crate) feeds: Feeds,
+    #[serde(default)]
+    pub(crate) lists: Lists,
+    #[serde(default)]
+    pub(crate) export: Export,
+    #[serde(default)]
+    pub(crate) roles: Roles,
+    #[serde(default)]
+    pub(crate) report: Report,
+    #[serde(default)]
+    pub(crate) transcript: Transcript,
+
+    /// Path the configuration was loaded from, used to re-read it on reload.
+    #[serde(skip)]
+    pub(crate) path: PathBuf,
+    /// Socket path given on the command line, which replaces the configured one.
+    #[serde(skip)]
+    socket_override: Option<PathBuf>,
 }
 
 impl Config {
-    pub fn load(file: &str) -> Result<Config, anyhow::Error> {
-        let file = File::open(file)?;
-        Ok(from_read::<_, Config>(file)?)
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, anyhow::Error> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open configuration {}: {}", path.display(), e))?;
+        let mut deserializer = IniDeserializer::from_read(file);
+        let mut config: Config = serde_path_to_error::deserialize(&mut deserializer)
+            .map_err(|e| parse_error(path, e))?;
+        config.path = path.to_path_buf();
+        config
+            .timeouts
+            .check()
+            .and_then(|_| config.data.check())
+            .map_err(|e| anyhow!("Invalid configuration {}: {}", path.display(), e))?;
+        config.data.email_key = config
+            .data
+            .email_key_file
+            .as_deref()
+            .map(email_key::load_key)
+            .transpose()?;
+        Ok(config)
+    }
+
+    /// Load the configuration again from the same file, keeping the command line overrides.
+    pub fn reload(&self) -> Result<Config, anyhow::Error> {
+        let mut config = Config::load(&self.path)?;
+        if let Some(path) = &self.socket_override {
+            config.override_socket_path(path.clone());
+        }
+        Ok(config)
+    }
+
+    /// Use the given socket path instead of the configured one, also after a reload.
+    pub fn override_socket_path(&mut self, path: PathBuf) {
+        self.socket.path = path.clone();
+        self.socket_override = Some(path);
     }
+
+    /// Read the data files from the directory instead of the configured one, keeping their file
+    /// names, e.g. from a copy of /var/lib/greylistd. Data of the Python greylistd isn't
+    /// imported then.
+    pub fn override_data_dir(&mut self, dir: &Path) {
+        let data = &mut self.data;
+        for path in [&mut data.statefile, &mut data.tripletfile]
+            .into_iter()
+            .chain(data.subnetfile.as_mut())
+        {
+            if let Some(name) = path.file_name() {
+                *path = dir.join(name);
+            }
+        }
+        data.import_legacy = false;
+    }
+
+    /// The directory of the statefile.
+    pub fn data_dir(&self) -> &Path {
+        self.data.statefile.parent().unwrap_or(Path::new("."))
+    }
+
+    /// List the effective settings that differ between this and a newly loaded configuration.
+    pub(crate) fn diff(&self, new: &Config) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        diff_fields!(changes, self.timeouts, new.timeouts, "timeouts", {
+            retry_min => "retryMin",
+            retry_max => "retryMax",
+            expire => "expire",
+            quarantine_expire => "quarantineExpire",
+            white_expire => "whiteExpire",
+            black_expire => "blackExpire",
+            subnet_expire => "subnetExpire",
+        });
+        diff_fields!(changes, self.socket, new.socket, "socket", {
+            path => "path",
+            mode => "mode",
+            compat => "compat",
+            allow => "allow",
+            tcp_listen => "tcpListen",
+            tcp_allow => "tcpAllow",
+            tcp_proxy_protocol => "tcpProxyProtocol",
+            banner => "banner",
+            response_codes => "responseCodes",
+            tcp_response_codes => "tcpResponseCodes",
+            admin_users => "adminUsers",
+            admin_groups => "adminGroups",
+            queue_length => "queueLength",
+        });
+        diff_fields!(changes, self.data, new.data, "data", {
+            update => "update",
+            statefile => "statefile",
+            tripletfile => "tripletfile",
+            savetriplets => "savetriplets",
+            singlecheck => "singlecheck",
+            singleupdate => "singleupdate",
+            onlysubnet => "onlysubnet",
+            ipv4_mask_bits => "ipv4MaskBits",
+            ipv6_mask_bits => "ipv6MaskBits",
+            ipv6_aggregate_prefixes => "ipv6AggregatePrefixes",
+            sender_lowercase_domain => "senderLowercaseDomain",
+            sender_lowercase_local => "senderLowercaseLocal",
+            sender_strip_extension => "senderStripExtension",
+            sender_collapse_verp => "senderCollapseVerp",
+            email_key_file => "emailKeyFile",
+            import_legacy => "importLegacy",
+            save_busy_rate => "saveBusyRate",
+            save_max_delay => "saveMaxDelay",
+            subnetfile => "subnetfile",
+            shards => "shards",
+        });
+        diff_fields!(changes, self.notify, new.notify, "notify", {
+            important => "important",
+            before => "before",
+            hook => "hook",
+        });
+        diff_fields!(changes, self.policy, new.policy, "policy", {
+            maintenance => "maintenance",
+            loading_answer => "loadingAnswer",
+            fail_open => "failOpen",
+            recidivist_retry_min => "recidivistRetryMin",
+            recidivist_attempts => "recidivistAttempts",
+            promotion_attempts => "promotionAttempts",
+            promotion_spacing => "promotionSpacing",
+            pending_white => "pendingWhite",
+            request_budget_ms => "requestBudgetMs",
+        });
+        diff_fields!(changes, self.log, new.log, "log", {
+            rate_limit => "rateLimit",
+            file => "file",
+            target => "target",
+            level => "level",
+        });
+        diff_fields!(changes, self.postfix, new.postfix, "postfix", {
+            white_action => "whiteAction",
+            grey_action => "greyAction",
+            black_action => "blackAction",
+            quarantine_action => "quarantineAction",
+            invalid_action => "invalidAction",
+        });
+        diff_fields!(changes, self.feeds, new.feeds, "feeds", {
+            sources => "sources",
+            refresh => "refresh",
+        });
+        diff_fields!(changes, self.lists, new.lists, "lists", {
+            custom => "custom",
+        });
+        diff_fields!(changes, self.export, new.export, "export", {
+            path => "path",
+            format => "format",
+            action => "action",
+        });
+        diff_fields!(changes, self.roles, new.roles, "roles", {
+            domains => "domains",
+            primary => "primary",
+            backup => "backup",
+            submission => "submission",
+        });
+        diff_fields!(changes, self.report, new.report, "report", {
+            interval => "interval",
+            path => "path",
+        });
+        diff_fields!(changes, self.transcript, new.transcript, "transcript", {
+            path => "path",
+            peers => "peers",
+            sample => "sample",
+        });
+        changes
+    }
+}
+
+pub(crate) struct ConfigChange {
+    pub(crate) key: &'static str,
+    pub(crate) old: String,
+    pub(crate) new: String,
 }
 
-#[derive(Deserialize)]
+impl ConfigChange {
+    /// Whether applying the change would orphan or merge the stored data, as entries are matched
+    /// by an id that depends on these settings or are stored at a different location.
+    pub(crate) fn is_unsafe(&self) -> bool {
+        matches!(
+            self.key,
+            "data.onlysubnet"
+                | "data.ipv4MaskBits"
+                | "data.ipv6MaskBits"
+                | "data.senderLowercaseDomain"
+                | "data.senderLowercaseLocal"
+                | "data.senderStripExtension"
+                | "data.senderCollapseVerp"
+                | "data.emailKeyFile"
+                | "data.statefile"
+                | "data.tripletfile"
+                | "data.subnetfile"
+        )
+    }
+}
+
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Timeouts {
     /// Initial delay before previously unknown triplets are allowed to pass
@@ -34,14 +295,84 @@ pub(crate) struct Timeouts {
     #[serde(deserialize_with = "deserialize_duration_seconds")]
     pub(crate) retry_max: Duration,
 
-    /// Lifetime of auto-whitelisted triplets that have allowed mail to pass
+    /// Lifetime of auto-whitelisted triplets that have allowed mail to pass, and of blacklisted
+    /// triplets unless whiteExpire or blackExpire are set
     /// Default is 60 days = 5,184,000 seconds
     #[serde(default = "_default_expire")]
     #[serde(deserialize_with = "deserialize_duration_seconds")]
     pub(crate) expire: Duration,
+
+    /// Lifetime of quarantined triplets that have not been seen
+    /// Default is the same as expire
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) quarantine_expire: Option<Duration>,
+
+    /// Lifetime of whitelisted triplets that have not been seen
+    /// Default is the same as expire
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) white_expire: Option<Duration>,
+
+    /// Lifetime of blacklisted triplets that have not been seen
+    /// Default is the same as expire
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) black_expire: Option<Duration>,
+
+    /// Lifetime of the retry history of subnets that have not been seen
+    /// Default is four times expire
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) subnet_expire: Option<Duration>,
 }
 
-#[derive(Deserialize)]
+impl Timeouts {
+    /// Reject windows in which a greylisted triplet could never become white, or a white one
+    /// would expire before greylisted ones.
+    fn check(&self) -> Result<(), anyhow::Error> {
+        if self.retry_min >= self.retry_max {
+            return Err(anyhow!(
+                "Option retryMin ({}s) must be less than retryMax ({}s)",
+                self.retry_min.as_secs(),
+                self.retry_max.as_secs()
+            ));
+        }
+        if self.expire < self.retry_max {
+            return Err(anyhow!(
+                "Option expire ({}s) must not be less than retryMax ({}s)",
+                self.expire.as_secs(),
+                self.retry_max.as_secs()
+            ));
+        }
+        if self.white_expire() < self.retry_max {
+            return Err(anyhow!(
+                "Option whiteExpire ({}s) must not be less than retryMax ({}s)",
+                self.white_expire().as_secs(),
+                self.retry_max.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn quarantine_expire(&self) -> Duration {
+        self.quarantine_expire.unwrap_or(self.expire)
+    }
+
+    pub(crate) fn white_expire(&self) -> Duration {
+        self.white_expire.unwrap_or(self.expire)
+    }
+
+    pub(crate) fn black_expire(&self) -> Duration {
+        self.black_expire.unwrap_or(self.expire)
+    }
+
+    pub(crate) fn subnet_expire(&self) -> Duration {
+        self.subnet_expire.unwrap_or(self.expire * 4)
+    }
+}
+
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Socket {
     /// Path to the UNIX domain socket on which greylistd will listen.
@@ -52,13 +383,206 @@ pub struct Socket {
     /// UNIX filemode of that socket.  See "chmod(1)" for the meaning of this.
     /// Default mode is 0660.
     pub mode: String,
+
+    /// Output format of command responses, "native" or "python".
+    /// With "python" timestamps and durations in the list and stats output are formatted
+    /// like the original greylistd does, for scripts parsing its output.
+    /// Default is "native".
+    #[serde(default)]
+    pub(crate) compat: Compat,
+
+    /// Comma separated list of commands that are accepted on this socket, e.g.
+    /// "check,update,status". Other commands are answered with "permission denied".
+    /// Default is to allow all commands.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_list")]
+    pub(crate) allow: Option<Vec<String>>,
+
+    /// Address and port to additionally accept connections on over TCP, e.g. "0.0.0.0:2501",
+    /// for several mail servers sharing one greylist. The protocol is the same as on the
+    /// UNIX domain socket.
+    /// Default is to only listen on the UNIX domain socket.
+    #[serde(default)]
+    pub tcp_listen: Option<SocketAddr>,
+
+    /// Comma separated list of networks TCP connections are accepted from, e.g.
+    /// "10.0.0.0/8, 192.168.1.0/24". Connections from other addresses are closed.
+    /// Default is to only accept connections from loopback addresses.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub(crate) tcp_allow: Option<Vec<Network>>,
+
+    /// Whether TCP connections start with a PROXY protocol header (version 1 or 2), as sent by
+    /// haproxy. The address of the client in the header is used for the per client statistics
+    /// and log messages, tcpAllow is checked against the address of the proxy. Connections
+    /// without a valid header are closed.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) tcp_proxy_protocol: bool,
+
+    /// Whether to greet every connection with a line like
+    /// "greylistd 0.1.0 proto=2 features=json,batch", so clients can detect what the server
+    /// supports. Clients that expect only the answer, like Exim's readsocket and Postfix,
+    /// don't understand the banner.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub banner: bool,
+
+    /// Whether to prefix answers on the UNIX domain socket with a numeric response code, e.g.
+    /// "200 white" or "400 bad-triplet ...", i.e. protocol version 3.
+    /// Default is "false", the plain answers of the original greylistd
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub response_codes: bool,
+
+    /// Like responseCodes, for connections on the TCP socket.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) tcp_response_codes: bool,
+
+    /// Comma separated list of users, by name or uid, that may run commands changing the data
+    /// or the daemon, like add, delete, clear, save and reload, on the UNIX domain socket.
+    /// Root and the user greylistd runs as always may, other clients only check and query.
+    /// Default is no further users.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_users")]
+    pub(crate) admin_users: Vec<u32>,
+
+    /// Like adminUsers for groups, by name or gid, matched against the primary group of the
+    /// connecting process.
+    /// Default is no groups.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_groups")]
+    pub(crate) admin_groups: Vec<u32>,
+
+    /// Accepted connections waiting to be handled at most, further connections are answered
+    /// with "busy" and closed, so a burst can't queue up unbounded memory.
+    /// Default is 1000
+    #[serde(default = "_default_queue_length")]
+    pub(crate) queue_length: usize,
+}
+
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Option<Vec<Network>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(list) = deserialize_list(deserializer)? else {
+        return Ok(None);
+    };
+    list.iter()
+        .map(|network| network.parse().map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
 }
 
-#[derive(Deserialize)]
+fn deserialize_prefixes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let list = deserialize_list(deserializer)?.unwrap_or_default();
+    list.iter()
+        .map(|prefix| {
+            prefix
+                .trim_start_matches('/')
+                .parse()
+                .map_err(|_| de::Error::custom(format!("Invalid prefix length: {}", prefix)))
+        })
+        .collect()
+}
+
+fn deserialize_users<'de, D>(deserializer: D) -> Result<Vec<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let list = deserialize_list(deserializer)?.unwrap_or_default();
+    list.iter()
+        .map(|user| lookup_id(user, IdKind::User).map_err(de::Error::custom))
+        .collect()
+}
+
+fn deserialize_groups<'de, D>(deserializer: D) -> Result<Vec<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let list = deserialize_list(deserializer)?.unwrap_or_default();
+    list.iter()
+        .map(|group| lookup_id(group, IdKind::Group).map_err(de::Error::custom))
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum IdKind {
+    User,
+    Group,
+}
+
+/// Resolve a user or group name from the system databases, numeric ids are taken as they are.
+fn lookup_id(name: &str, kind: IdKind) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let unknown = || match kind {
+        IdKind::User => format!("Unknown user: {}", name),
+        IdKind::Group => format!("Unknown group: {}", name),
+    };
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        // SAFETY: the entry, buffer and result pointers are valid for the duration of the call
+        // and buf.len() is the size of the buffer. The id is copied out before buf is dropped.
+        let (ret, id) = unsafe {
+            match kind {
+                IdKind::User => {
+                    let mut entry = std::mem::zeroed::<libc::passwd>();
+                    let mut result = std::ptr::null_mut();
+                    let ret = libc::getpwnam_r(
+                        c_name.as_ptr(),
+                        &mut entry,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    );
+                    (ret, (!result.is_null()).then_some(entry.pw_uid))
+                }
+                IdKind::Group => {
+                    let mut entry = std::mem::zeroed::<libc::group>();
+                    let mut result = std::ptr::null_mut();
+                    let ret = libc::getgrnam_r(
+                        c_name.as_ptr(),
+                        &mut entry,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    );
+                    (ret, (!result.is_null()).then_some(entry.gr_gid))
+                }
+            }
+        };
+        match ret {
+            // Groups with many members don't fit into the buffer
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 => return id.ok_or_else(unknown),
+            _ => return Err(unknown()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compat {
+    #[default]
+    Native,
+    Python,
+}
+
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Data {
-    /// Update interval -- save data to the filesystem if it has been more
-    /// than this many seconds (default 600) since the last save.
+    /// Update interval -- prune the data and save it to the filesystem every
+    /// this many seconds (default 600), if it changed since the last save.
     #[serde(default = "_default_update")]
     #[serde(deserialize_with = "deserialize_duration_seconds")]
     pub(crate) update: Duration,
@@ -76,6 +600,8 @@ pub(crate) struct Data {
     pub(crate) tripletfile: PathBuf,
 
     /// Whether or not to retain unhashed triplets, for the "list" command.
+    /// Without them, entries loaded from the state file are listed by their hash
+    /// and an existing tripletfile is removed on the next save.
     /// Default is "true"
     #[serde(default = "_default_true")]
     #[serde(deserialize_with = "deserialize_bool")]
@@ -84,7 +610,8 @@ pub(crate) struct Data {
     /// Whether check/update also checks for a whitelist entry, which only
     /// contains the first word of the triplet, that is the IP address usually.
     /// If set to true, you can also insert general IP addresses/networks into the
-    /// whitelist, without email addresses.
+    /// whitelist, without email addresses. The most specific matching entry is used,
+    /// with onlysubnet an IP address matches its whole subnet.
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_bool")]
     pub(crate) singlecheck: bool,
@@ -100,6 +627,634 @@ pub(crate) struct Data {
     #[serde(default = "_default_true")]
     #[serde(deserialize_with = "deserialize_bool")]
     pub(crate) onlysubnet: bool,
+
+    /// Prefix length of the subnet an IPv4 address is matched by with onlysubnet.
+    /// Default is 24
+    #[serde(default = "_default_ipv4_mask_bits")]
+    pub(crate) ipv4_mask_bits: u8,
+
+    /// Prefix length of the subnet an IPv6 address is matched by with onlysubnet.
+    /// Default is 64
+    #[serde(default = "_default_ipv6_mask_bits")]
+    pub(crate) ipv6_mask_bits: u8,
+
+    /// Comma separated list of IPv6 prefix lengths a triplet without a listed entry of its own
+    /// is matched at, in this order, e.g. "56, 48" for providers that send retries from other
+    /// addresses of a /48. A white entry of the same sender and recipient within the network
+    /// counts as a match. The prefixes must be shorter than the one entries are stored with,
+    /// ipv6MaskBits with onlysubnet.
+    /// Default is no aggregation.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_prefixes")]
+    pub(crate) ipv6_aggregate_prefixes: Vec<u8>,
+
+    /// Whether the domain of sender addresses is matched case insensitively, so
+    /// "foo@Example.COM" and "foo@example.com" share an entry. Stored triplets keep the
+    /// address as received.
+    /// Default is "true"
+    #[serde(default = "_default_true")]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) sender_lowercase_domain: bool,
+
+    /// Whether the local part of sender addresses is matched case insensitively as well.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) sender_lowercase_local: bool,
+
+    /// Whether a "+extension" of the local part of sender addresses is ignored, so
+    /// "user+tag1@" and "user+tag2@" share an entry.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) sender_strip_extension: bool,
+
+    /// Whether the parts of the local part of sender addresses that change with every message,
+    /// words with digits or "=" between "-", ".", "_" and "+" as in VERP and bounce addresses
+    /// like "bounce-12345-me=mydomain", are ignored, so retries of list mail match.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) sender_collapse_verp: bool,
+
+    /// Path of a file with a secret key, the sender and recipient addresses of triplets are then
+    /// stored and matched as their HMAC-SHA256 with it, while the IP addresses stay in the clear.
+    /// Entries stored with another key no longer match, entries stored without a key are
+    /// protected with it when they are loaded.
+    /// Default is storing the addresses as they are.
+    #[serde(default)]
+    pub(crate) email_key_file: Option<PathBuf>,
+
+    /// The key read from emailKeyFile
+    #[serde(skip)]
+    pub(crate) email_key: Option<Vec<u8>>,
+
+    /// Whether the data files are only read, for `greylist --offline`, so loading doesn't move
+    /// damaged shards aside
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+
+    /// Whether to import the data of the Python greylistd from /var/lib/greylistd when the
+    /// statefile and tripletfile don't exist yet, e.g. after switching to other paths.
+    /// Default is "false"
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) import_legacy: bool,
+
+    /// Requests per second, averaged over 10 seconds, from which periodic saves are deferred
+    /// until the rate drops again, so writing the data doesn't compete with mail delivery.
+    /// Changes made meanwhile are lost if the daemon is killed.
+    /// Default is to never defer saves.
+    #[serde(default)]
+    pub(crate) save_busy_rate: Option<u32>,
+
+    /// Longest time saves are deferred while busy, see saveBusyRate.
+    /// Default is 30 minutes = 1800 seconds
+    #[serde(default = "_default_save_max_delay")]
+    #[serde(deserialize_with = "deserialize_duration_seconds")]
+    pub(crate) save_max_delay: Duration,
+
+    /// Path to the file that keeps the retry history of each /24 IPv4 and /64 IPv6 subnet
+    /// (attempts, successes and the last outcome), which outlives the expired triplets.
+    /// Default is to not keep a history.
+    #[serde(default)]
+    pub(crate) subnetfile: Option<PathBuf>,
+
+    /// Number of files the entries are split into by their hash, e.g. "states.0" to
+    /// "states.15" next to the statefile and tripletfile, which only keep the statistics then.
+    /// The shards are written and loaded in parallel, and a damaged shard only loses its own
+    /// entries. Shards left over from a higher number are merged on the next load.
+    /// Default is 1, all entries are kept in the statefile and tripletfile.
+    #[serde(default = "_default_shards")]
+    pub(crate) shards: usize,
+}
+
+impl Data {
+    fn check(&self) -> Result<(), anyhow::Error> {
+        if self.ipv4_mask_bits > 32 {
+            return Err(anyhow!(
+                "Option ipv4MaskBits ({}) must not be greater than 32",
+                self.ipv4_mask_bits
+            ));
+        }
+        if self.ipv6_mask_bits > 128 {
+            return Err(anyhow!(
+                "Option ipv6MaskBits ({}) must not be greater than 128",
+                self.ipv6_mask_bits
+            ));
+        }
+        let stored_prefix = if self.onlysubnet {
+            self.ipv6_mask_bits
+        } else {
+            128
+        };
+        if let Some(prefix) = self
+            .ipv6_aggregate_prefixes
+            .iter()
+            .find(|prefix| **prefix >= stored_prefix)
+        {
+            return Err(anyhow!(
+                "Option ipv6AggregatePrefixes ({}) must be shorter than the stored prefix ({})",
+                prefix,
+                stored_prefix
+            ));
+        }
+        if !(1..=MAX_SHARDS).contains(&self.shards) {
+            return Err(anyhow!(
+                "Option shards ({}) must be between 1 and {}",
+                self.shards,
+                MAX_SHARDS
+            ));
+        }
+        Ok(())
+    }
+
+    /// The IPv4 and IPv6 prefix lengths addresses are matched by, none without onlysubnet.
+    pub(crate) fn subnet_prefixes(&self) -> Option<(u8, u8)> {
+        self.onlysubnet
+            .then_some((self.ipv4_mask_bits, self.ipv6_mask_bits))
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Policy {
+    /// Start in maintenance mode, answering every check and update with this status
+    /// ("white", "grey" or "black") without looking at or recording any data.
+    /// Can be changed at runtime with the "maintenance" command.
+    /// Default is no maintenance mode.
+    #[serde(default)]
+    pub(crate) maintenance: Option<ListingStatus>,
+
+    /// Status check and update are answered with while the data is loaded at startup.
+    /// Default is "white".
+    #[serde(default = "_default_loading_answer")]
+    pub(crate) loading_answer: ListingStatus,
+
+    /// Answer check and update with white instead of grey when looking up an entry fails
+    /// internally, e.g. because its stored time lies in the future.
+    /// Default is false.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) fail_open: bool,
+
+    /// Initial delay in seconds for senders from subnets (/24 for IPv4 and /64 for IPv6) that
+    /// repeatedly let their greylisted attempts expire, instead of retryMin. Expired attempts are
+    /// remembered until none happened for the expire time.
+    /// Default is no penalty.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) recidivist_retry_min: Option<Duration>,
+
+    /// Number of expired attempts from a subnet after which recidivistRetryMin applies.
+    /// Default is 3
+    #[serde(default = "_default_recidivist_attempts")]
+    pub(crate) recidivist_attempts: u32,
+
+    /// Number of distinct delivery attempts of a greylisted triplet, counting the first one,
+    /// after which it's whitelisted, instead of the first retry after retryMin. Attempts closer
+    /// than promotionSpacing to the last counted one count as the same attempt. All attempts
+    /// have to happen before retryMax, otherwise the count starts over.
+    /// Default is the first retry.
+    #[serde(default)]
+    pub(crate) promotion_attempts: Option<u32>,
+
+    /// Least time in seconds between two attempts that count separately for promotionAttempts.
+    /// Default is retryMin.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) promotion_spacing: Option<Duration>,
+
+    /// Answer the retry that would whitelist a greylisted triplet still with grey, and only mark
+    /// the triplet as pending white. The next attempt once retryMin has elapsed since the first
+    /// one whitelists it, so no mail passes on a single retry.
+    /// Default is false.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub(crate) pending_white: bool,
+
+    /// Longest time in milliseconds a check or update may have waited, e.g. in the queue of
+    /// accepted connections, before it is decided. Later requests are answered like a failed
+    /// lookup, white with failOpen and grey otherwise, without recording them, so the MTA gets
+    /// an answer before its own timeout. The first request of a connection is timed from when
+    /// the connection was accepted.
+    /// Default is no budget.
+    #[serde(default)]
+    pub(crate) request_budget_ms: Option<u64>,
+}
+
+impl Policy {
+    pub(crate) fn request_budget(&self) -> Option<Duration> {
+        self.request_budget_ms.map(Duration::from_millis)
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            maintenance: None,
+            loading_answer: _default_loading_answer(),
+            fail_open: false,
+            recidivist_retry_min: None,
+            recidivist_attempts: _default_recidivist_attempts(),
+            promotion_attempts: None,
+            promotion_spacing: None,
+            pending_white: false,
+            request_budget_ms: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Notify {
+    /// Comma separated list of patterns for important senders, matched against the sender
+    /// address and IP of whitelisted entries. "*" matches any number of characters,
+    /// e.g. "*@partner.example, 192.0.2.*".
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_list")]
+    pub(crate) important: Option<Vec<String>>,
+
+    /// Notify this many seconds before an important entry expires.
+    /// Default is 7 days = 604800 seconds
+    #[serde(default = "_default_notify_before")]
+    #[serde(deserialize_with = "deserialize_duration_seconds")]
+    pub(crate) before: Duration,
+
+    /// Program that is run for every important entry about to expire, with the entry and the
+    /// expiry time in seconds since the epoch as arguments.
+    /// Default is to not notify.
+    #[serde(default)]
+    pub(crate) hook: Option<PathBuf>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self {
+            important: None,
+            before: _default_notify_before(),
+            hook: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Log {
+    /// Maximum number of messages per minute for each kind of failure that clients can cause,
+    /// like invalid commands. Further messages are only counted and the count is logged once
+    /// the minute is over. 0 disables the limit.
+    /// Default is 10
+    #[serde(default = "_default_rate_limit")]
+    pub(crate) rate_limit: u32,
+
+    /// Path of a file to write log messages to instead of stderr. It is reopened on SIGUSR2,
+    /// e.g. after logrotate moved it.
+    /// Default is stderr.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+
+    /// Where to write log messages, "stderr", "syslog" (with the daemon facility) or "auto",
+    /// which is stderr when it's a terminal or a log file is set and syslog otherwise.
+    /// Default is "auto".
+    #[serde(default)]
+    pub target: LogTarget,
+
+    /// Most verbose messages to log, "error", "warn", "info" or "debug", which includes the
+    /// decision for every check and update. The RUST_LOG environment variable overrides it.
+    /// Default is "info".
+    #[serde(default = "_default_log_level")]
+    #[serde(deserialize_with = "deserialize_log_level")]
+    pub level: LevelFilter,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            rate_limit: _default_rate_limit(),
+            file: None,
+            target: LogTarget::default(),
+            level: _default_log_level(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Auto,
+    Stderr,
+    Syslog,
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<LevelFilter, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map_err(|_| de::Error::custom(format!("Invalid log level: {}", s)))
+}
+
+/// Answers for Postfix policy requests, which are detected by their first line.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Postfix {
+    /// Action for whitelisted triplets.
+    /// Default is "DUNNO"
+    #[serde(default = "_default_white_action")]
+    pub(crate) white_action: String,
+
+    /// Action for greylisted triplets.
+    /// Default is "DEFER_IF_PERMIT Greylisted, try again later"
+    #[serde(default = "_default_grey_action")]
+    pub(crate) grey_action: String,
+
+    /// Action for blacklisted triplets.
+    /// Default is "REJECT"
+    #[serde(default = "_default_black_action")]
+    pub(crate) black_action: String,
+
+    /// Action for quarantined triplets.
+    /// Default is "PREPEND X-Greylist: quarantine"
+    #[serde(default = "_default_quarantine_action")]
+    pub(crate) quarantine_action: String,
+
+    /// Action for requests that can't be decided, e.g. with an invalid client address, or
+    /// that are refused, e.g. as update isn't allowed on the socket.
+    /// Default is the greyAction.
+    #[serde(default)]
+    pub(crate) invalid_action: Option<String>,
+}
+
+impl Postfix {
+    pub(crate) fn action(&self, status: &ListingStatus) -> &str {
+        match status {
+            ListingStatus::White => &self.white_action,
+            ListingStatus::Grey => &self.grey_action,
+            ListingStatus::Black => &self.black_action,
+            ListingStatus::Quarantine => &self.quarantine_action,
+            // Custom lists are answered with their configured answer, not a listing status
+            ListingStatus::Custom(_) => "DUNNO",
+        }
+    }
+
+    /// The action for requests that can't be decided, which must not let them pass.
+    pub(crate) fn invalid_action(&self) -> &str {
+        self.invalid_action.as_deref().unwrap_or(&self.grey_action)
+    }
+}
+
+impl Default for Postfix {
+    fn default() -> Self {
+        Self {
+            white_action: _default_white_action(),
+            grey_action: _default_grey_action(),
+            black_action: _default_black_action(),
+            quarantine_action: _default_quarantine_action(),
+            invalid_action: None,
+        }
+    }
+}
+
+/// Blocklists imported into the blacklist, e.g. the Spamhaus DROP and FireHOL lists.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Feeds {
+    /// Comma separated list of feeds as name:path, e.g. "drop:/var/lib/greylistd/drop.txt".
+    /// The files contain one IP address or network per line, text after "#" or ";" is
+    /// ignored. Downloading them is left to e.g. a cron job, feeds require singlecheck.
+    /// Default is no feeds.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_feed_sources")]
+    pub(crate) sources: Vec<FeedSource>,
+
+    /// Re-read the feed files every this many seconds, networks that were removed from a
+    /// file are removed from the blacklist.
+    /// Default is 3600.
+    #[serde(default = "_default_feed_refresh")]
+    #[serde(deserialize_with = "deserialize_duration_seconds")]
+    pub(crate) refresh: Duration,
+}
+
+impl Default for Feeds {
+    fn default() -> Self {
+        Self {
+            sources: Vec::new(),
+            refresh: _default_feed_refresh(),
+        }
+    }
+}
+
+/// Greylisting policies per role of the receiving mail server, e.g. laxer ones for a backup MX
+/// whose mail is greylisted again by the primary MX.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Roles {
+    /// Comma separated list of recipient domains with the role requests for them are handled
+    /// with as domain:role, e.g. "example.net:backup" for domains this server is a backup MX
+    /// for. Subdomains match as well. Requests tagged with --role keep their role.
+    /// Default is no domains, untagged requests have the primary role.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_role_domains")]
+    pub(crate) domains: Vec<(String, Role)>,
+
+    /// Policy for requests with the primary role, see backup.
+    /// Default is "greylist".
+    #[serde(default)]
+    pub(crate) primary: RolePolicy,
+
+    /// Policy for requests with the backup role, "greylist" as configured, a duration like
+    /// "1m" to greylist with that retryMin instead, or "pass" to answer unseen and greylisted
+    /// triplets with white without recording them. Black and other entries are still answered.
+    /// Default is "greylist".
+    #[serde(default)]
+    pub(crate) backup: RolePolicy,
+
+    /// Policy for requests with the submission role, see backup.
+    /// Default is "greylist".
+    #[serde(default)]
+    pub(crate) submission: RolePolicy,
+}
+
+/// Periodic report of the database health, so growth and policy drift are visible.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Report {
+    /// Interval between reports, e.g. "1w" for a weekly report. The time of the last report
+    /// is stored with the statistics, so reloads and restarts don't postpone it.
+    /// Default is no report.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_duration_seconds")]
+    pub(crate) interval: Option<Duration>,
+
+    /// Path of a file the reports are appended to.
+    /// Default is to log them.
+    #[serde(default)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// Complete transcripts of selected connections, to debug how a client talks to the daemon.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Transcript {
+    /// Path of a file the requests and answers of the selected connections are appended to,
+    /// created readable by the daemon user only. At most 1 MiB is recorded per connection.
+    /// The addresses are written in the clear, so it can't be combined with emailKeyFile.
+    /// Default is no transcripts.
+    #[serde(default)]
+    pub(crate) path: Option<PathBuf>,
+
+    /// Comma separated list of clients whose connections are recorded, named like in
+    /// `stats --clients`, e.g. "uid=101,ip=192.0.2.1".
+    /// Default is all clients.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_list")]
+    pub(crate) peers: Option<Vec<String>>,
+
+    /// Record only every n-th connection of the selected clients.
+    /// Default is every connection.
+    #[serde(default)]
+    pub(crate) sample: Option<u32>,
+}
+
+/// The role of the receiving mail server a request is handled for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Role {
+    Primary,
+    Backup,
+    Submission,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Role::Primary),
+            "backup" => Ok(Role::Backup),
+            "submission" => Ok(Role::Submission),
+            _ => Err(anyhow!(
+                "Invalid role: {}, use primary, backup or submission",
+                s.escape_debug()
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum RolePolicy {
+    #[default]
+    Greylist,
+    /// Greylist with this retryMin
+    RetryMin(Duration),
+    Pass,
+}
+
+impl FromStr for RolePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "greylist" => Ok(RolePolicy::Greylist),
+            "pass" => Ok(RolePolicy::Pass),
+            retry_min => parse_duration(retry_min)
+                .map(RolePolicy::RetryMin)
+                .map_err(|e| anyhow!("Invalid role policy: {}", e)),
+        }
+    }
+}
+
+derive_deserialize_from_fromstr!(RolePolicy, "Invalid role policy");
+
+fn deserialize_role_domains<'de, D>(deserializer: D) -> Result<Vec<(String, Role)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(list) = deserialize_list(deserializer)? else {
+        return Ok(Vec::new());
+    };
+    list.iter()
+        .map(|domain| {
+            let invalid = || de::Error::custom(format!("Invalid role domain: {}", domain));
+            let (name, role) = domain.rsplit_once(':').ok_or_else(invalid)?;
+            let name = name.trim().trim_start_matches('.').to_lowercase();
+            if name.is_empty() || name.contains(['@', ' ']) {
+                return Err(invalid());
+            }
+            let role = role.trim().parse().map_err(|_| invalid())?;
+            Ok((name, role))
+        })
+        .collect()
+}
+
+/// Export of the whitelisted addresses and networks, for other checks of the MTA.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Export {
+    /// Path of a file the white IP addresses and networks are written to on every save.
+    /// Whitelisted triplets are exported by their sender IP, or its subnet with onlysubnet.
+    /// Default is no export.
+    #[serde(default)]
+    pub(crate) path: Option<PathBuf>,
+
+    /// Format of the file, "postfix" for a Postfix cidr: access table or "exim" for a host
+    /// list with one address or network per line.
+    /// Default is "postfix".
+    #[serde(default)]
+    pub(crate) format: ExportFormat,
+
+    /// Action written after every address or network in the postfix format.
+    /// Default is "OK"
+    #[serde(default = "_default_export_action")]
+    pub(crate) action: String,
+}
+
+impl Default for Export {
+    fn default() -> Self {
+        Self {
+            path: None,
+            format: ExportFormat::default(),
+            action: _default_export_action(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    #[default]
+    Postfix,
+    Exim,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FeedSource {
+    /// The name entries of the feed are tagged with
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+}
+
+fn deserialize_feed_sources<'de, D>(deserializer: D) -> Result<Vec<FeedSource>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(list) = deserialize_list(deserializer)? else {
+        return Ok(Vec::new());
+    };
+    list.iter()
+        .map(|source| {
+            let (name, path) = source
+                .split_once(':')
+                .filter(|(name, path)| is_valid_name(name) && !path.is_empty())
+                .ok_or_else(|| de::Error::custom(format!("Invalid feed source: {}", source)))?;
+            Ok(FeedSource {
+                name: name.to_string(),
+                path: PathBuf::from(path),
+            })
+        })
+        .collect()
 }
 
 const fn _default_true() -> bool {
@@ -122,6 +1277,73 @@ fn _default_update() -> Duration {
     Duration::from_secs(600)
 }
 
+fn _default_feed_refresh() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn _default_loading_answer() -> ListingStatus {
+    ListingStatus::White
+}
+
+/// Highest number of shard files, each is written by its own thread.
+pub(crate) const MAX_SHARDS: usize = 64;
+
+const fn _default_shards() -> usize {
+    1
+}
+
+const fn _default_save_max_delay() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+const fn _default_queue_length() -> usize {
+    1000
+}
+
+const fn _default_ipv4_mask_bits() -> u8 {
+    24
+}
+
+const fn _default_ipv6_mask_bits() -> u8 {
+    64
+}
+
+const fn _default_recidivist_attempts() -> u32 {
+    3
+}
+
+const fn _default_rate_limit() -> u32 {
+    10
+}
+
+const fn _default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn _default_notify_before() -> Duration {
+    Duration::from_secs(604800)
+}
+
+fn _default_white_action() -> String {
+    "DUNNO".to_string()
+}
+
+fn _default_grey_action() -> String {
+    "DEFER_IF_PERMIT Greylisted, try again later".to_string()
+}
+
+fn _default_black_action() -> String {
+    "REJECT".to_string()
+}
+
+fn _default_quarantine_action() -> String {
+    "PREPEND X-Greylist: quarantine".to_string()
+}
+
+fn _default_export_action() -> String {
+    "OK".to_string()
+}
+
 fn _default_statefile() -> PathBuf {
     "/var/lib/greylistd/states".into()
 }
@@ -129,3 +1351,81 @@ fn _default_statefile() -> PathBuf {
 fn _default_tripletfile() -> PathBuf {
     "/var/lib/greylistd/triplets".into()
 }
+
+/// Named lists besides white, grey, black and quarantine, e.g. for partners or bulk senders.
+#[derive(Clone, Default, Deserialize)]
+pub(crate) struct Lists {
+    /// Comma separated list of custom lists as name:answer or name:answer:expire, e.g.
+    /// "partner:white:90d, bulk:bulk". Entries are added with `add --list <name>`, check and
+    /// update requests matching them are answered with the answer, and they expire when they
+    /// weren't seen for the expire duration, or the expire timeout if not given.
+    /// Default is no custom lists.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_custom_lists")]
+    pub(crate) custom: Vec<CustomList>,
+}
+
+impl Lists {
+    pub(crate) fn get(&self, name: &str) -> Option<&CustomList> {
+        self.custom.iter().find(|list| list.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CustomList {
+    pub(crate) name: String,
+    pub(crate) answer: String,
+    pub(crate) expire: Option<Duration>,
+}
+
+/// Whether the name can be used for a feed or custom list, it's stored in the state file.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn deserialize_custom_lists<'de, D>(deserializer: D) -> Result<Vec<CustomList>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(list) = deserialize_list(deserializer)? else {
+        return Ok(Vec::new());
+    };
+    let mut lists = Vec::<CustomList>::with_capacity(list.len());
+    for custom in &list {
+        let invalid = || de::Error::custom(format!("Invalid custom list: {}", custom));
+        let mut parts = custom.splitn(3, ':');
+        let name = parts.next().filter(|name| is_valid_name(name));
+        let answer = parts
+            .next()
+            .filter(|answer| !answer.is_empty() && !answer.contains(char::is_whitespace));
+        let (Some(name), Some(answer)) = (name, answer) else {
+            return Err(invalid());
+        };
+        let expire = parts
+            .next()
+            .map(parse_duration)
+            .transpose()
+            .map_err(|e| de::Error::custom(format!("{} in custom list {}", e, custom)))?;
+        if name.parse::<ListingStatus>().is_ok() {
+            return Err(de::Error::custom(format!(
+                "Custom list name {} is reserved",
+                name
+            )));
+        }
+        if lists.iter().any(|list| list.name == name) {
+            return Err(de::Error::custom(format!(
+                "Duplicate custom list: {}",
+                name
+            )));
+        }
+        lists.push(CustomList {
+            name: name.to_string(),
+            answer: answer.to_string(),
+            expire,
+        });
+    }
+    Ok(lists)
+}
@@ -0,0 +1,182 @@
+//! The transports requests are accepted on, the Unix socket and the optional TCP listener.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+/// A socket the daemon accepts connections on.
+#[derive(Clone, Copy)]
+pub enum Listener<'a> {
+    Unix(&'a UnixListener),
+    Tcp(&'a TcpListener),
+}
+
+impl Listener<'_> {
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Listener::Unix(listener) => listener.set_nonblocking(true),
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| Connection::Unix(stream)),
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+        }
+    }
+}
+
+impl AsRawFd for Listener<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Unix(listener) => listener.as_raw_fd(),
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// An accepted client connection, requests are handled the same on both transports.
+pub(crate) enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    pub(crate) fn try_clone(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Connection::Unix(stream) => Connection::Unix(stream.try_clone()?),
+            Connection::Tcp(stream) => Connection::Tcp(stream.try_clone()?),
+        })
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.set_write_timeout(timeout),
+            Connection::Tcp(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Identify the client, by the uid of the connecting process for the Unix socket and by
+    /// the IP address for TCP.
+    pub(crate) fn peer(&self) -> Peer {
+        match self {
+            Connection::Unix(stream) => match peer_credentials(stream) {
+                Some(cred) => Peer {
+                    identity: format!("uid={}", cred.uid),
+                    credentials: Some((cred.uid, cred.gid)),
+                },
+                None => Peer::unknown(),
+            },
+            Connection::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => Peer::ip(addr.ip()),
+                Err(_) => Peer::unknown(),
+            },
+        }
+    }
+}
+
+/// The client of a connection.
+pub(crate) struct Peer {
+    /// The name requests are counted under, "uid=<uid>" or "ip=<address>"
+    identity: String,
+    /// The uid and gid of the connecting process, only known on the Unix socket
+    pub(crate) credentials: Option<(u32, u32)>,
+}
+
+impl Peer {
+    /// A TCP client, directly connected or passed on by a proxy.
+    pub(crate) fn ip(ip: IpAddr) -> Peer {
+        Peer {
+            identity: format!("ip={}", ip.to_canonical()),
+            credentials: None,
+        }
+    }
+
+    /// The user running `greylist --offline`, with the credentials of the process.
+    pub(crate) fn local() -> Peer {
+        // SAFETY: geteuid and getegid have no preconditions and can't fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Peer {
+            identity: format!("uid={}", uid),
+            credentials: Some((uid, gid)),
+        }
+    }
+
+    fn unknown() -> Peer {
+        Peer {
+            identity: "unknown".to_string(),
+            credentials: None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.identity)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+fn peer_credentials(stream: &UnixStream) -> Option<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes and len holds the size of cred.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(cred)
+}
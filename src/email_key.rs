@@ -0,0 +1,72 @@
+//! Sender and recipient addresses stored as their HMAC-SHA256 with a site key (emailKeyFile),
+//! so the data files don't reveal who corresponds with whom while the IP addresses stay usable.
+
+use crate::entry_id::EntryId;
+use crate::{App, Key, Triplet};
+use anyhow::anyhow;
+use hmac_sha256::HMAC;
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Domain of a protected address, its local part is the HMAC in lowercase hex. The reserved
+/// top level domain can't be the one of a real address, and the address stays valid.
+const DOMAIN: &str = "@hmac.invalid";
+
+/// Read the key, without trailing whitespace like the newline of a key written with echo.
+pub(crate) fn load_key(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    let mut key = std::fs::read(path)
+        .map_err(|e| anyhow!("Failed to read emailKeyFile {}: {}", path.display(), e))?;
+    while key.last().is_some_and(u8::is_ascii_whitespace) {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(anyhow!("The emailKeyFile {} is empty", path.display()));
+    }
+    Ok(key)
+}
+
+/// Whether the address has the form of a protected one, as stored in the tripletfile.
+pub(crate) fn is_protected(email: &str) -> bool {
+    email
+        .strip_suffix(DOMAIN)
+        .is_some_and(|mac| mac.len() == 64 && mac.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// The address as stored and matched with the key. Addresses of requests are always protected,
+/// also if they look like a protected one, so clients can't address stored entries by their HMAC.
+pub(crate) fn protect(email: &str, key: &[u8]) -> String {
+    let mut protected = HMAC::mac(email, key)
+        .iter()
+        .fold(String::new(), |mut protected, b| {
+            let _ = write!(protected, "{:02x}", b);
+            protected
+        });
+    protected.push_str(DOMAIN);
+    protected
+}
+
+impl App {
+    /// The key of the entry as written to the tripletfile, with the protected addresses of its
+    /// id if emailKeyFile is set, so it gets the same id when loaded again.
+    pub(crate) fn stored_key<'a>(&self, id: &EntryId, key: &'a Key) -> Cow<'a, Key> {
+        let Key::Triplet(triplet) = key else {
+            return Cow::Borrowed(key);
+        };
+        if self.config.data.email_key.is_none() {
+            return Cow::Borrowed(key);
+        }
+        match id {
+            EntryId::Triplet {
+                sender_email,
+                recipient_email,
+                ..
+            } => Cow::Owned(Key::Triplet(Triplet {
+                sender_ip: triplet.sender_ip,
+                sender_email: sender_email.clone(),
+                recipient_email: recipient_email.clone(),
+            })),
+            _ => Cow::Borrowed(key),
+        }
+    }
+}
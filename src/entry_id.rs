@@ -0,0 +1,284 @@
+use crate::config::Data;
+use crate::email_key::{is_protected, protect};
+use crate::{subnet_ip, Key, Network};
+use std::net::IpAddr;
+
+/// What entries are stored and matched by, the key normalized for the onlysubnet and sender
+/// address settings.
+///
+/// Two keys only share an entry if their ids are equal, unlike with a hash of the key.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum EntryId {
+    Triplet {
+        ip: IpAddr,
+        sender_email: Option<String>,
+        recipient_email: String,
+    },
+    Network(Network),
+    /// An entry loaded from the state file without its data
+    Hash(u64),
+}
+
+impl EntryId {
+    /// The hash of the entry in the data files.
+    ///
+    /// It's a 64-bit FNV-1a hash over an explicit encoding of the id, so it stays the same
+    /// across Rust releases and platforms.
+    pub(crate) fn stable_hash(&self) -> u64 {
+        let mut hasher = Fnv::new();
+        match self {
+            EntryId::Triplet {
+                ip,
+                sender_email,
+                recipient_email,
+            } => {
+                hasher.write(&[0]);
+                hasher.write_ip(ip);
+                match sender_email {
+                    Some(sender_email) => {
+                        hasher.write(&[1]);
+                        hasher.write_str(sender_email);
+                    }
+                    None => hasher.write(&[0]),
+                }
+                hasher.write_str(recipient_email);
+            }
+            EntryId::Network(network) => {
+                hasher.write(&[1]);
+                hasher.write_ip(&network.addr);
+                hasher.write(&[network.prefix]);
+            }
+            EntryId::Hash(hash) => return *hash,
+        }
+        hasher.finish()
+    }
+}
+
+impl Key {
+    /// The id of the entry for this key, with onlysubnet a single IP address stands for its
+    /// whole subnet of the configured IPv4 and IPv6 prefix lengths.
+    pub(crate) fn id(&self, data: &Data) -> EntryId {
+        self.id_with(data, false)
+    }
+
+    /// The id of a key loaded from the tripletfile, whose addresses are already normalized and
+    /// protected if it was saved with emailKeyFile. Addresses saved without it are protected now.
+    pub(crate) fn stored_id(&self, data: &Data) -> EntryId {
+        self.id_with(data, true)
+    }
+
+    fn id_with(&self, data: &Data, stored: bool) -> EntryId {
+        let subnet = data.subnet_prefixes();
+        let ip = |ip| match subnet {
+            Some((prefix4, prefix6)) => subnet_ip(ip, prefix4, prefix6),
+            None => ip,
+        };
+        match self {
+            Key::Triplet(triplet) => EntryId::Triplet {
+                ip: match subnet {
+                    Some((prefix4, prefix6)) => triplet.network_key(prefix4, prefix6),
+                    None => triplet.sender_ip,
+                },
+                sender_email: triplet.sender_email.as_deref().map(|sender_email| {
+                    match &data.email_key {
+                        Some(_) if stored && is_protected(sender_email) => sender_email.to_string(),
+                        Some(email_key) => {
+                            protect(&normalize_sender(sender_email, data), email_key)
+                        }
+                        None => normalize_sender(sender_email, data),
+                    }
+                }),
+                recipient_email: match &data.email_key {
+                    Some(_) if stored && is_protected(&triplet.recipient_email) => {
+                        triplet.recipient_email.clone()
+                    }
+                    Some(email_key) => protect(&triplet.recipient_email, email_key),
+                    None => triplet.recipient_email.clone(),
+                },
+            },
+            Key::Network(network) if network.is_host() => EntryId::Network(Network {
+                addr: ip(network.addr),
+                prefix: network.prefix,
+            }),
+            Key::Network(network) => EntryId::Network(*network),
+            Key::Hash(hash) => EntryId::Hash(*hash),
+        }
+    }
+}
+
+/// The sender address as matched, according to the sender options of the configuration.
+fn normalize_sender(sender_email: &str, data: &Data) -> String {
+    let (local, domain) = match sender_email.rsplit_once('@') {
+        Some((local, domain)) => (local, Some(domain)),
+        None => (sender_email, None),
+    };
+    let mut local = local.to_string();
+    if data.sender_strip_extension {
+        // A local part starting with "+" is left alone, there'd be nothing left
+        if let Some(end) = local.find('+').filter(|end| *end > 0) {
+            local.truncate(end);
+        }
+    }
+    if data.sender_collapse_verp {
+        local = collapse_verp(&local);
+    }
+    if data.sender_lowercase_local {
+        local = local.to_lowercase();
+    }
+    match domain {
+        Some(domain) if data.sender_lowercase_domain => {
+            format!("{}@{}", local, domain.to_lowercase())
+        }
+        Some(domain) => format!("{}@{}", local, domain),
+        None => local,
+    }
+}
+
+/// Characters separating the words of a local part for sender_collapse_verp.
+const VERP_SEPARATORS: [char; 4] = ['-', '.', '_', '+'];
+
+/// Replace the words of a local part that contain digits or "=" with "#".
+fn collapse_verp(local: &str) -> String {
+    local
+        .split_inclusive(VERP_SEPARATORS)
+        .map(|part| {
+            let word = part.trim_end_matches(VERP_SEPARATORS);
+            if word.contains(|c: char| c.is_ascii_digit() || c == '=') {
+                format!("#{}", &part[word.len()..])
+            } else {
+                part.to_string()
+            }
+        })
+        .collect()
+}
+
+/// 64-bit FNV-1a hasher, which unlike DefaultHasher is specified and stable.
+pub(crate) struct Fnv(u64);
+
+impl Fnv {
+    pub(crate) fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    fn write_ip(&mut self, ip: &IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => {
+                self.write(&[4]);
+                self.write(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.write(&[6]);
+                self.write(&ip.octets());
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(ini: &str) -> Data {
+        serde_ini::from_str(ini).unwrap()
+    }
+
+    fn id(triplet: &str, data: &Data) -> EntryId {
+        Key::Triplet(triplet.parse().unwrap()).id(data)
+    }
+
+    #[test]
+    fn ipv6_triplets_share_the_default_64() {
+        let data = data("");
+        let a = id("2001:db8:1:2::1 s@example.com r@example.org", &data);
+        assert_eq!(
+            a,
+            id("2001:db8:1:2:ffff::9 s@example.com r@example.org", &data)
+        );
+        assert_ne!(a, id("2001:db8:1:3::1 s@example.com r@example.org", &data));
+        assert_ne!(a, id("2001:db8:1:2::1 t@example.com r@example.org", &data));
+        assert_eq!(
+            a.stable_hash(),
+            id("2001:db8:1:2::2 s@example.com r@example.org", &data).stable_hash()
+        );
+    }
+
+    #[test]
+    fn ipv6_triplets_share_a_56() {
+        let data = data("ipv6MaskBits = 56\n");
+        let a = id("2001:db8:1:200::1 s@example.com r@example.org", &data);
+        assert_eq!(
+            a,
+            id("2001:db8:1:2ff::1 s@example.com r@example.org", &data)
+        );
+        assert_ne!(
+            a,
+            id("2001:db8:1:300::1 s@example.com r@example.org", &data)
+        );
+        assert_ne!(
+            a,
+            id("2001:db8:1:100::1 s@example.com r@example.org", &data)
+        );
+    }
+
+    #[test]
+    fn ipv6_hosts_share_their_subnet() {
+        let data = data("ipv6MaskBits = 56\n");
+        let host = |ip: &str| Key::Network(ip.parse().unwrap()).id(&data);
+        assert_eq!(host("2001:db8:1:200::1"), host("2001:db8:1:2ab::5"));
+        assert_ne!(host("2001:db8:1:200::1"), host("2001:db8:1:300::1"));
+        // Networks keep their own prefix
+        assert_ne!(host("2001:db8:1:200::/64"), host("2001:db8:1:200::1"));
+    }
+
+    #[test]
+    fn full_addresses_without_onlysubnet() {
+        let data = data("onlysubnet = false\n");
+        assert_ne!(
+            id("2001:db8:1:2::1 r@example.org", &data),
+            id("2001:db8:1:2::2 r@example.org", &data)
+        );
+    }
+
+    #[test]
+    fn stored_keys_get_the_same_id() {
+        let sender_options =
+            "senderLowercaseLocal = true\nsenderStripExtension = true\nsenderCollapseVerp = true\n";
+        let config: crate::config::Config = serde_ini::from_str(&format!(
+            "[timeouts]\n\n[socket]\npath = /nonexistent/socket\nmode = 0600\n\n[data]\n{}",
+            sender_options
+        ))
+        .unwrap();
+        let mut app = crate::App::with_config(config);
+        let key = Key::Triplet(
+            "192.0.2.1 Bounce-12345-me=example.org+tag@Lists.Example.COM r@example.org"
+                .parse()
+                .unwrap(),
+        );
+        for email_key in [None, Some(b"secret".to_vec())] {
+            app.config.data.email_key = email_key;
+            let data = &app.config.data;
+            let id = key.id(data);
+            let stored = app.stored_key(&id, &key);
+            let loaded = Key::from_stored(&stored.to_string()).unwrap();
+            assert_eq!(loaded.stored_id(data), id, "{}", stored);
+            // Keys saved before emailKeyFile was set are protected when loaded
+            assert_eq!(key.stored_id(data), id);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Export of the whitelisted IP addresses and networks on every save, so other checks of the MTA
+//! can use what greylistd learned, e.g. to skip further checks for known senders.
+
+use crate::config::ExportFormat;
+use crate::{App, Key, ListingStatus, Network};
+use std::collections::BTreeSet;
+use std::fs::{rename, File};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+impl App {
+    /// The white addresses and networks, triplets by their sender IP or its subnet with
+    /// onlysubnet, sorted and without duplicates.
+    fn white_networks(&self) -> BTreeSet<(IpAddr, u8)> {
+        let prefixes = self.config.data.subnet_prefixes();
+        self.triplets
+            .values()
+            .filter(|entry| entry.listing_status == ListingStatus::White)
+            .filter_map(|entry| match &entry.key {
+                Key::Network(network) => Some(*network),
+                Key::Triplet(triplet) => Some(match prefixes {
+                    Some((prefix4, prefix6)) => Network {
+                        addr: triplet.network_key(prefix4, prefix6),
+                        prefix: if triplet.sender_ip.is_ipv4() {
+                            prefix4
+                        } else {
+                            prefix6
+                        },
+                    },
+                    None => Network::host(triplet.sender_ip),
+                }),
+                // Entries without their triplet have no address to export
+                Key::Hash(_) => None,
+            })
+            .map(|network| (network.addr, network.prefix))
+            .collect()
+    }
+
+    /// Write the white addresses and networks to the configured export file, if any.
+    ///
+    /// A failed export is only logged, it doesn't affect saving the data.
+    pub(crate) fn export_white(&self) {
+        let Some(path) = &self.config.export.path else {
+            return;
+        };
+        let networks = self.white_networks();
+        match self.write_export(path, &networks) {
+            Ok(()) => log::debug!(
+                "Exported {} white networks to {}",
+                networks.len(),
+                path.display()
+            ),
+            Err(e) => log::warn!("Failed to export whitelist to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Write the export to a temporary file first and rename it, so the MTA never reads a
+    /// partial file.
+    fn write_export(
+        &self,
+        path: &Path,
+        networks: &BTreeSet<(IpAddr, u8)>,
+    ) -> Result<(), anyhow::Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writeln!(
+            writer,
+            "# Whitelist exported by greylistd, changes are overwritten"
+        )?;
+        for &(addr, prefix) in networks {
+            let network = Network { addr, prefix };
+            match self.config.export.format {
+                ExportFormat::Postfix => {
+                    writeln!(writer, "{}\t{}", network, self.config.export.action)?
+                }
+                ExportFormat::Exim => writeln!(writer, "{}", network)?,
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        rename(&tmp, path)?;
+        Ok(())
+    }
+}
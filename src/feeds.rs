@@ -0,0 +1,160 @@
+use crate::config::FeedSource;
+use crate::entry_id::EntryId;
+use crate::{App, GreylistEntry, Key, Lifetime, ListingStatus, Network, Reason, TripletStatus};
+use crossbeam_channel::{never, tick, Receiver};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io::Write;
+use std::time::{Instant, SystemTime};
+
+/// The networks listed in a feed file, ignoring comments after "#" or ";" and invalid lines.
+fn read_feed(source: &FeedSource) -> Result<Vec<Network>, anyhow::Error> {
+    let content = read_to_string(&source.path)?;
+    let mut invalid = 0;
+    let networks = content
+        .lines()
+        .map(|line| line.split(['#', ';']).next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let network = line.parse::<Network>().ok();
+            invalid += usize::from(network.is_none());
+            network
+        })
+        .collect();
+    if invalid > 0 {
+        log::warn!(
+            "Ignored {} invalid lines in feed {} ({})",
+            invalid,
+            source.name,
+            source.path.display()
+        );
+    }
+    Ok(networks)
+}
+
+impl App {
+    /// Fires every feed refresh interval, never without feeds.
+    pub(crate) fn feed_timer(&self) -> Receiver<Instant> {
+        if self.config.feeds.sources.is_empty() {
+            never()
+        } else {
+            tick(self.config.feeds.refresh)
+        }
+    }
+
+    /// The configured feeds with their number of entries and rejected update requests.
+    pub(crate) fn feed_stats(&self) -> Vec<(&str, usize, u64)> {
+        self.config
+            .feeds
+            .sources
+            .iter()
+            .map(|source| {
+                let networks = self
+                    .triplets
+                    .values()
+                    .filter(
+                        |entry| matches!(&entry.reason, Reason::Feed(name) if *name == source.name),
+                    )
+                    .count();
+                let rejected = self.feed_hits.get(&source.name).copied().unwrap_or(0);
+                (source.name.as_str(), networks, rejected)
+            })
+            .collect()
+    }
+
+    pub(crate) fn write_feed_stats(&self, writer: &mut impl Write) -> Result<(), anyhow::Error> {
+        if self.config.feeds.sources.is_empty() {
+            writeln!(writer, "No feeds are configured")?;
+            return Ok(());
+        }
+        writeln!(
+            writer,
+            "Feed entries and rejected updates since last (re)start:"
+        )?;
+        for (name, networks, rejected) in self.feed_stats() {
+            writeln!(
+                writer,
+                "{}: {} networks, {} rejected updates",
+                name, networks, rejected
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Re-read all feed files and update the blacklist entries of each feed.
+    ///
+    /// If a file can't be read, the entries of its feed are kept until they expire.
+    pub(crate) fn refresh_feeds(&mut self, now: SystemTime) {
+        let sources = self.config.feeds.sources.clone();
+        let count = self.triplets.len();
+        // Entries of feeds that were removed from the configuration
+        self.triplets.retain(|_, entry| match &entry.reason {
+            Reason::Feed(name) => sources.iter().any(|source| source.name == *name),
+            _ => true,
+        });
+        self.dirty |= self.triplets.len() != count;
+
+        for source in &sources {
+            match read_feed(source) {
+                Ok(networks) => self.import_feed(&source.name, networks, now),
+                Err(e) => log::warn!(
+                    "Failed to read feed {} ({}): {}",
+                    source.name,
+                    source.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Blacklist the networks of a feed and remove its entries that are no longer listed.
+    ///
+    /// Existing entries from other sources are left alone, so a manual whitelist entry
+    /// overrides the feed.
+    fn import_feed(&mut self, name: &str, networks: Vec<Network>, now: SystemTime) {
+        let reason = Reason::Feed(name.to_string());
+        let mut listed = HashSet::<EntryId>::with_capacity(networks.len());
+        let mut added = 0;
+        let mut overridden = 0;
+        for network in networks {
+            let key = Key::Network(network);
+            let id = self.entry_id(&key);
+            match self.triplets.get_mut(&id) {
+                Some(entry) if entry.reason == reason => entry.triplet_status.last_seen = now,
+                Some(_) => overridden += 1,
+                None => {
+                    self.triplets.insert(
+                        id.clone(),
+                        GreylistEntry {
+                            key,
+                            listing_status: ListingStatus::Black,
+                            reason: reason.clone(),
+                            lifetime: Lifetime::Timeout,
+                            triplet_status: TripletStatus {
+                                first_seen: now,
+                                last_seen: now,
+                                count: 0,
+                            },
+                        },
+                    );
+                    added += 1;
+                }
+            }
+            listed.insert(id);
+        }
+        let count = self.triplets.len();
+        self.triplets
+            .retain(|id, entry| entry.reason != reason || listed.contains(id));
+        let removed = count - self.triplets.len();
+        // The last seen time of all entries of the feed changed
+        self.dirty = true;
+        log::info!(
+            "Imported feed {}: {} networks, {} added, {} removed, {} overridden by other entries",
+            name,
+            listed.len(),
+            added,
+            removed,
+            overridden
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! JSON answers of the list, status, stats and subnet commands, for tools that audit the greylist.
+//!
+//! Times are given as seconds since the epoch, independent of the compat setting.
+
+use crate::resources::ResourceUsage;
+use crate::subnets::Outcome;
+use crate::{App, GreylistEntry, Key, Lifetime, ListingStatus, Network, Reason};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize)]
+struct ListEntry {
+    list: ListingStatus,
+    /// The IP address, or the network for network entries
+    sender_ip: Option<String>,
+    sender_email: Option<String>,
+    recipient_email: Option<String>,
+    /// Only set for entries loaded from the state file without their data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<u64>,
+    first_seen: u64,
+    last_seen: u64,
+    count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+    /// Only set for manually added entries, "permanent" or "until:<seconds>"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lifetime: Option<Lifetime>,
+}
+
+/// A page of `list --cursor`, without a next cursor on the last page.
+#[derive(Serialize)]
+struct ListPage {
+    entries: Vec<ListEntry>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    /// The listing status, or "unseen"
+    status: String,
+    first_seen: Option<u64>,
+    last_seen: Option<u64>,
+    count: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Subnet {
+    subnet: String,
+    attempts: u32,
+    successes: u32,
+    last_outcome: Outcome,
+    last_seen: u64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    start: u64,
+    uptime: u64,
+    paused: bool,
+    maintenance: Option<ListingStatus>,
+    internal_errors: u64,
+    protocol_errors: u64,
+    accept_failures: u64,
+    /// Connections answered with busy as too many were waiting
+    busy_connections: u64,
+    /// Requests answered without a decision as they exceeded the request budget
+    over_budget: u64,
+    white: ListCount,
+    grey: ListCount,
+    black: ListCount,
+    quarantine: ListCount,
+    /// The custom lists by name
+    lists: BTreeMap<String, ListCount>,
+    greylisted: u32,
+    became_whitelisted: u32,
+    expired_from_greylist: u32,
+    would_pass: u32,
+    quarantined: u32,
+    blacklisted: u32,
+    distinct_sender_ips: u64,
+    distinct_sender_domains: u64,
+    distinct_recipients: u64,
+    /// Entries dropped from each list by the last prune
+    last_prune: Option<LastPrune>,
+    resources: ResourceUsage,
+}
+
+#[derive(Serialize)]
+struct LastPrune {
+    time: u64,
+    dropped: BTreeMap<String, u32>,
+}
+
+#[derive(Serialize)]
+struct ListCount {
+    items: u32,
+    requests: u32,
+}
+
+#[derive(Serialize)]
+struct ClientStats {
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Serialize)]
+struct FeedStats {
+    networks: usize,
+    rejected: u64,
+}
+
+#[derive(Serialize)]
+struct SourceCount {
+    auto: usize,
+    manual: usize,
+    imported: usize,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn list_entry(entry: &GreylistEntry, reasons: bool) -> ListEntry {
+    let (sender_ip, sender_email, recipient_email, hash) = match &entry.key {
+        Key::Triplet(triplet) => (
+            Some(triplet.sender_ip.to_string()),
+            triplet.sender_email.clone(),
+            Some(triplet.recipient_email.clone()),
+            None,
+        ),
+        Key::Network(network) => (Some(network.to_string()), None, None, None),
+        Key::Hash(hash) => (None, None, None, Some(*hash)),
+    };
+    ListEntry {
+        list: entry.listing_status.clone(),
+        sender_ip,
+        sender_email,
+        recipient_email,
+        hash,
+        first_seen: seconds(entry.triplet_status.first_seen),
+        last_seen: seconds(entry.triplet_status.last_seen),
+        count: entry.triplet_status.count,
+        reason: reasons.then(|| entry.reason.clone()),
+        lifetime: Some(entry.lifetime).filter(|lifetime| reasons && !lifetime.is_automatic()),
+    }
+}
+
+pub(crate) fn write_list(
+    entries: &[&GreylistEntry],
+    reasons: bool,
+    writer: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let entries = entries
+        .iter()
+        .map(|entry| list_entry(entry, reasons))
+        .collect::<Vec<_>>();
+    serde_json::to_writer(writer, &entries)?;
+    Ok(())
+}
+
+/// The number of matching entries of `list --count` by list.
+pub(crate) fn write_list_counts(
+    counts: &[(&ListingStatus, usize)],
+    writer: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let counts = counts
+        .iter()
+        .map(|(status, count)| (status.to_string(), *count))
+        .collect::<BTreeMap<_, _>>();
+    serde_json::to_writer(writer, &counts)?;
+    Ok(())
+}
+
+pub(crate) fn write_list_page(
+    entries: &[&GreylistEntry],
+    reasons: bool,
+    next_cursor: Option<u64>,
+    writer: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let page = ListPage {
+        entries: entries
+            .iter()
+            .map(|entry| list_entry(entry, reasons))
+            .collect(),
+        next_cursor: next_cursor.map(|cursor| format!("{:016x}", cursor)),
+    };
+    serde_json::to_writer(writer, &page)?;
+    Ok(())
+}
+
+impl App {
+    pub(crate) fn write_subnets_json(
+        &self,
+        network: &Network,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
+        let subnets = self
+            .subnets_in(network)
+            .into_iter()
+            .map(|(subnet, history)| Subnet {
+                subnet: subnet.to_string(),
+                attempts: history.attempts(),
+                successes: history.successes(),
+                last_outcome: history.last_outcome(),
+                last_seen: seconds(history.last_seen()),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer(writer, &subnets)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_status_json(
+        &self,
+        key: &Key,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
+        let status = match self.get_entry(key) {
+            Some(entry) => Status {
+                status: entry.listing_status.to_string(),
+                first_seen: Some(seconds(entry.triplet_status.first_seen)),
+                last_seen: Some(seconds(entry.triplet_status.last_seen)),
+                count: Some(entry.triplet_status.count),
+            },
+            None => Status {
+                status: "unseen".to_string(),
+                first_seen: None,
+                last_seen: None,
+                count: None,
+            },
+        };
+        serde_json::to_writer(writer, &status)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_stats_json(
+        &self,
+        clients: bool,
+        sources: bool,
+        feeds: bool,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
+        if clients {
+            let clients = self
+                .peers
+                .iter()
+                .map(|(peer, stats)| {
+                    let stats = ClientStats {
+                        requests: stats.requests,
+                        errors: stats.errors,
+                    };
+                    (peer, stats)
+                })
+                .collect::<BTreeMap<_, _>>();
+            serde_json::to_writer(writer, &clients)?;
+            return Ok(());
+        }
+        if sources {
+            let sources = self
+                .statuses()
+                .into_iter()
+                .map(|status| {
+                    let (auto, manual, imported) = self.count_by_source(&status);
+                    (
+                        status.to_string(),
+                        SourceCount {
+                            auto,
+                            manual,
+                            imported,
+                        },
+                    )
+                })
+                .collect::<BTreeMap<_, _>>();
+            serde_json::to_writer(writer, &sources)?;
+            return Ok(());
+        }
+        if feeds {
+            let feeds = self
+                .feed_stats()
+                .into_iter()
+                .map(|(name, networks, rejected)| (name, FeedStats { networks, rejected }))
+                .collect::<BTreeMap<_, _>>();
+            serde_json::to_writer(writer, &feeds)?;
+            return Ok(());
+        }
+
+        let count = |status| {
+            let (items, requests) = self.count_by_status(&status);
+            ListCount { items, requests }
+        };
+        let statistics = &self.statistics;
+        let stats = Stats {
+            start: seconds(statistics.start),
+            uptime: SystemTime::now()
+                .duration_since(statistics.start)
+                .unwrap_or_default()
+                .as_secs(),
+            paused: self.paused,
+            maintenance: self.maintenance.clone(),
+            internal_errors: self.panics,
+            protocol_errors: self.protocol_errors,
+            accept_failures: self.accept_counters.failures.load(Ordering::Relaxed),
+            busy_connections: self.accept_counters.busy.load(Ordering::Relaxed),
+            over_budget: self.over_budget,
+            white: count(ListingStatus::White),
+            grey: count(ListingStatus::Grey),
+            black: count(ListingStatus::Black),
+            quarantine: count(ListingStatus::Quarantine),
+            lists: self
+                .config
+                .lists
+                .custom
+                .iter()
+                .map(|list| {
+                    let status = ListingStatus::Custom(list.name.clone());
+                    (list.name.clone(), count(status))
+                })
+                .collect(),
+            greylisted: statistics.grey,
+            became_whitelisted: statistics.white,
+            expired_from_greylist: statistics.expired,
+            would_pass: statistics.would_pass,
+            quarantined: statistics.quarantine,
+            blacklisted: statistics.black,
+            distinct_sender_ips: self.diversity.sender_ips.estimate(),
+            distinct_sender_domains: self.diversity.sender_domains.estimate(),
+            distinct_recipients: self.diversity.recipients.estimate(),
+            last_prune: self.last_prune.as_ref().map(|(time, dropped)| LastPrune {
+                time: seconds(*time),
+                dropped: dropped.clone(),
+            }),
+            resources: self.resource_usage(),
+        };
+        serde_json::to_writer(writer, &stats)?;
+        Ok(())
+    }
+}
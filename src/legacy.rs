@@ -0,0 +1,353 @@
+//! Migration from the data directory of the Python greylistd package.
+
+use crate::config::Config;
+use crate::{
+    entries_from_states, load_triplet_states, GreylistEntry, Key, StoredStates, StoredStatistics,
+    TripletStatus,
+};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{exists, metadata, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the Python greylistd keeps its state and triplet files.
+pub(crate) const LEGACY_DATA_DIR: &str = "/var/lib/greylistd";
+
+/// Whether a data file doesn't exist or was never written to.
+fn is_empty(path: &Path) -> bool {
+    metadata(path).map_or(true, |metadata| metadata.len() == 0)
+}
+
+/// The state and triplet files of the Python greylistd to load instead of the configured data
+/// files, when importLegacy is enabled and the configured files haven't been written yet.
+///
+/// The formats are the same, so the configured files are written on the next save and the
+/// legacy files are left untouched. They are read leniently, see [load_states].
+pub(crate) fn legacy_files(config: &Config) -> Option<(PathBuf, PathBuf)> {
+    let data = &config.data;
+    if !data.import_legacy || !is_empty(&data.statefile) || !is_empty(&data.tripletfile) {
+        return None;
+    }
+    let dir = Path::new(LEGACY_DATA_DIR);
+    let statefile = dir.join("states");
+    if is_empty(&statefile) {
+        return None;
+    }
+    Some((statefile, dir.join("triplets")))
+}
+
+/// Load the data files, reading the files of the Python greylistd leniently if they can't be
+/// parsed: invalid lines are skipped with a warning instead of failing the start.
+///
+/// Files are read leniently when importing them from the legacy data directory, or when the
+/// state file wasn't written by greylistd-rs. Returns whether they were read leniently, so
+/// they're saved in the format of greylistd-rs.
+pub(crate) fn load_states(
+    tripletfile: &Path,
+    statefile: &Path,
+    legacy: bool,
+) -> Result<(Vec<GreylistEntry>, StoredStatistics, bool), anyhow::Error> {
+    let error = match load_triplet_states(tripletfile, statefile) {
+        Ok((entries, statistics)) => return Ok((entries, statistics, false)),
+        Err(e) => e,
+    };
+    if !legacy && !is_legacy_format(statefile)? {
+        return Err(error);
+    }
+    log::warn!(
+        "Reading the data as written by the Python greylistd, skipping invalid lines: {:#}",
+        error
+    );
+    let mut skipped = Skipped::default();
+    let triplets = read_triplets(tripletfile, &mut skipped)?;
+    let states = read_states(statefile, &triplets, &mut skipped)?;
+    if let Some(first) = &skipped.first {
+        log::warn!(
+            "Skipped {} invalid lines of the greylistd data, the first at {}",
+            skipped.count,
+            first
+        );
+    }
+    let (entries, statistics) = entries_from_states(triplets, states)?;
+    Ok((entries, statistics, true))
+}
+
+/// Whether the state file was written by the Python greylistd, which has no custom lists.
+/// greylistd-rs always writes the section.
+fn is_legacy_format(statefile: &Path) -> Result<bool, anyhow::Error> {
+    if !exists(statefile)? {
+        return Ok(false);
+    }
+    Ok(!read_to_string(statefile)?
+        .lines()
+        .any(|line| line.trim() == "[custom]"))
+}
+
+/// Lines skipped while reading leniently.
+#[derive(Default)]
+struct Skipped {
+    count: usize,
+    /// Location and reason of the first skipped line
+    first: Option<String>,
+}
+
+impl Skipped {
+    fn skip(&mut self, path: &Path, line: usize, reason: impl Display) {
+        self.count += 1;
+        if self.first.is_none() {
+            self.first = Some(format!("{}:{}: {}", path.display(), line, reason));
+        }
+    }
+}
+
+/// A `key = value` line of an INI file.
+struct IniValue {
+    /// The section of the line, empty before the first section header
+    section: String,
+    key: String,
+    value: String,
+    line: usize,
+}
+
+/// Read the values of an INI file like Python's ConfigParser writes it, which also separates
+/// keys with ":". A missing file is read as empty.
+fn read_ini(path: &Path, skipped: &mut Skipped) -> Result<Vec<IniValue>, anyhow::Error> {
+    if !exists(path)? {
+        return Ok(Vec::new());
+    }
+    let mut section = String::new();
+    let mut values = Vec::new();
+    for (index, line) in read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.trim().to_string();
+            continue;
+        }
+        match line.split_once(['=', ':']) {
+            Some((key, value)) if !key.trim().is_empty() => values.push(IniValue {
+                section: section.clone(),
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+                line: index + 1,
+            }),
+            _ => skipped.skip(path, index + 1, "not a key = value line"),
+        }
+    }
+    Ok(values)
+}
+
+fn read_triplets(
+    path: &Path,
+    skipped: &mut Skipped,
+) -> Result<HashMap<String, Key>, anyhow::Error> {
+    let mut triplets = HashMap::new();
+    for value in read_ini(path, skipped)? {
+        match Key::from_stored(&value.value) {
+            Ok(key) => {
+                triplets.insert(value.key, key);
+            }
+            Err(e) => skipped.skip(path, value.line, e),
+        }
+    }
+    Ok(triplets)
+}
+
+fn read_states(
+    path: &Path,
+    triplets: &HashMap<String, Key>,
+    skipped: &mut Skipped,
+) -> Result<StoredStates, anyhow::Error> {
+    let mut states = StoredStates::default();
+    for IniValue {
+        section,
+        key,
+        value,
+        line,
+    } in read_ini(path, skipped)?
+    {
+        // States without a triplet are kept by their hash, Python's hashes can be negative
+        if section != "statistics" && !triplets.contains_key(&key) && key.parse::<u64>().is_err() {
+            skipped.skip(path, line, format_args!("Invalid hash: {}", key));
+            continue;
+        }
+        let result = match section.as_str() {
+            "statistics" => read_statistic(&mut states.statistics, &key, &value),
+            "white" => parse_status(&value).map(|status| {
+                states.white.insert(key, status);
+            }),
+            "grey" => parse_status(&value).map(|status| {
+                states.grey.insert(key, status);
+            }),
+            "black" => parse_status(&value).map(|status| {
+                states.black.insert(key, status);
+            }),
+            "quarantine" => parse_status(&value).map(|status| {
+                states.quarantine.insert(key, status);
+            }),
+            "custom" => value.parse().map(|status| {
+                states.custom.insert(key, status);
+            }),
+            "reasons" | "sources" => value.parse().map(|reason| {
+                states.reasons.insert(key, reason);
+            }),
+            "lifetimes" => value.parse().map(|lifetime| {
+                states.lifetimes.insert(key, lifetime);
+            }),
+            _ => Err(anyhow!("Unknown section: {}", section)),
+        };
+        if let Err(e) = result {
+            skipped.skip(path, line, e);
+        }
+    }
+    Ok(states)
+}
+
+/// Parse a status of "<last seen> <first seen> <count>", allowing fractional seconds.
+fn parse_status(value: &str) -> Result<TripletStatus, anyhow::Error> {
+    let invalid = || anyhow!("Invalid triplet status: {}", value);
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    let [last_seen, first_seen, count] = parts[..] else {
+        return Err(invalid());
+    };
+    Ok(TripletStatus {
+        last_seen: parse_time(last_seen).ok_or_else(invalid)?,
+        first_seen: parse_time(first_seen).ok_or_else(invalid)?,
+        count: count.parse().map_err(|_| invalid())?,
+    })
+}
+
+fn read_statistic(
+    statistics: &mut StoredStatistics,
+    key: &str,
+    value: &str,
+) -> Result<(), anyhow::Error> {
+    let invalid = || anyhow!("Invalid statistic {}: {}", key, value);
+    let counter = || value.parse::<u32>().map_err(|_| invalid());
+    match key {
+        "start" => statistics.start = parse_time(value).ok_or_else(invalid)?,
+        "lastsave" => statistics.lastsave = parse_time(value).ok_or_else(invalid)?,
+        "white" => statistics.white = counter()?,
+        "grey" => statistics.grey = counter()?,
+        "black" => statistics.black = counter()?,
+        "expired" => statistics.expired = counter()?,
+        "would_pass" => statistics.would_pass = counter()?,
+        "quarantine" => statistics.quarantine = counter()?,
+        _ => return Err(anyhow!("Unknown statistic: {}", key)),
+    }
+    Ok(())
+}
+
+/// Parse seconds since the epoch, possibly fractional as written by Python's time.time().
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let seconds = value.parse::<f64>().ok()?;
+    (seconds.is_finite() && seconds >= 0.0)
+        .then(|| UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_config;
+    use crate::{load_data, App, ListingStatus};
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    /// Files as written by the Python greylistd, with fractional times, negative hashes, ":"
+    /// separators and an invalid line.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/legacy")
+            .join(name)
+    }
+
+    /// The entries by their stored key, status and times.
+    fn entries(app: &App) -> BTreeMap<String, String> {
+        app.triplets
+            .values()
+            .map(|entry| {
+                (
+                    entry.key.to_string(),
+                    format!("{} {}", entry.listing_status, entry.triplet_status),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loads_python_files() {
+        let (entries, statistics, lenient) =
+            load_states(&fixture("triplets"), &fixture("states"), true).unwrap();
+        assert!(lenient);
+        assert_eq!(entries.len(), 4);
+        let status = |key: &str| {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.key.to_string() == key)
+                .unwrap_or_else(|| panic!("Missing {}", key));
+            (
+                entry.listing_status.clone(),
+                entry.triplet_status.to_string(),
+            )
+        };
+        assert_eq!(
+            status("192.0.2.1 sender@example.com user@example.org"),
+            (ListingStatus::White, "1699999000 1699900000 5".to_string())
+        );
+        assert_eq!(
+            status("198.51.100.7 user@example.org"),
+            (ListingStatus::White, "1699998000 1699800000 2".to_string())
+        );
+        assert_eq!(
+            status("203.0.113.5 list-bounces+123@lists.example.net user@example.org"),
+            (ListingStatus::Grey, "1699999900 1699999900 1".to_string())
+        );
+        assert_eq!(
+            status("10.1.2.3 foo user@example.org"),
+            (ListingStatus::Black, "1699000000 1698000000 9".to_string())
+        );
+        assert_eq!(
+            statistics.start,
+            UNIX_EPOCH + Duration::from_secs(1_699_990_000)
+        );
+        assert_eq!(
+            (
+                statistics.white,
+                statistics.grey,
+                statistics.black,
+                statistics.expired
+            ),
+            (12, 30, 2, 7)
+        );
+    }
+
+    #[test]
+    fn saved_python_files_load_back() {
+        let (config, dir) = scratch_config("").unwrap();
+        fs::copy(fixture("states"), dir.path().join("states")).unwrap();
+        fs::copy(fixture("triplets"), dir.path().join("triplets")).unwrap();
+
+        let data = load_data(&config).unwrap();
+        assert!(data.imported);
+        let mut app = App::with_config(config.clone());
+        app.install(data);
+        let imported = entries(&app);
+        assert_eq!(imported.len(), 4);
+        app.save_at(UNIX_EPOCH + Duration::from_secs(1_700_000_100))
+            .unwrap();
+
+        let data = load_data(&config).unwrap();
+        assert!(!data.imported);
+        let mut loaded = App::with_config(config);
+        loaded.install(data);
+        assert_eq!(entries(&loaded), imported);
+        assert_eq!(loaded.statistics.white, 12);
+    }
+}
@@ -1,193 +1,1114 @@
-use crate::config::Config;
-use anyhow::anyhow;
-use crossbeam_channel::Receiver;
+use crate::busy::RequestRate;
+use crate::cardinality::Diversity;
+use crate::codes::{write_answer, Handled, ResponseCode};
+use crate::config::{Compat, Config, Role, RolePolicy};
+use crate::connection::{Connection, Peer};
+use crate::entry_id::EntryId;
+use crate::logging::Throttles;
+use crate::middleware::{DecisionRequest, Middleware, Operation};
+use crate::parser::{
+    is_binary, Command, DebugCommand, InvalidKey, ListFilter, ListSort, COMMAND_NAMES,
+    MAX_REQUEST_LENGTH,
+};
+use crate::recidivist::ExpiredAttempts;
+use crate::report::{ReportBaseline, SaveTimes};
+use crate::resources::ResourceMonitor;
+use crate::subnets::{Outcome, SubnetHistory};
+use crate::transcript::Transcribed;
+use anyhow::{anyhow, Context};
+use crossbeam_channel::{bounded, never, select, tick, Receiver, Sender, TrySendError};
+use log::Level;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_ini::{from_read, to_writer};
 use serde_plain::{derive_deserialize_from_fromstr, derive_serialize_from_display};
 use serde_utils::{deserialize_systemtime_seconds, serialize_systemtime_seconds};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
-use std::fs::{exists, File};
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{BufWriter, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fs::{exists, hard_link, remove_file, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
 use std::ops::Add;
-use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::Path;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::RwLock;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod aggregate;
+mod busy;
+mod cardinality;
+mod codes;
+mod compat;
 pub mod config;
+mod connection;
+mod email_key;
+mod entry_id;
+mod export;
+mod feeds;
+mod json;
+mod legacy;
+pub mod logging;
+pub mod middleware;
+mod notify;
+mod offline;
+pub mod parser;
+mod policy;
+mod postfix;
+mod promotion;
+mod proxy;
+mod recidivist;
+mod report;
+mod resources;
+mod roles;
 pub mod serde_utils;
+mod shards;
+pub mod snapshot;
+pub mod subnets;
+mod summary;
+pub mod testing;
+mod transcript;
+
+pub use connection::Listener;
+
+/// Why the daemon stops serving requests, sent by the signal handler.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// Save the data and exit, on SIGTERM and SIGINT
+    Shutdown,
+    /// Save the data and load the configuration and data again, on SIGHUP
+    Reload,
+}
+
+/// Why the daemon exits, logged and reported to systemd on exit. Each reason has its own exit
+/// code, so a supervisor can restart differently per cause.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    /// Stopped by SIGTERM or SIGINT
+    Signal,
+    /// No more connections can be accepted
+    Listener,
+    /// The configuration or sockets couldn't be set up again for a reload
+    Reload,
+    /// Loading or saving the data failed
+    Storage,
+    /// Any other error
+    Failure,
+}
+
+impl ExitReason {
+    /// The exit code, from sysexits.h for the failures.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ExitReason::Signal => 0,
+            ExitReason::Failure => 1,
+            ExitReason::Listener => 69,
+            ExitReason::Storage => 74,
+            ExitReason::Reload => 78,
+        }
+    }
+
+    /// The reason attached to the error with [anyhow::Context], otherwise a generic failure.
+    pub fn of(error: &anyhow::Error) -> ExitReason {
+        error
+            .downcast_ref::<ExitReason>()
+            .copied()
+            .unwrap_or(ExitReason::Failure)
+    }
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExitReason::Signal => "stopped by signal",
+            ExitReason::Listener => "no longer accepting connections",
+            ExitReason::Reload => "reload failed",
+            ExitReason::Storage => "fatal storage error",
+            ExitReason::Failure => "fatal error",
+        })
+    }
+}
 
 pub struct App {
     config: Config,
-    triplets: HashMap<u64, GreylistEntry>,
+    triplets: HashMap<EntryId, GreylistEntry>,
+    statistics: StoredStatistics,
+    /// Receives the data while it's loaded in the background
+    loading: Option<Receiver<Result<LoadedData, anyhow::Error>>>,
+    dump_on_exit: bool,
+    conflicts: Vec<Conflict>,
+    peers: HashMap<String, PeerStatistics>,
+    /// Answer all check and update requests with white, while still recording them
+    paused: bool,
+    /// Requests that failed with a panic since the last (re)start
+    panics: u64,
+    /// Connections closed for binary data or too long requests since the last (re)start
+    protocol_errors: u64,
+    /// Check and update requests answered without a decision as they exceeded
+    /// requestBudgetMs since the last (re)start
+    over_budget: u64,
+    /// Connections the accept thread failed to accept or answered with busy since the last
+    /// (re)start
+    accept_counters: Arc<AcceptCounters>,
+    throttles: Throttles,
+    /// Expired greylisted attempts per subnet, for the recidivist penalty
+    expired_attempts: HashMap<IpAddr, ExpiredAttempts>,
+    /// Ids of white triplets by each IPv6 aggregation prefix and the triplet's id at it
+    white_aggregates: HashMap<(u8, EntryId), EntryId>,
+    /// Connections of the clients selected for transcripts, for sampling
+    transcribed_connections: u64,
+    /// Retry history per subnet, stored in the subnetfile
+    subnets: HashMap<IpAddr, SubnetHistory>,
+    /// Answer all check and update requests with this status, without looking at the data
+    maintenance: Option<ListingStatus>,
+    /// Entries the expiry hook has been run for, with their last seen time at that point
+    notified: HashMap<EntryId, SystemTime>,
+    /// Whether entries changed since the last save, periodic saves are skipped otherwise
+    dirty: bool,
+    /// Distinct senders and recipients of updates since the last (re)start
+    diversity: Diversity,
+    /// Update requests rejected by the entries of each feed since the last (re)start
+    feed_hits: HashMap<String, u64>,
+    /// Recent requests, to defer saves while busy
+    request_rate: RequestRate,
+    /// Since when periodic saves are deferred while busy
+    deferred_save: Option<Instant>,
+    /// Time of the last prune and the number of entries it dropped from each list
+    last_prune: Option<(SystemTime, BTreeMap<String, u32>)>,
+    /// Connection backlog and the resources that are warned about
+    resources: ResourceMonitor,
+    /// Durations of the saves since the last report
+    save_times: SaveTimes,
+    /// Stages added by embedders, asked before the greylist in this order
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+/// Request counters of a single client identity.
+#[derive(Default)]
+struct PeerStatistics {
+    requests: u64,
+    errors: u64,
+}
+
+/// Two stored entries that map to the same entry id, detected while loading.
+struct Conflict {
+    hash: u64,
+    kept: Key,
+    dropped: Key,
+}
+
+/// Entries and statistics loaded from the state files.
+struct LoadedData {
+    triplets: HashMap<EntryId, GreylistEntry>,
     statistics: StoredStatistics,
+    conflicts: Vec<Conflict>,
+    subnets: HashMap<IpAddr, SubnetHistory>,
+    /// Whether the entries were imported from the Python greylistd and still need to be saved
+    /// in the format of greylistd-rs
+    imported: bool,
 }
 
 impl App {
     pub fn new(config: Config) -> Result<App, anyhow::Error> {
-        if !config.data.savetriplets {
-            return Err(anyhow!("Option savetriplets must be enabled"));
-        }
-        if config.data.singleupdate || config.data.singlecheck {
-            return Err(anyhow!(
-                "Options singleupdate and singlecheck aren't supported yet"
-            ));
+        check_config(&config)?;
+        let data = load_data(&config)?;
+        let mut app = App::with_config(config);
+        app.install(data);
+        Ok(app)
+    }
+
+    /// Create the app while the data is still loaded in the background, so requests can already
+    /// be answered. Until loading has finished, check and update are answered with the
+    /// configured loadingAnswer and other commands are refused.
+    pub fn new_loading(config: Config) -> Result<App, anyhow::Error> {
+        check_config(&config)?;
+        let (sender, receiver) = bounded(1);
+        let load_config = config.clone();
+        std::thread::spawn(move || {
+            // The receiver is gone if the daemon is stopped before loading finished
+            let _ = sender.send(load_data(&load_config));
+        });
+        let mut app = App::with_config(config);
+        app.loading = Some(receiver);
+        Ok(app)
+    }
+
+    /// Validate the configuration and load the state files without serving requests, for
+    /// `--check-config`.
+    pub fn check(config: &Config, writer: &mut impl Write) -> Result<(), anyhow::Error> {
+        check_config(config)?;
+        writeln!(writer, "Configuration {} is valid", config.path.display())?;
+        let data = load_data(config)?;
+        if legacy::legacy_files(config).is_some() {
+            writeln!(
+                writer,
+                "Loaded {} entries to import from {}",
+                data.triplets.len(),
+                legacy::LEGACY_DATA_DIR
+            )?;
+        } else {
+            writeln!(
+                writer,
+                "Loaded {} entries from {} and {}",
+                data.triplets.len(),
+                config.data.statefile.display(),
+                config.data.tripletfile.display()
+            )?;
         }
-        let (triplets, statistics) =
-            load_triplet_states(&config.data.tripletfile, &config.data.statefile)?;
+        Ok(())
+    }
 
-        let only_subnet = config.data.onlysubnet;
-        Ok(App {
+    fn with_config(config: Config) -> App {
+        let maintenance = config.policy.maintenance.clone();
+        let throttles = Throttles::new(config.log.rate_limit);
+        App {
             config,
-            triplets: triplets
-                .into_iter()
-                .map(|entry| (entry.triplet.hash(only_subnet), entry))
-                .collect(),
-            statistics,
-        })
+            triplets: HashMap::new(),
+            statistics: Default::default(),
+            loading: None,
+            dump_on_exit: false,
+            conflicts: Vec::new(),
+            peers: HashMap::new(),
+            paused: false,
+            panics: 0,
+            protocol_errors: 0,
+            over_budget: 0,
+            accept_counters: Arc::new(AcceptCounters::default()),
+            throttles,
+            expired_attempts: HashMap::new(),
+            white_aggregates: HashMap::new(),
+            transcribed_connections: 0,
+            subnets: HashMap::new(),
+            maintenance,
+            notified: HashMap::new(),
+            dirty: false,
+            diversity: Diversity::new(),
+            feed_hits: HashMap::new(),
+            request_rate: RequestRate::new(),
+            deferred_save: None,
+            last_prune: None,
+            resources: ResourceMonitor::new(),
+            save_times: SaveTimes::default(),
+            middleware: Vec::new(),
+        }
+    }
+
+    fn install(&mut self, data: LoadedData) {
+        self.triplets = data.triplets;
+        self.statistics = data.statistics;
+        self.conflicts = data.conflicts;
+        self.subnets = data.subnets;
+        self.rebuild_aggregates();
+        self.dirty |= data.imported;
     }
 
+    /// Write a full dump of all entries and statistics to stdout when the daemon exits.
+    pub fn set_dump_on_exit(&mut self, dump_on_exit: bool) {
+        self.dump_on_exit = dump_on_exit;
+    }
+
+    /// Serve requests on the listeners until a stop signal or a reload command is received.
+    /// Returns why the daemon exits, or None if the configuration and data should be reloaded.
+    ///
+    /// Errors of loading and saving the data have [ExitReason::Storage] attached.
     pub fn run(
         mut self,
-        listener: UnixListener,
-        stop_signal: Receiver<()>,
-    ) -> Result<bool, anyhow::Error> {
-        use crossbeam_channel::{select, unbounded};
-
-        let mut reload = false;
-        let should_exit = RwLock::new(false);
-        let (stream_sender, stream_receiver) = unbounded();
-        std::thread::scope(|s| -> Result<(), anyhow::Error> {
-            s.spawn(|| {
-                for stream in listener.incoming() {
-                    if *should_exit.read().unwrap() {
-                        return;
-                    }
-                    if stream_sender.send(stream.unwrap()).is_err() {
-                        return;
-                    }
-                }
+        listeners: &[Listener],
+        stop_signal: Receiver<Stop>,
+    ) -> Result<Option<ExitReason>, anyhow::Error> {
+        for listener in listeners {
+            listener.set_nonblocking()?;
+        }
+        let (wake_sender, wake_receiver) = UnixStream::pair()?;
+        let (stream_sender, stream_receiver) = bounded(self.config.socket.queue_length);
+        let busy_answers = listeners
+            .iter()
+            .map(|listener| self.busy_answer(listener))
+            .collect::<Result<Vec<_>, _>>()?;
+        let exit = std::thread::scope(|s| {
+            let wake_receiver = &wake_receiver;
+            let counters = Arc::clone(&self.accept_counters);
+            let busy_answers = &busy_answers;
+            s.spawn(move || {
+                accept_connections(
+                    listeners,
+                    busy_answers,
+                    wake_receiver,
+                    stream_sender,
+                    &counters,
+                )
             });
 
-            loop {
-                select! {
-                    recv(stream_receiver) -> stream => {
-                        match self.handle_client(stream?) {
-                            Err(e) => eprintln!("Failed to handle request: {:?}", e),
-                            Ok(result) => {
-                                if result {
-                        *should_exit.write().unwrap() = true;
-                                    reload = true;
-                                    break;
-                                }
-                            }
+            let result = self.serve(&stream_receiver, &stop_signal);
+            // The accept thread must be woken up in every case, otherwise the scope never ends
+            if let Err(e) = (&wake_sender).write_all(&[0]) {
+                log::error!("Failed to stop accepting connections: {}", e);
+            }
+            result
+        })?;
+
+        // Answer connections that were accepted before the accept thread stopped
+        for (stream, accepted) in stream_receiver.try_iter() {
+            if let Err(e) = self.handle_client(stream, accepted) {
+                self.throttles
+                    .failed_request
+                    .log(format_args!("Failed to handle request: {:?}", e));
+            }
+        }
+        self.throttles.flush();
+        if self.loading.is_some() {
+            // Saving now would replace the stored data with the empty state
+            log::warn!("Data hasn't finished loading, not saving");
+            return Ok(exit);
+        }
+        self.save().context(ExitReason::Storage)?;
+        if self.dump_on_exit && exit.is_some() {
+            self.dump(&mut std::io::stdout().lock())?;
+        }
+        Ok(exit)
+    }
+
+    /// Check that the configuration can be reloaded and log the changes, otherwise return the
+    /// reason it's refused.
+    fn check_reload(&self, force: bool) -> Result<(), (ResponseCode, String)> {
+        let config = self.config.reload().map_err(|e| {
+            (
+                ResponseCode::Failed,
+                format!("Failed to load configuration: {}", e),
+            )
+        })?;
+        let changes = self.config.diff(&config);
+        let unsafe_keys = changes
+            .iter()
+            .filter(|change| change.is_unsafe())
+            .map(|change| change.key)
+            .collect::<Vec<_>>();
+        if !unsafe_keys.is_empty() && !force {
+            return Err((
+                ResponseCode::Refused,
+                format!(
+                    "refusing to reload, changing {} invalidates the stored data (use reload --force)",
+                    unsafe_keys.join(", ")
+                ),
+            ));
+        }
+        for change in changes {
+            log::info!(
+                "Configuration change {}: {} -> {}",
+                change.key,
+                change.old,
+                change.new
+            );
+        }
+        Ok(())
+    }
+
+    fn serve(
+        &mut self,
+        streams: &Receiver<(Connection, Instant)>,
+        stop_signal: &Receiver<Stop>,
+    ) -> Result<Option<ExitReason>, anyhow::Error> {
+        let mut autosave = self.autosave_timer();
+        let mut last_save = self.statistics.lastsave;
+        let feeds = self.feed_timer();
+        let quiet = self.quiet_timer();
+        let resources = self.resource_timer();
+        let report = self.report_timer();
+        if self.loading.is_none() {
+            self.refresh_feeds(SystemTime::now());
+        }
+        let exit = loop {
+            let loading = self.loading.clone().unwrap_or_else(never);
+            select! {
+                recv(loading) -> data => {
+                    self.loading = None;
+                    match data {
+                        Ok(data) => self.install(data.context(ExitReason::Storage)?),
+                        Err(_) => {
+                            return Err(anyhow!("Loading the data failed").context(ExitReason::Storage))
                         }
+                    }
+                    self.refresh_feeds(SystemTime::now());
+                },
+                recv(streams) -> stream => {
+                    let Ok((stream, accepted)) = stream else {
+                        log::error!("No longer accepting connections, shutting down");
+                        break Some(ExitReason::Listener);
+                    };
+                    self.resources.record_backlog(streams.len());
+                    match self.handle_client(stream, accepted) {
+                        Err(e) => self
+                            .throttles
+                            .failed_request
+                            .log(format_args!("Failed to handle request: {:?}", e)),
+                        Ok(true) => break None,
+                        Ok(false) => {}
+                    }
+                },
+                recv(stop_signal) -> stop => match stop {
+                    Ok(Stop::Reload) => match self.check_reload(false) {
+                        Ok(()) => break None,
+                        Err((_, message)) => log::error!("Not reloading on SIGHUP: {}", message),
                     },
-                    recv(stop_signal) -> _ => {
-                        *should_exit.write().unwrap() = true;
-                        break
-                    },
-                }
+                    // A closed channel stops like SIGTERM
+                    Ok(Stop::Shutdown) | Err(_) => break Some(ExitReason::Signal),
+                },
+                recv(autosave) -> _ => {
+                    if self.loading.is_none() {
+                        self.autosave().context(ExitReason::Storage)?;
+                    }
+                },
+                recv(feeds) -> _ => {
+                    if self.loading.is_none() {
+                        self.refresh_feeds(SystemTime::now());
+                    }
+                },
+                recv(resources) -> _ => self.check_resources(),
+                recv(report) -> _ => {
+                    if self.loading.is_none() {
+                        self.report_if_due(SystemTime::now());
+                    }
+                },
+                recv(quiet) -> _ => {
+                    if self.deferred_save.is_some() && self.loading.is_none() {
+                        self.autosave().context(ExitReason::Storage)?;
+                    }
+                },
+            }
 
-                let last_save = self.statistics.lastsave;
-                let diff = SystemTime::now().duration_since(last_save)?;
-                if diff > self.config.data.update {
-                    self.save()?;
-                }
+            if self.config.data.update.is_zero()
+                && self.dirty
+                && self.loading.is_none()
+                && !self.defer_save()
+            {
+                self.save().context(ExitReason::Storage)?;
             }
-            self.save()?;
-            // connect to socket to trigger exit
-            UnixStream::connect(&self.config.socket.path)?;
-            Ok(())
-        })?;
-        Ok(reload)
+            if self.statistics.lastsave != last_save {
+                // Restart the interval after every save, including explicit ones
+                last_save = self.statistics.lastsave;
+                autosave = self.autosave_timer();
+            }
+        };
+        Ok(exit)
     }
 
-    fn prune_expired_entries(&mut self) {
-        let now = SystemTime::now();
+    fn prune_expired_entries(&mut self, now: SystemTime) {
         let oldest_retry = now - self.config.timeouts.retry_max;
-        let oldest_expire = now - self.config.timeouts.expire;
-        self.triplets.retain(|_, entry| match entry.listing_status {
-            ListingStatus::Grey => entry.triplet_status.first_seen > oldest_retry,
-            ListingStatus::White | ListingStatus::Black => {
-                entry.triplet_status.last_seen > oldest_expire
+        let oldest_white = now - self.config.timeouts.white_expire();
+        let oldest_black = now - self.config.timeouts.black_expire();
+        let oldest_quarantine = now - self.config.timeouts.quarantine_expire();
+        let oldest_custom = self
+            .config
+            .lists
+            .custom
+            .iter()
+            .map(|list| {
+                let expire = list.expire.unwrap_or(self.config.timeouts.expire);
+                (list.name.clone(), now - expire)
+            })
+            .collect::<HashMap<_, _>>();
+        let track_attempts = self.config.policy.recidivist_retry_min.is_some()
+            || self.config.data.subnetfile.is_some()
+            || self.config.report.interval.is_some();
+        let mut expired_attempts = Vec::new();
+        let mut expired_grey = 0;
+        let mut dropped = self
+            .statuses()
+            .iter()
+            .map(|status| (status.to_string(), 0))
+            .collect::<BTreeMap<_, _>>();
+        let count = self.triplets.len();
+        self.triplets.retain(|_, entry| {
+            let keep = match entry.lifetime {
+                Lifetime::Permanent => true,
+                Lifetime::Until(until) => until > now,
+                Lifetime::Timeout => match entry.listing_status {
+                    ListingStatus::Grey => {
+                        let keep = entry.triplet_status.first_seen > oldest_retry;
+                        if !keep {
+                            expired_grey += 1;
+                            if track_attempts {
+                                expired_attempts.push(entry.key.clone());
+                            }
+                        }
+                        keep
+                    }
+                    ListingStatus::White => entry.triplet_status.last_seen > oldest_white,
+                    ListingStatus::Black => entry.triplet_status.last_seen > oldest_black,
+                    ListingStatus::Quarantine => entry.triplet_status.last_seen > oldest_quarantine,
+                    ListingStatus::Custom(ref name) => oldest_custom
+                        .get(name)
+                        .map_or(true, |oldest| entry.triplet_status.last_seen > *oldest),
+                },
+            };
+            if !keep {
+                *dropped.entry(entry.listing_status.to_string()).or_default() += 1;
             }
+            keep
         });
+        self.dirty |= self.triplets.len() != count;
+        self.statistics.expired += expired_grey;
+        self.last_prune = Some((now, dropped));
+        for key in expired_attempts {
+            self.record_expired_attempt(&key, now);
+            self.record_subnet_outcome(&key, Outcome::Expired, now);
+        }
+        self.prune_expired_attempts(now);
+        self.prune_subnets(now);
+        self.rebuild_aggregates();
     }
 
     fn save(&mut self) -> Result<(), anyhow::Error> {
-        self.prune_expired_entries();
-        let now = SystemTime::now();
+        self.save_at(SystemTime::now())
+    }
+
+    /// Fires every update interval, an interval of 0 saves after every change instead.
+    fn autosave_timer(&self) -> Receiver<Instant> {
+        match self.config.data.update {
+            interval if interval.is_zero() => never(),
+            interval => tick(interval),
+        }
+    }
+
+    /// Prune the data and save it if anything changed since the last save, unless saves are
+    /// deferred while busy.
+    fn autosave(&mut self) -> Result<(), anyhow::Error> {
+        if self.defer_save() {
+            return Ok(());
+        }
+        self.deferred_save = None;
+        self.prune_expired_entries(SystemTime::now());
+        if self.dirty {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save_at(&mut self, now: SystemTime) -> Result<(), anyhow::Error> {
+        let started = Instant::now();
+        self.prune_expired_entries(now);
+        self.notify_expiring_entries(now);
         let triplets = self
             .triplets
             .iter()
-            .map(|(hash, entry)| (hash.to_string(), &entry.triplet))
+            .filter(|(_, entry)| !matches!(entry.key, Key::Hash(_)))
+            .map(|(id, entry)| {
+                (
+                    id.stable_hash().to_string(),
+                    self.stored_key(id, &entry.key),
+                )
+            })
             .collect::<HashMap<_, _>>();
 
         let white = self
             .triplets
             .iter()
             .filter(|(_, entry)| entry.listing_status == ListingStatus::White)
-            .map(|(hash, entry)| (hash.to_string(), entry.triplet_status.clone()))
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.triplet_status.clone()))
             .collect::<HashMap<_, _>>();
         let grey = self
             .triplets
             .iter()
             .filter(|(_, entry)| entry.listing_status == ListingStatus::Grey)
-            .map(|(hash, entry)| (hash.to_string(), entry.triplet_status.clone()))
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.triplet_status.clone()))
             .collect::<HashMap<_, _>>();
         let black = self
             .triplets
             .iter()
             .filter(|(_, entry)| entry.listing_status == ListingStatus::Black)
-            .map(|(hash, entry)| (hash.to_string(), entry.triplet_status.clone()))
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.triplet_status.clone()))
+            .collect::<HashMap<_, _>>();
+        let quarantine = self
+            .triplets
+            .iter()
+            .filter(|(_, entry)| entry.listing_status == ListingStatus::Quarantine)
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.triplet_status.clone()))
+            .collect::<HashMap<_, _>>();
+        let custom = self
+            .triplets
+            .iter()
+            .filter_map(|(id, entry)| match &entry.listing_status {
+                ListingStatus::Custom(list) => Some((
+                    id.stable_hash().to_string(),
+                    CustomStatus {
+                        list: list.clone(),
+                        status: entry.triplet_status.clone(),
+                    },
+                )),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let reasons = self
+            .triplets
+            .iter()
+            .filter(|(_, entry)| entry.reason != Reason::default_for(&entry.listing_status))
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.reason.clone()))
+            .collect::<HashMap<_, _>>();
+        let lifetimes = self
+            .triplets
+            .iter()
+            .filter(|(_, entry)| !entry.lifetime.is_automatic())
+            .map(|(id, entry)| (id.stable_hash().to_string(), entry.lifetime))
             .collect::<HashMap<_, _>>();
         self.statistics.lastsave = now;
+        self.deferred_save = None;
         let state = StoredStates {
             statistics: self.statistics.clone(),
             white,
             grey,
             black,
+            quarantine,
+            custom,
+            reasons,
+            lifetimes,
+        };
+
+        let (triplets, state) = shards::write_shards(&self.config.data, triplets, state)?;
+
+        // The triplets are written first, so every stored state has its triplet
+        let tripletfile = &self.config.data.tripletfile;
+        if self.config.data.savetriplets {
+            write_data_file(tripletfile, &triplets)?;
+        } else {
+            // Don't keep addresses from before savetriplets was disabled
+            for file in [tripletfile.clone(), backup_path(tripletfile)] {
+                if exists(&file)? {
+                    remove_file(&file)?;
+                }
+            }
+        }
+        write_data_file(&self.config.data.statefile, &state)?;
+        self.save_subnets()?;
+        self.dirty = false;
+        self.save_times.record(started.elapsed());
+        self.export_white();
+
+        // Saving after every change would flood the log
+        let level = if self.config.data.update.is_zero() {
+            Level::Debug
+        } else {
+            Level::Info
         };
+        log::log!(
+            level,
+            "Saved {} entries to {} in {} ms",
+            self.triplets.len(),
+            self.config.data.statefile.display(),
+            started.elapsed().as_millis()
+        );
+        Ok(())
+    }
 
-        let triplet_file = File::create(&self.config.data.tripletfile)?;
-        let state_file = File::create(&self.config.data.statefile)?;
-        to_writer(triplet_file, &triplets)?;
-        to_writer(state_file, &state)?;
+    /// An empty copy of the app with the same configuration, for evaluating hypothetical events.
+    fn scratch(&self) -> App {
+        App::with_config(self.config.clone())
+    }
+
+    fn dump(&self, writer: &mut impl Write) -> Result<(), anyhow::Error> {
+        let mut entries = self.triplets.values().collect::<Vec<_>>();
+        entries
+            .sort_by_cached_key(|entry| (entry.listing_status.to_string(), entry.key.to_string()));
 
+        writeln!(
+            writer,
+            "greylistd dump at {}",
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+        )?;
+        writeln!(
+            writer,
+            "statistics: start={} lastsave={} white={} grey={} black={} expired={} quarantine={}",
+            self.statistics.start.duration_since(UNIX_EPOCH)?.as_secs(),
+            self.statistics
+                .lastsave
+                .duration_since(UNIX_EPOCH)?
+                .as_secs(),
+            self.statistics.white,
+            self.statistics.grey,
+            self.statistics.black,
+            self.statistics.expired,
+            self.statistics.quarantine,
+        )?;
+        writeln!(writer, "entries: {}", entries.len())?;
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "Status     Last Seen            First Seen           Count      Reason         Data"
+        )?;
+        for entry in entries {
+            writeln!(
+                writer,
+                "{: <10} {: <20} {: <20} {: <10} {: <14} {}",
+                entry.listing_status.to_string(),
+                entry
+                    .triplet_status
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs(),
+                entry
+                    .triplet_status
+                    .first_seen
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs(),
+                entry.triplet_status.count,
+                entry.reason.to_string(),
+                entry.key
+            )?;
+        }
+        writer.flush()?;
         Ok(())
     }
 
-    fn handle_client(&mut self, mut stream: UnixStream) -> Result<bool, anyhow::Error> {
+    /// Handle the requests of a connection, the first one is timed from when it was accepted.
+    fn handle_client(
+        &mut self,
+        mut stream: Connection,
+        accepted: Instant,
+    ) -> Result<bool, anyhow::Error> {
+        let mut peer = stream.peer();
+        if let Connection::Tcp(tcp) = &stream {
+            if !self.is_tcp_allowed(tcp) {
+                self.throttles
+                    .refused_connection
+                    .log(format_args!("Refused TCP connection from {}", peer));
+                return Ok(false);
+            }
+            if self.config.socket.tcp_proxy_protocol {
+                stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+                match proxy::read_header(&mut stream) {
+                    Ok(Some(client)) => peer = Peer::ip(client),
+                    // Health checks of the proxy are counted for the proxy itself
+                    Ok(None) => {}
+                    Err(e) => {
+                        self.protocol_errors += 1;
+                        self.peers.entry(peer.to_string()).or_default().errors += 1;
+                        self.throttles
+                            .invalid_command
+                            .log(format_args!("Closed connection from {}: {}", peer, e));
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        self.peers.entry(peer.to_string()).or_default().requests += 1;
+        self.request_rate.record(Instant::now());
+        // A panic only fails this request, the connection is closed without an answer
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.handle_request(stream, &peer, accepted)
+        }))
+        .unwrap_or_else(|_| {
+            self.panics += 1;
+            Err(anyhow!("Request handler panicked"))
+        });
+        if result.is_err() {
+            self.peers.entry(peer.to_string()).or_default().errors += 1;
+        }
+        result
+    }
+
+    /// Answer the requests of a connection, one per line, or Postfix policy requests if the
+    /// first line is an attribute.
+    ///
+    /// The answer to a newline terminated request ends with a newline, so clients can send
+    /// several requests. A request ended by closing the connection is answered as is, like the
+    /// original greylistd does.
+    fn handle_request(
+        &mut self,
+        stream: Connection,
+        peer: &Peer,
+        accepted: Instant,
+    ) -> Result<bool, anyhow::Error> {
         stream.set_read_timeout(Some(Duration::from_secs(2)))?;
-        let mut buf = vec![0; 16384];
-        let n = stream.read(&mut buf)?;
-        buf.truncate(n);
-        let line = String::from_utf8(buf)?;
-        // let Some(line) = BufReader::new(stream.try_clone()?).lines().next() else {
-        //     return Err(anyhow!("Empty request"));
-        // };
-        let cmd = line.parse::<Command>();
-        let mut writer = BufWriter::new(stream);
-        if let Ok(cmd) = cmd {
-            match cmd {
+        // A remote client that stops reading mustn't hold up the others either
+        stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+        let codes = match stream {
+            Connection::Unix(_) => self.config.socket.response_codes,
+            Connection::Tcp(_) => self.config.socket.tcp_response_codes,
+        };
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let transcript = self.transcript(peer);
+        let mut writer = Transcribed::new(BufWriter::new(stream), transcript);
+        if self.config.socket.banner {
+            writeln!(writer, "{}", banner(codes))?;
+            writer.flush()?;
+        }
+        let mut line = Vec::new();
+        let mut handled = false;
+        loop {
+            line.clear();
+            let limit = MAX_REQUEST_LENGTH as u64 + 1;
+            match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+                Ok(_) => {}
+                Err(e) if handled && is_timeout(&e) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            writer.request(&line);
+            let terminated = line.ends_with(b"\n");
+            if terminated {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            } else if line.len() > MAX_REQUEST_LENGTH {
+                self.protocol_errors += 1;
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                self.throttles
+                    .invalid_command
+                    .log(format_args!("Too long request from {}", peer));
+                let answer = format!(
+                    "Request too long, at most {} bytes allowed",
+                    MAX_REQUEST_LENGTH
+                );
+                write_answer(
+                    &mut writer,
+                    codes.then_some(ResponseCode::TooLong),
+                    answer.as_bytes(),
+                )?;
+                return Ok(false);
+            } else if line.is_empty() && handled {
+                return Ok(false);
+            }
+            if is_binary(&line) {
+                self.protocol_errors += 1;
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                self.throttles
+                    .invalid_command
+                    .log(format_args!("Binary data from {}", peer));
+                write_answer(
+                    &mut writer,
+                    codes.then_some(ResponseCode::Binary),
+                    b"Protocol error: binary data received",
+                )?;
+                writer.flush()?;
+                return Ok(false);
+            }
+            if !handled && terminated && postfix::is_policy_request(&line) {
+                return self.handle_policy_requests(
+                    &mut reader,
+                    &mut writer,
+                    &line,
+                    peer,
+                    accepted,
+                );
+            }
+            // The client waited for the first request since connecting, e.g. in the queue
+            let received = if handled { Instant::now() } else { accepted };
+            handled = true;
+
+            let reload = if codes {
+                let mut answer = Vec::new();
+                let handled = self.handle_command(&line, &mut answer, peer, received)?;
+                write_answer(&mut writer, Some(handled.code), &answer)?;
+                handled.reload
+            } else {
+                self.handle_command(&line, &mut writer, peer, received)?
+                    .reload
+            };
+            if terminated {
+                writeln!(writer)?;
+                reader.get_ref().set_read_timeout(Some(IDLE_TIMEOUT))?;
+            }
+            writer.flush()?;
+            if reload || !terminated {
+                return Ok(reload);
+            }
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        request: &[u8],
+        writer: &mut impl Write,
+        peer: &Peer,
+        received: Instant,
+    ) -> Result<Handled, anyhow::Error> {
+        self.run_command(parser::parse_request(request), writer, peer, received)
+    }
+
+    /// Answer a parsed request, or the error it failed to parse with.
+    fn run_command(
+        &mut self,
+        cmd: Result<Command, anyhow::Error>,
+        writer: &mut impl Write,
+        peer: &Peer,
+        received: Instant,
+    ) -> Result<Handled, anyhow::Error> {
+        let mut code = ResponseCode::Ok;
+        match cmd {
+            Ok(cmd) if !self.is_allowed(&cmd) => {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                code = ResponseCode::Denied;
+                write!(writer, "permission denied: {} is not allowed", cmd.name())?;
+            }
+            Ok(cmd) if cmd.needs_admin() && !self.is_admin(peer) => {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                code = ResponseCode::Denied;
+                self.throttles.denied_command.log(format_args!(
+                    "Denied {} command from {}",
+                    cmd.name(),
+                    peer
+                ));
+                write!(
+                    writer,
+                    "permission denied: {} is only allowed for admins",
+                    cmd.name()
+                )?;
+            }
+            Ok(Command::Update { time: Some(_), .. } | Command::Check { time: Some(_), .. })
+                if !self.is_admin(peer) =>
+            {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                code = ResponseCode::Denied;
+                write!(
+                    writer,
+                    "permission denied: time= is only allowed for admins"
+                )?;
+            }
+            Ok(cmd) if self.unknown_list(&cmd).is_some() => {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                code = ResponseCode::UnknownList;
+                write!(
+                    writer,
+                    "Unknown list: {}",
+                    self.unknown_list(&cmd).unwrap_or_default()
+                )?;
+            }
+            Ok(cmd)
+                if self.loading.is_some()
+                    && !matches!(
+                        cmd,
+                        Command::Maintenance { .. }
+                            | Command::Pause
+                            | Command::Resume
+                            | Command::Update { .. }
+                            | Command::Check { .. }
+                    ) =>
+            {
+                code = ResponseCode::Unavailable;
+                write!(
+                    writer,
+                    "greylistd is still loading its data, try again later"
+                )?;
+            }
+            Ok(cmd) => match cmd {
                 Command::Update {
                     triplet,
                     check_status,
+                    retry_hint,
+                    time,
+                    role,
                 } => {
-                    let entry = self.add_or_update_triplet(triplet);
-                    if let Some(status) = check_status {
-                        if entry.listing_status == status {
-                            write!(writer, "true")?;
-                        } else {
-                            write!(writer, "false")?;
+                    let key = Key::Triplet(triplet);
+                    let request = DecisionRequest {
+                        operation: Operation::Update,
+                        role: self.role_for(&key, role),
+                        key,
+                        now: time.unwrap_or_else(SystemTime::now),
+                    };
+                    let decision = self.decide(&request, peer, received);
+                    self.write_decision(
+                        writer,
+                        &request,
+                        &decision,
+                        check_status,
+                        false,
+                        retry_hint,
+                    )?;
+                }
+                Command::Maintenance { status } => {
+                    match &status {
+                        Some(status) => write!(
+                            writer,
+                            "maintenance mode enabled, all requests are answered with {}",
+                            status
+                        )?,
+                        None => write!(writer, "maintenance mode disabled")?,
+                    }
+                    self.maintenance = status;
+                }
+                Command::Pause => {
+                    self.paused = true;
+                    write!(
+                        writer,
+                        "greylisting paused, all requests are answered with white until resume"
+                    )?;
+                }
+                Command::Resume => {
+                    self.paused = false;
+                    write!(writer, "greylisting resumed")?;
+                }
+                Command::Dump { to } => {
+                    if let Some(to) = to {
+                        // Never replace an existing file, the daemon may run as root
+                        let file = OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .mode(0o600)
+                            .open(&to);
+                        match file {
+                            Ok(file) => {
+                                self.dump(&mut BufWriter::new(file))?;
+                                write!(
+                                    writer,
+                                    "greylistd data has been dumped to {}",
+                                    to.display()
+                                )?;
+                            }
+                            Err(e) => {
+                                code = ResponseCode::Failed;
+                                write!(writer, "Failed to create {}: {}", to.display(), e)?;
+                            }
                         }
                     } else {
-                        write!(writer, "{}", entry.listing_status)?;
+                        self.dump(writer)?;
+                    }
+                }
+                Command::Debug(DebugCommand::Resources) => self.write_resources(writer)?,
+                Command::Debug(DebugCommand::Conflicts) => {
+                    writeln!(
+                        writer,
+                        "{} conflicting entries during load",
+                        self.conflicts.len()
+                    )?;
+                    for conflict in &self.conflicts {
+                        writeln!(
+                            writer,
+                            "{}: kept {} dropped {}",
+                            conflict.hash, conflict.kept, conflict.dropped
+                        )?;
+                    }
+                }
+                Command::Summarize { min_entries, apply } => {
+                    self.summarize_networks(min_entries, apply, writer)?;
+                }
+                Command::Subnet { .. } if self.config.data.subnetfile.is_none() => {
+                    code = ResponseCode::Failed;
+                    write!(
+                        writer,
+                        "No subnet history is kept, set subnetfile in the [data] section"
+                    )?;
+                }
+                Command::Subnet {
+                    network,
+                    json: true,
+                } => self.write_subnets_json(&network, writer)?,
+                Command::Subnet { network, .. } => {
+                    if !self.write_subnets(&network, writer)? {
+                        code = ResponseCode::Unseen;
+                    }
+                }
+                Command::PolicyTest { file } => {
+                    let result = policy::check_scenario_owner(&file, peer)
+                        .and_then(|_| policy::test_scenarios(self, &file, writer));
+                    if let Err(e) = result {
+                        code = ResponseCode::Failed;
+                        write!(writer, "Policy test failed: {}", e)?;
                     }
                 }
                 Command::Save => {
@@ -195,70 +1116,151 @@ impl App {
                     write!(writer, "greylistd data has been saved")?;
                 }
                 Command::Check {
-                    triplet,
+                    key,
                     check_status,
+                    detailed,
+                    retry_hint,
+                    time,
+                    role,
                 } => {
-                    let status = self.check_triplet(triplet);
-                    if let Some(check_status) = check_status {
-                        if status == check_status {
-                            write!(writer, "true")?;
-                        } else {
-                            write!(writer, "false")?;
-                        }
+                    let request = DecisionRequest {
+                        operation: Operation::Check,
+                        role: self.role_for(&key, role),
+                        key,
+                        now: time.unwrap_or_else(SystemTime::now),
+                    };
+                    let decision = self.decide(&request, peer, received);
+                    self.write_decision(
+                        writer,
+                        &request,
+                        &decision,
+                        check_status,
+                        detailed,
+                        retry_hint,
+                    )?;
+                }
+                Command::Outbound { reply } => {
+                    if self.add_outbound(reply, SystemTime::now()) {
+                        write!(writer, "Added to whitelist")?;
                     } else {
-                        write!(writer, "{}", status)?;
+                        write!(writer, "Not added, the reply is blacklisted")?;
                     }
                 }
                 Command::Add {
-                    triplet,
+                    key,
                     add_status,
+                    ttl,
                 } => {
-                    self.add_triplet(triplet, add_status.clone());
-                    write!(writer, "Added to {}list", add_status)?;
+                    let now = SystemTime::now();
+                    let lifetime = manual_lifetime(ttl, now);
+                    self.add_entry(key, add_status.clone(), Reason::Manual, lifetime, now);
+                    write!(writer, "Added to {}", add_status.list_name())?;
                 }
-                Command::List { status } => {
+                Command::List {
+                    status,
+                    reasons,
+                    json,
+                    cursor,
+                    filter,
+                    count,
+                } => {
                     let status = if status.is_empty() {
-                        &[
-                            ListingStatus::White,
-                            ListingStatus::Grey,
-                            ListingStatus::Black,
-                        ][..]
+                        self.statuses()
                     } else {
-                        &status
+                        status
                     };
+                    let status = &status[..];
+                    let (entries, next_cursor) = match cursor {
+                        Some(cursor) => self.list_page(status, &filter, cursor),
+                        None => (self.list_entries(status, &filter), None),
+                    };
+                    if count {
+                        let counts = status
+                            .iter()
+                            .map(|list_status| {
+                                let matching = entries
+                                    .iter()
+                                    .filter(|entry| entry.listing_status == *list_status)
+                                    .count();
+                                (list_status, matching)
+                            })
+                            .collect::<Vec<_>>();
+                        if json {
+                            json::write_list_counts(&counts, writer)?;
+                        } else {
+                            for (list_status, matching) in counts {
+                                writeln!(writer, "{}: {}", list_status.list_name(), matching)?;
+                            }
+                        }
+                        return Ok(Handled::answered(code));
+                    }
+                    if json {
+                        if cursor.is_some() {
+                            json::write_list_page(&entries, reasons, next_cursor, writer)?;
+                        } else {
+                            json::write_list(&entries, reasons, writer)?;
+                        }
+                        return Ok(Handled::answered(code));
+                    }
                     for list_status in status {
-                        writeln!(writer, "{}list data:", list_status)?;
+                        writeln!(writer, "{} data:", list_status.list_name())?;
                         writeln!(writer, "=============")?;
-                        writeln!(writer, "Last Seen            Count      Data")?;
-                        for entry in self.triplets.values() {
+                        if reasons {
+                            writeln!(
+                                writer,
+                                "Last Seen            Count      Reason         Expires              Data"
+                            )?;
+                        } else {
+                            writeln!(writer, "Last Seen            Count      Data")?;
+                        }
+                        for entry in &entries {
                             if entry.listing_status != *list_status {
                                 continue;
                             }
-                            writeln!(
+                            write!(
                                 writer,
-                                "{: <20?} {: <10} {}",
-                                entry
-                                    .triplet_status
-                                    .last_seen
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
+                                "{: <20} {: <10} ",
+                                self.format_time(entry.triplet_status.last_seen),
                                 entry.triplet_status.count,
-                                entry.triplet
                             )?;
+                            if reasons {
+                                let expires = match entry.lifetime {
+                                    Lifetime::Timeout => "-".to_string(),
+                                    Lifetime::Permanent => "never".to_string(),
+                                    Lifetime::Until(until) => self.format_time(until),
+                                };
+                                write!(
+                                    writer,
+                                    "{: <14} {: <20} ",
+                                    entry.reason.to_string(),
+                                    expires
+                                )?;
+                            }
+                            writeln!(writer, "{}", entry.key)?;
                         }
                         writeln!(writer)?
                     }
+                    if cursor.is_some() {
+                        match next_cursor {
+                            Some(next_cursor) => {
+                                writeln!(writer, "Next cursor: {:016x}", next_cursor)?
+                            }
+                            None => writeln!(writer, "Next cursor: none")?,
+                        }
+                    }
                 }
-                Command::Delete { triplet } => {
-                    let entry = self.triplets.remove(&self.hash_triplet(&triplet));
+                Command::Delete { key } => {
+                    let entry = self.triplets.remove(&self.entry_id(&key));
                     if let Some(entry) = entry {
-                        write!(writer, "Removed from {}list", entry.listing_status)?;
+                        self.dirty = true;
+                        write!(writer, "Removed from {}", entry.listing_status.list_name())?;
                     } else {
+                        code = ResponseCode::Unseen;
                         write!(writer, "Not found")?;
                     }
                 }
                 Command::Clear { status } => {
+                    self.dirty = true;
                     if status.is_empty() {
                         self.triplets.drain();
                         self.statistics = Default::default();
@@ -268,84 +1270,224 @@ impl App {
                     }
                     write!(writer, "data and statistics cleared")?;
                 }
-                Command::Reload => {
+                Command::Reload { force } => {
+                    if let Err((code, message)) = self.check_reload(force) {
+                        write!(writer, "{}", message)?;
+                        return Ok(Handled::answered(code));
+                    }
                     write!(writer, "reloading configuration and data")?;
-                    return Ok(true);
+                    writer.flush()?;
+                    return Ok(Handled { code, reload: true });
                 }
-                Command::Status { triplet } => {
-                    if let Some(entry) = self.get_entry(&triplet) {
+                Command::Status { key, json: true } => self.write_status_json(&key, writer)?,
+                Command::Status { key, .. } => {
+                    if let Some(entry) = self.get_entry(&key) {
                         write!(writer, "{}", entry.listing_status)?;
                     } else {
+                        code = ResponseCode::Unseen;
                         write!(writer, "unseen")?;
                     };
                 }
-                Command::Stats => {
-                    writeln!(
-                        writer,
-                        "Statistics since {} ({}s ago)",
-                        self.statistics
-                            .start
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                        SystemTime::now()
-                            .duration_since(self.statistics.start)
-                            .unwrap()
-                            .as_secs(),
-                    )?;
-                    writeln!(writer)?;
-                    for state in [
-                        ListingStatus::White,
-                        ListingStatus::Grey,
-                        ListingStatus::Black,
-                    ] {
-                        let (item_count, request_count) = self
-                            .triplets
-                            .iter()
-                            .filter(|(_, e)| e.listing_status == state)
-                            .fold((0, 0), |(item, req), (_, e)| {
-                                (item + 1, req + e.triplet_status.count)
-                            });
+                Command::Stats {
+                    clients,
+                    sources,
+                    feeds,
+                    json: true,
+                } => self.write_stats_json(clients, sources, feeds, writer)?,
+                Command::Stats { feeds: true, .. } => self.write_feed_stats(writer)?,
+                Command::Stats { clients: true, .. } => {
+                    let mut peers = self.peers.iter().collect::<Vec<_>>();
+                    peers.sort_by(|(a_name, a), (b_name, b)| {
+                        b.requests.cmp(&a.requests).then(a_name.cmp(b_name))
+                    });
+                    writeln!(writer, "Requests per client since last (re)start:")?;
+                    for (peer, stats) in peers {
                         writeln!(
                             writer,
-                            "{} items, matching {} requests, are currently {}listed",
-                            item_count, request_count, state
+                            "{}: {} requests, {} errors",
+                            peer, stats.requests, stats.errors
                         )?;
                     }
-                    writeln!(writer)?;
-
-                    let grey_count = self
-                        .triplets
-                        .iter()
-                        .filter(|(_, e)| e.listing_status == ListingStatus::Grey)
-                        .count() as u32;
-                    let previous_grey = self.statistics.grey - grey_count;
-                    let expired_grey = previous_grey - self.statistics.white;
-
-                    writeln!(
-                        writer,
-                        "Of {} items that were initially greylisted:",
-                        previous_grey
-                    )?;
-
-                    writeln!(
-                        writer,
-                        " - {} ({:.1}%) became whitelisted",
-                        self.statistics.white,
-                        self.statistics.white as f64 * 100.0 / previous_grey as f64
+                }
+                Command::Stats { sources: true, .. } => {
+                    writeln!(writer, "Listed items by source:")?;
+                    for state in self.statuses() {
+                        let (auto, manual, imported) = self.count_by_source(&state);
+                        writeln!(
+                            writer,
+                            "{}: {} auto, {} manual, {} imported",
+                            state.participle(),
+                            auto,
+                            manual,
+                            imported
+                        )?;
+                    }
+                }
+                Command::Stats { .. } => {
+                    if let Some(status) = &self.maintenance {
+                        writeln!(
+                            writer,
+                            "Maintenance mode is enabled, all requests are answered with {}",
+                            status
+                        )?;
+                    }
+                    if self.paused {
+                        writeln!(
+                            writer,
+                            "Greylisting is paused, all requests are answered with white"
+                        )?;
+                    }
+                    if self.panics > 0 {
+                        writeln!(
+                            writer,
+                            "{} requests failed with an internal error since last (re)start",
+                            self.panics
+                        )?;
+                    }
+                    if self.protocol_errors > 0 {
+                        writeln!(
+                            writer,
+                            "{} connections were closed for binary data or too long requests since last (re)start",
+                            self.protocol_errors
+                        )?;
+                    }
+                    let accept_failures = self.accept_counters.failures.load(Ordering::Relaxed);
+                    if accept_failures > 0 {
+                        writeln!(
+                            writer,
+                            "{} connections failed to be accepted since last (re)start",
+                            accept_failures
+                        )?;
+                    }
+                    let busy = self.accept_counters.busy.load(Ordering::Relaxed);
+                    if busy > 0 {
+                        writeln!(
+                            writer,
+                            "{} connections were answered with busy since last (re)start",
+                            busy
+                        )?;
+                    }
+                    if self.over_budget > 0 {
+                        writeln!(
+                            writer,
+                            "{} requests exceeded the request budget since last (re)start",
+                            self.over_budget
+                        )?;
+                    }
+                    let uptime = SystemTime::now()
+                        .duration_since(self.statistics.start)
+                        .unwrap();
+                    match self.config.socket.compat {
+                        Compat::Native => writeln!(
+                            writer,
+                            "Statistics since {} ({}s ago)",
+                            self.format_time(self.statistics.start),
+                            uptime.as_secs(),
+                        )?,
+                        Compat::Python => writeln!(
+                            writer,
+                            "Statistics since {} ({} ago)",
+                            self.format_time(self.statistics.start),
+                            compat::format_duration(uptime),
+                        )?,
+                    }
+                    writeln!(writer)?;
+                    for state in self.statuses() {
+                        let (item_count, request_count) = self.count_by_status(&state);
+                        writeln!(
+                            writer,
+                            "{} items, matching {} requests, are currently {}",
+                            item_count,
+                            request_count,
+                            state.participle()
+                        )?;
+                    }
+                    writeln!(writer)?;
+
+                    // Greylisted items that were whitelisted or expired since, the others are
+                    // still waiting or were deleted
+                    let white = self.statistics.white;
+                    let expired = self.statistics.expired;
+                    let previous_grey = white.saturating_add(expired);
+                    if previous_grey == 0 {
+                        writeln!(
+                            writer,
+                            "No greylisted items have been whitelisted or expired yet"
+                        )?;
+                    } else {
+                        writeln!(
+                            writer,
+                            "Of {} items that were initially greylisted:",
+                            previous_grey
+                        )?;
+                        writeln!(
+                            writer,
+                            " - {} ({:.1}%) became whitelisted",
+                            white,
+                            white as f64 * 100.0 / previous_grey as f64
+                        )?;
+                        writeln!(
+                            writer,
+                            " - {} ({:.1}%) expired from the greylist",
+                            expired,
+                            expired as f64 * 100.0 / previous_grey as f64
+                        )?;
+                    }
+                    writeln!(writer)?;
+
+                    writeln!(
+                        writer,
+                        "{} checks found greylisted items that an update would have whitelisted",
+                        self.statistics.would_pass
                     )?;
+                    writeln!(
+                        writer,
+                        "{} items were put into quarantine",
+                        self.statistics.quarantine
+                    )?;
+                    writeln!(
+                        writer,
+                        "{} items were put on the blacklist",
+                        self.statistics.black
+                    )?;
+                    writeln!(writer)?;
 
                     writeln!(
                         writer,
-                        " - {} ({:.1}%) expired from the greylist",
-                        expired_grey,
-                        expired_grey as f64 * 100.0 / previous_grey as f64
+                        "About {} sender IPs, {} sender domains and {} recipients were seen since last (re)start",
+                        self.diversity.sender_ips.estimate(),
+                        self.diversity.sender_domains.estimate(),
+                        self.diversity.recipients.estimate()
                     )?;
+                    if let Some((time, dropped)) = &self.last_prune {
+                        let dropped = self
+                            .statuses()
+                            .iter()
+                            .map(|status| {
+                                let status = status.to_string();
+                                let count = dropped.get(&status).copied().unwrap_or(0);
+                                format!("{} {}", count, status)
+                            })
+                            .collect::<Vec<_>>();
+                        writeln!(
+                            writer,
+                            "The last prune at {} dropped {} entries",
+                            self.format_time(*time),
+                            dropped.join(", ")
+                        )?;
+                    }
                 }
-                Command::Mrtg => {
-                    self.prune_expired_entries();
-                    writeln!(writer, "{}", self.statistics.grey)?;
-                    writeln!(writer, "{}", self.statistics.white)?;
+                Command::Mrtg { status } => {
+                    self.prune_expired_entries(SystemTime::now());
+                    if let Some(status) = status {
+                        // MRTG takes two values, the imported entries are in stats --sources
+                        let (auto, manual, _) = self.count_by_source(&status);
+                        writeln!(writer, "{}", auto)?;
+                        writeln!(writer, "{}", manual)?;
+                    } else {
+                        writeln!(writer, "{}", self.statistics.grey)?;
+                        writeln!(writer, "{}", self.statistics.white)?;
+                    }
                     writeln!(
                         writer,
                         "{}",
@@ -356,152 +1498,1084 @@ impl App {
                     )?;
                     writeln!(writer, "hostname")?;
                 }
+            },
+            Err(e) => {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                self.throttles
+                    .invalid_command
+                    .log(format_args!("Invalid command from {}: {}", peer, e));
+                code = if e.is::<InvalidKey>() {
+                    ResponseCode::BadTriplet
+                } else {
+                    ResponseCode::BadRequest
+                };
+                write!(writer, "Invalid command: {}", e)?;
+            }
+        };
+        Ok(Handled::answered(code))
+    }
+
+    /// Format a timestamp for command output, as seconds since the epoch or, in Python
+    /// compatibility mode, as local time like the original greylistd.
+    fn format_time(&self, time: SystemTime) -> String {
+        match self.config.socket.compat {
+            Compat::Native => time
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                .to_string(),
+            Compat::Python => compat::format_local_time(time),
+        }
+    }
+
+    /// Log the answer to a check or update with the timing it was based on, at debug level.
+    fn log_decision(
+        &self,
+        command: &str,
+        key: &Key,
+        answer: &dyn Display,
+        now: SystemTime,
+        role: Role,
+    ) {
+        if !log::log_enabled!(Level::Debug) {
+            return;
+        }
+        let lookup_key = self.lookup_key(key);
+        let timing = match self
+            .get_entry(&lookup_key)
+            .map(|entry| (entry, entry.age(now)))
+        {
+            Some((entry, Ok(age))) if entry.listing_status == ListingStatus::Grey => format!(
+                ", {}s of {}s retryMin elapsed",
+                age.as_secs(),
+                self.retry_min_for(&lookup_key, role).as_secs()
+            ),
+            Some((_, Ok(age))) => format!(", first seen {}s ago", age.as_secs()),
+            Some((_, Err(_))) | None => String::new(),
+        };
+        let role = match role {
+            Role::Primary => String::new(),
+            role => format!(" for {} role", role),
+        };
+        log::debug!(
+            "{} {}: {}{}{}",
+            command,
+            self.loggable_key(key),
+            answer,
+            role,
+            timing
+        );
+    }
+
+    /// The key as it may be logged, only the IP address and hash of triplets without
+    /// savetriplets, as the addresses aren't stored either.
+    fn loggable_key(&self, key: &Key) -> String {
+        match key {
+            Key::Triplet(triplet) if !self.config.data.savetriplets => format!(
+                "{} (hash {})",
+                triplet.sender_ip,
+                self.entry_id(key).stable_hash()
+            ),
+            key => key.to_string(),
+        }
+    }
+
+    /// Whether the TCP peer is in one of the tcpAllow networks, only loopback by default.
+    fn is_tcp_allowed(&self, stream: &TcpStream) -> bool {
+        let Ok(addr) = stream.peer_addr() else {
+            return false;
+        };
+        let ip = addr.ip().to_canonical();
+        match &self.config.socket.tcp_allow {
+            Some(allow) => allow.iter().any(|network| network.contains(ip)),
+            None => ip.is_loopback(),
+        }
+    }
+
+    /// The answer to connections on the listener that are closed as too many are waiting.
+    fn busy_answer(&self, listener: &Listener) -> Result<Vec<u8>, anyhow::Error> {
+        let codes = match listener {
+            Listener::Unix(_) => self.config.socket.response_codes,
+            Listener::Tcp(_) => self.config.socket.tcp_response_codes,
+        };
+        let mut answer = Vec::new();
+        write_answer(&mut answer, codes.then_some(ResponseCode::Busy), b"busy")?;
+        Ok(answer)
+    }
+
+    /// Whether the peer may run admin commands: root, the user greylistd runs as and the
+    /// configured adminUsers and adminGroups. Peers without credentials, i.e. on the TCP
+    /// socket, never are.
+    fn is_admin(&self, peer: &Peer) -> bool {
+        let Some((uid, gid)) = peer.credentials else {
+            return false;
+        };
+        // SAFETY: geteuid has no preconditions and can't fail.
+        let euid = unsafe { libc::geteuid() };
+        uid == 0
+            || uid == euid
+            || self.config.socket.admin_users.contains(&uid)
+            || self.config.socket.admin_groups.contains(&gid)
+    }
+
+    fn is_allowed(&self, cmd: &Command) -> bool {
+        match &self.config.socket.allow {
+            Some(allow) => allow.iter().any(|name| name == cmd.name()),
+            None => true,
+        }
+    }
+
+    /// Whether a request received at the given time already took longer than requestBudgetMs,
+    /// so deciding it would keep the client waiting even longer.
+    fn is_over_budget(&self, received: Instant) -> bool {
+        self.config
+            .policy
+            .request_budget()
+            .is_some_and(|budget| received.elapsed() > budget)
+    }
+
+    /// The answer to a request over budget, white with failOpen and grey otherwise, like a
+    /// failed lookup.
+    fn over_budget_answer(&mut self, peer: &Peer, received: Instant) -> ListingStatus {
+        self.over_budget += 1;
+        self.throttles.over_budget.log(format_args!(
+            "Answered request from {} without a decision after {} ms",
+            peer,
+            received.elapsed().as_millis()
+        ));
+        if self.config.policy.fail_open {
+            ListingStatus::White
+        } else {
+            ListingStatus::Grey
+        }
+    }
+
+    /// Log an internal error while looking up an entry and return the answer given instead.
+    fn lookup_failed(&mut self, peer: &Peer, error: anyhow::Error) -> ListingStatus {
+        self.throttles
+            .failed_lookup
+            .log(format_args!("Failed to look up entry: {:#}", error));
+        self.peers.entry(peer.to_string()).or_default().errors += 1;
+        if self.config.policy.fail_open {
+            ListingStatus::White
+        } else {
+            ListingStatus::Grey
+        }
+    }
+
+    /// The built-in listing statuses followed by the configured custom lists.
+    fn statuses(&self) -> Vec<ListingStatus> {
+        [
+            ListingStatus::White,
+            ListingStatus::Grey,
+            ListingStatus::Black,
+            ListingStatus::Quarantine,
+        ]
+        .into_iter()
+        .chain(
+            self.config
+                .lists
+                .custom
+                .iter()
+                .map(|list| ListingStatus::Custom(list.name.clone())),
+        )
+        .collect()
+    }
+
+    /// The answer to check and update requests for entries with the status, which is the
+    /// configured answer for custom lists.
+    fn answer(&self, status: &ListingStatus) -> String {
+        match status {
+            ListingStatus::Custom(name) => match self.config.lists.get(name) {
+                Some(list) => list.answer.clone(),
+                None => name.clone(),
+            },
+            status => status.to_string(),
+        }
+    }
+
+    /// The first custom list used by the command that isn't configured.
+    fn unknown_list<'a>(&self, cmd: &'a Command) -> Option<&'a str> {
+        cmd.statuses().into_iter().find_map(|status| match status {
+            ListingStatus::Custom(name) if self.config.lists.get(name).is_none() => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+    }
+
+    /// The entries with one of the statuses matching the filter, grouped by status in the
+    /// order of the statuses. With a limit only the first entries in the sort order are kept.
+    fn list_entries(&self, status: &[ListingStatus], filter: &ListFilter) -> Vec<&GreylistEntry> {
+        let now = SystemTime::now();
+        let mut entries = self
+            .triplets
+            .values()
+            .filter(|entry| status.contains(&entry.listing_status) && filter.matches(entry, now))
+            .collect::<Vec<_>>();
+        match filter.sort {
+            Some(ListSort::LastSeen) => {
+                entries.sort_by_key(|entry| Reverse(entry.triplet_status.last_seen))
+            }
+            Some(ListSort::Count) => {
+                entries.sort_by_key(|entry| Reverse(entry.triplet_status.count))
+            }
+            None => {}
+        }
+        if let Some(limit) = filter.limit {
+            entries.truncate(limit);
+        }
+        entries.sort_by_key(|entry| status.iter().position(|s| *s == entry.listing_status));
+        entries
+    }
+
+    /// A page of the entries with one of the statuses, ordered by their hash starting at the
+    /// cursor, and the cursor of the next page if there are more entries.
+    ///
+    /// Entries that are added or removed between pages don't shift the others, so an
+    /// interrupted transfer can be resumed with the last cursor.
+    fn list_page(
+        &self,
+        status: &[ListingStatus],
+        filter: &ListFilter,
+        cursor: u64,
+    ) -> (Vec<&GreylistEntry>, Option<u64>) {
+        let now = SystemTime::now();
+        let mut entries = self
+            .triplets
+            .iter()
+            .map(|(id, entry)| (id.stable_hash(), entry))
+            .filter(|(hash, entry)| {
+                *hash >= cursor
+                    && status.contains(&entry.listing_status)
+                    && filter.matches(entry, now)
+            })
+            .collect::<Vec<_>>();
+        let next_cursor = if entries.len() > LIST_PAGE_SIZE {
+            entries.select_nth_unstable_by_key(LIST_PAGE_SIZE, |(hash, _)| *hash);
+            let next_cursor = entries[LIST_PAGE_SIZE].0;
+            entries.truncate(LIST_PAGE_SIZE);
+            Some(next_cursor)
+        } else {
+            None
+        };
+        entries.sort_unstable_by_key(|(hash, _)| *hash);
+        (
+            entries.into_iter().map(|(_, entry)| entry).collect(),
+            next_cursor,
+        )
+    }
+
+    /// Number of entries with the status and the number of requests they matched.
+    fn count_by_status(&self, status: &ListingStatus) -> (u32, u32) {
+        self.triplets
+            .values()
+            .filter(|entry| entry.listing_status == *status)
+            .fold((0, 0), |(items, requests), entry| {
+                (items + 1, requests + entry.triplet_status.count)
+            })
+    }
+
+    /// Number of automatic, manual and imported entries with the given status, the imported
+    /// ones come from the feeds.
+    fn count_by_source(&self, status: &ListingStatus) -> (usize, usize, usize) {
+        self.triplets
+            .values()
+            .filter(|entry| entry.listing_status == *status)
+            .fold((0, 0, 0), |(auto, manual, imported), entry| {
+                match entry.reason {
+                    Reason::Manual => (auto, manual + 1, imported),
+                    Reason::Feed(_) => (auto, manual, imported + 1),
+                    Reason::FirstSeen
+                    | Reason::RetryPromoted
+                    | Reason::PendingWhite
+                    | Reason::Outbound => (auto + 1, manual, imported),
+                }
+            })
+    }
+
+    fn get_entry(&self, key: &Key) -> Option<&GreylistEntry> {
+        self.triplets.get(&self.entry_id(key))
+    }
+
+    /// The id the entry of the key is stored under, which is only its hash for entries loaded
+    /// without their data.
+    fn entry_id(&self, key: &Key) -> EntryId {
+        let id = key.id(&self.config.data);
+        if !self.triplets.contains_key(&id) {
+            let hashed = EntryId::Hash(id.stable_hash());
+            if self.triplets.contains_key(&hashed) {
+                return hashed;
+            }
+        }
+        id
+    }
+
+    /// The key updates of the triplet are stored under, only its IP address with singleupdate.
+    fn update_key(&self, triplet: Triplet) -> Key {
+        if self.config.data.singleupdate {
+            Key::Network(Network::host(triplet.sender_ip))
+        } else {
+            Key::Triplet(triplet)
+        }
+    }
+
+    /// With singlecheck, the id of the white or black entry for the IP address of the key or
+    /// the most specific network containing it.
+    fn single_match(&self, key: &Key) -> Option<EntryId> {
+        if !self.config.data.singlecheck {
+            return None;
+        }
+        let ip = key.ip()?;
+        (0..=Network::max_prefix(ip)).rev().find_map(|prefix| {
+            let id = self.entry_id(&Key::Network(Network {
+                addr: mask_ip(ip, prefix),
+                prefix,
+            }));
+            self.triplets
+                .get(&id)
+                .filter(|entry| entry.listing_status != ListingStatus::Grey)
+                .map(|_| id)
+        })
+    }
+
+    /// The key a request for the given key is answered from, only its IP address for triplets
+    /// with singleupdate.
+    fn lookup_key(&self, key: &Key) -> Key {
+        match key {
+            Key::Triplet(triplet) => self.update_key(triplet.clone()),
+            Key::Network(_) | Key::Hash(_) => key.clone(),
+        }
+    }
+
+    /// Seconds until a retry of a greylisted key would pass, rounded up.
+    fn retry_after(&self, key: &Key, now: SystemTime, role: Role) -> u64 {
+        let key = self.lookup_key(key);
+        let retry_min = self.retry_min_for(&key, role);
+        let remaining = match self.get_entry(&key) {
+            Some(entry) => match entry.age(now) {
+                Ok(age) => retry_min
+                    .saturating_sub(age)
+                    .max(self.promotion_wait(entry, now)),
+                Err(_) => retry_min,
+            },
+            // Not seen yet, the first update starts the delay
+            None => retry_min,
+        };
+        remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+    }
+
+    fn check_key(
+        &self,
+        key: &Key,
+        now: SystemTime,
+        role: Role,
+    ) -> Result<CheckResult, anyhow::Error> {
+        let key = &self.lookup_key(key);
+        if let Some(id) = self.single_match(key) {
+            return Ok(CheckResult::Listed(
+                self.triplets[&id].listing_status.clone(),
+            ));
+        }
+        if self.aggregate_match(key).is_some() {
+            return Ok(CheckResult::Listed(ListingStatus::White));
+        }
+        let Some(entry) = self.get_entry(key) else {
+            return Ok(CheckResult::Listed(ListingStatus::Grey));
+        };
+        if entry.listing_status == ListingStatus::Grey {
+            let diff = entry.age(now)?;
+            if diff <= self.config.timeouts.retry_max
+                && self.promotes(entry, now, diff, self.retry_min_for(key, role))
+            {
+                return Ok(CheckResult::WouldPass);
+            }
+        }
+
+        Ok(CheckResult::Listed(entry.listing_status.clone()))
+    }
+
+    /// Whitelist the triplet of replies to outgoing mail, unless it's blacklisted.
+    fn add_outbound(&mut self, reply: Triplet, now: SystemTime) -> bool {
+        let key = self.update_key(reply);
+        if let Some(entry) = self.get_entry(&key) {
+            if entry.listing_status == ListingStatus::Black {
+                return false;
+            }
+            // Manually added entries are kept as they are
+            if !entry.lifetime.is_automatic() {
+                return true;
+            }
+        }
+        self.add_entry(
+            key,
+            ListingStatus::White,
+            Reason::Outbound,
+            Lifetime::Timeout,
+            now,
+        );
+        true
+    }
+
+    fn add_entry(
+        &mut self,
+        key: Key,
+        listing_status: ListingStatus,
+        reason: Reason,
+        lifetime: Lifetime,
+        now: SystemTime,
+    ) -> &GreylistEntry {
+        self.dirty = true;
+        let id = self.entry_id(&key);
+        if listing_status == ListingStatus::White {
+            self.index_aggregates(&id);
+        }
+        let previous = self.triplets.get(&id).map(|entry| &entry.listing_status);
+        if previous != Some(&listing_status) {
+            match listing_status {
+                ListingStatus::Quarantine => self.statistics.quarantine += 1,
+                ListingStatus::Black => self.statistics.black += 1,
+                ListingStatus::White | ListingStatus::Grey | ListingStatus::Custom(_) => {}
+            }
+        }
+        match self.triplets.entry(id) {
+            Entry::Occupied(entry) => {
+                let entry = entry.into_mut();
+                entry.triplet_status.last_seen = now;
+                entry.listing_status = listing_status;
+                entry.reason = reason;
+                entry.lifetime = lifetime;
+                entry
+            }
+            Entry::Vacant(entry) => entry.insert(GreylistEntry {
+                key,
+                listing_status,
+                reason,
+                lifetime,
+                triplet_status: TripletStatus {
+                    first_seen: now,
+                    last_seen: now,
+                    count: 0,
+                },
+            }),
+        }
+    }
+
+    fn add_or_update_triplet(
+        &mut self,
+        triplet: Triplet,
+        now: SystemTime,
+        role: Role,
+    ) -> Result<&GreylistEntry, anyhow::Error> {
+        self.dirty = true;
+        self.diversity.record(&triplet);
+        let key = self.update_key(triplet);
+        if let Some(id) = self.single_match(&key) {
+            let entry = self.triplets.get_mut(&id).unwrap();
+            entry.triplet_status.last_seen = now;
+            entry.triplet_status.count += 1;
+            return Ok(entry);
+        }
+        if let Some(id) = self.aggregate_match(&key) {
+            let entry = self.triplets.get_mut(&id).unwrap();
+            entry.triplet_status.last_seen = now;
+            entry.triplet_status.count += 1;
+            return Ok(entry);
+        }
+        let id = self.entry_id(&key);
+        let retry_min = self.retry_min_for(&key, role);
+        let mut repeated = false;
+        let mut pending = false;
+        let outcome = match self.triplets.get(&id) {
+            Some(entry)
+                if entry.listing_status == ListingStatus::Grey && entry.lifetime.is_automatic() =>
+            {
+                let age = entry.age(now)?;
+                if age > self.config.timeouts.retry_max {
+                    // The previous attempt expired before this retry
+                    self.record_expired_attempt(&key, now);
+                    Some(Outcome::Greylisted)
+                } else {
+                    repeated = self.repeated_attempt(entry, now);
+                    let promotes = self.promotes(entry, now, age, retry_min);
+                    pending = !promotes && self.marks_pending(entry, now);
+                    promotes.then_some(Outcome::Passed)
+                }
+            }
+            Some(_) => None,
+            None => Some(Outcome::Greylisted),
+        };
+        if let Some(outcome) = outcome {
+            self.record_subnet_outcome(&key, outcome, now);
+        }
+        if outcome == Some(Outcome::Passed) {
+            self.index_aggregates(&id);
+        }
+        match self.triplets.entry(id) {
+            Entry::Occupied(entry) => {
+                let entry = entry.into_mut();
+                // Fail before modifying anything, so a broken entry stays as it is
+                let age = entry.age(now)?;
+                // Attempts too close to the previous one count as the same attempt
+                if !repeated {
+                    entry.triplet_status.last_seen = now;
+                    entry.triplet_status.count += 1;
+                }
+                // The status of manually added entries is only changed by an admin
+                if entry.listing_status == ListingStatus::Grey && entry.lifetime.is_automatic() {
+                    if age > self.config.timeouts.retry_max {
+                        self.statistics.expired += 1;
+                        entry.triplet_status.first_seen = now;
+                        if self.config.policy.promotion_attempts.is_some() {
+                            entry.triplet_status.count = 1;
+                        }
+                        // The retry of the expired attempt doesn't count for the new one
+                        entry.reason = Reason::FirstSeen;
+                    } else if outcome == Some(Outcome::Passed) {
+                        self.statistics.white += 1;
+                        entry.listing_status = ListingStatus::White;
+                        entry.reason = Reason::RetryPromoted;
+                    } else if pending {
+                        entry.reason = Reason::PendingWhite;
+                    }
+                }
+                Ok(entry)
+            }
+            Entry::Vacant(entry) => {
+                self.statistics.grey += 1;
+                Ok(entry.insert(GreylistEntry {
+                    key,
+                    listing_status: ListingStatus::Grey,
+                    reason: Reason::FirstSeen,
+                    lifetime: Lifetime::Timeout,
+                    triplet_status: TripletStatus {
+                        first_seen: now,
+                        last_seen: now,
+                        count: 1,
+                    },
+                }))
+            }
+        }
+    }
+}
+
+fn check_config(config: &Config) -> Result<(), anyhow::Error> {
+    if let Some(penalty) = config.policy.recidivist_retry_min {
+        if penalty >= config.timeouts.retry_max {
+            return Err(anyhow!(
+                "Option recidivistRetryMin must be less than retryMax"
+            ));
+        }
+    }
+    if let Some(attempts) = config.policy.promotion_attempts {
+        if attempts < 2 {
+            return Err(anyhow!("Option promotionAttempts must be at least 2"));
+        }
+        let spacing = config
+            .policy
+            .promotion_spacing
+            .unwrap_or(config.timeouts.retry_min);
+        if spacing * (attempts - 1) > config.timeouts.retry_max {
+            return Err(anyhow!(
+                "Option promotionAttempts doesn't fit into retryMax with promotionSpacing"
+            ));
+        }
+    }
+    let roles = &config.roles;
+    for (role, policy) in [
+        ("primary", roles.primary),
+        ("backup", roles.backup),
+        ("submission", roles.submission),
+    ] {
+        if let RolePolicy::RetryMin(retry_min) = policy {
+            if retry_min >= config.timeouts.retry_max {
+                return Err(anyhow!(
+                    "The retryMin of the {} role must be less than retryMax",
+                    role
+                ));
+            }
+        }
+    }
+    if config
+        .report
+        .interval
+        .is_some_and(|interval| interval.is_zero())
+    {
+        return Err(anyhow!("Option interval in [report] must not be 0"));
+    }
+    if config.transcript.path.is_some() && config.data.email_key_file.is_some() {
+        return Err(anyhow!(
+            "Transcripts contain the addresses in the clear, they can't be enabled with \
+             emailKeyFile"
+        ));
+    }
+    if config.transcript.sample == Some(0) {
+        return Err(anyhow!("Option sample in [transcript] must be at least 1"));
+    }
+    if config.socket.queue_length == 0 {
+        return Err(anyhow!("Option queueLength must be at least 1"));
+    }
+    if !config.feeds.sources.is_empty() && !config.data.singlecheck {
+        return Err(anyhow!(
+            "Feeds add network entries, which are only used with singlecheck = true"
+        ));
+    }
+    if let Some(allow) = &config.socket.allow {
+        if let Some(name) = allow
+            .iter()
+            .find(|name| !COMMAND_NAMES.contains(&name.as_str()))
+        {
+            return Err(anyhow!("Unknown command in socket allow list: {}", name));
+        }
+    }
+    Ok(())
+}
+
+fn load_data(config: &Config) -> Result<LoadedData, anyhow::Error> {
+    let started = Instant::now();
+    let legacy_files = legacy::legacy_files(config);
+    let (mut triplets, statistics, converted) = match &legacy_files {
+        Some((statefile, tripletfile)) => {
+            log::info!(
+                "Importing the greylistd data from {} and {}",
+                statefile.display(),
+                tripletfile.display()
+            );
+            legacy::load_states(tripletfile, statefile, true)?
+        }
+        None => legacy::load_states(&config.data.tripletfile, &config.data.statefile, false)?,
+    };
+    triplets.extend(shards::load_shards(config));
+
+    let mut map = HashMap::<EntryId, GreylistEntry>::with_capacity(triplets.len());
+    let mut conflicts = Vec::new();
+    let mut unlisted = 0;
+    for entry in triplets {
+        if let ListingStatus::Custom(name) = &entry.listing_status {
+            if config.lists.get(name).is_none() {
+                unlisted += 1;
+                continue;
             }
+        }
+        let id = entry.key.stored_id(&config.data);
+        let Some(existing) = map.remove(&id) else {
+            map.insert(id, entry);
+            continue;
+        };
+        let (kept, dropped) = if entry.wins_over(&existing) {
+            (entry, existing)
         } else {
-            write!(writer, "Invalid command")?;
+            (existing, entry)
+        };
+        let hash = id.stable_hash();
+        log::warn!(
+            "Conflict for {}: keeping \"{}\" ({}), dropping \"{}\" ({})",
+            hash,
+            kept.key,
+            kept.listing_status.list_name(),
+            dropped.key,
+            dropped.listing_status.list_name()
+        );
+        conflicts.push(Conflict {
+            hash,
+            kept: kept.key.clone(),
+            dropped: dropped.key,
+        });
+        map.insert(id, kept);
+    }
+    if unlisted > 0 {
+        log::warn!(
+            "Dropped {} entries of custom lists that are no longer configured",
+            unlisted
+        );
+    }
+    if !conflicts.is_empty() {
+        log::warn!(
+            "Dropped {} conflicting entries while loading, see \"debug conflicts\"",
+            conflicts.len()
+        );
+    }
+
+    log::info!(
+        "Loaded {} entries from {} in {} ms",
+        map.len(),
+        config.data.statefile.display(),
+        started.elapsed().as_millis()
+    );
+    let subnets = match &config.data.subnetfile {
+        Some(path) => subnets::load_subnets(path)?,
+        None => HashMap::new(),
+    };
+    Ok(LoadedData {
+        triplets: map,
+        statistics,
+        conflicts,
+        subnets,
+        imported: legacy_files.is_some() || converted,
+    })
+}
+
+/// Number of entries listed per page with `list --cursor`.
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Version of the request protocol announced in the banner.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Protocol version of connections with response codes.
+const PROTOCOL_VERSION_CODES: u32 = 3;
+
+/// Capabilities announced in the banner: JSON answers, several newline terminated requests
+/// per connection, paged lists and the Postfix policy protocol.
+const FEATURES: &[&str] = &["json", "batch", "cursor", "policy"];
+
+/// The line connections are greeted with if the banner is enabled.
+fn banner(codes: bool) -> String {
+    let version = if codes {
+        PROTOCOL_VERSION_CODES
+    } else {
+        PROTOCOL_VERSION
+    };
+    format!(
+        "greylistd {} proto={} features={}",
+        env!("CARGO_PKG_VERSION"),
+        version,
+        FEATURES.join(",")
+    )
+}
+
+/// Read timeout for further requests on a connection, requests are handled one connection at
+/// a time and an idle client would hold up the others.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn is_timeout(error: &std::io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Delay before accepting again after the first failure, doubled with every further failure.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+
+/// Longest delay between attempts while accepting keeps failing.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Counters of the accept thread, shared with the request handling.
+#[derive(Default)]
+struct AcceptCounters {
+    /// Connections that failed to be accepted
+    failures: AtomicU64,
+    /// Connections answered with busy as the queue was full
+    busy: AtomicU64,
+}
+
+/// How long answering busy may block the accept thread.
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Accept connections from all listeners and pass them on, until `wake` becomes readable or the
+/// receiving side is gone.
+///
+/// Connections are only accepted here, a client that sends nothing is dropped by the read
+/// timeout of the request handling. Failures like running out of file descriptors are counted
+/// and retried with a growing delay, only an unusable listener stops accepting. While the queue
+/// of `streams` is full, connections are answered with the busy answer of their listener and
+/// closed.
+fn accept_connections(
+    listeners: &[Listener],
+    busy_answers: &[Vec<u8>],
+    wake: &UnixStream,
+    streams: Sender<(Connection, Instant)>,
+    counters: &AcceptCounters,
+) {
+    let mut fds = std::iter::once(wake.as_raw_fd())
+        .chain(listeners.iter().map(Listener::as_raw_fd))
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    // Failures since the last accepted connection
+    let mut failing = 0u32;
+    // Connections answered with busy since the queue filled up
+    let mut shedding = 0u64;
+    loop {
+        if failing > 0 {
+            // The listeners stay readable while the connection isn't accepted, only wait for wake
+            let delay = ACCEPT_BACKOFF_MIN
+                .saturating_mul(1 << (failing - 1).min(10))
+                .min(ACCEPT_BACKOFF_MAX);
+            if let Err(e) = wait_readable(&mut fds[..1], Some(delay)) {
+                log::error!("Failed to wait for connections: {}", e);
+                return;
+            }
+            if fds[0].revents != 0 {
+                return;
+            }
+        }
+        if let Err(e) = wait_readable(&mut fds, None) {
+            log::error!("Failed to wait for connections: {}", e);
+            return;
+        }
+        if fds[0].revents != 0 {
+            return;
+        }
+        for ((listener, fd), busy_answer) in listeners.iter().zip(&fds[1..]).zip(busy_answers) {
+            if fd.revents == 0 {
+                continue;
+            }
+            match listener.accept() {
+                Ok(stream) => {
+                    if failing > 0 {
+                        log::info!("Accepting connections again after {} failures", failing);
+                        failing = 0;
+                    }
+                    match streams.try_send((stream, Instant::now())) {
+                        Ok(()) if shedding > 0 => {
+                            log::info!(
+                                "Handling connections again after answering {} with busy",
+                                shedding
+                            );
+                            shedding = 0;
+                        }
+                        Ok(()) => {}
+                        Err(TrySendError::Full((stream, _))) => {
+                            if shedding == 0 {
+                                log::warn!(
+                                    "{} connections are waiting, answering further ones with busy",
+                                    streams.len()
+                                );
+                            }
+                            shedding += 1;
+                            counters.busy.fetch_add(1, Ordering::Relaxed);
+                            answer_busy(stream, busy_answer);
+                        }
+                        Err(TrySendError::Disconnected(_)) => return,
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                // The client closed the connection before it was accepted
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                    counters.failures.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) if is_listener_error(&e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    counters.failures.fetch_add(1, Ordering::Relaxed);
+                    if failing == 0 {
+                        log::error!("Failed to accept connection, retrying: {}", e);
+                    }
+                    failing += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Answer a connection that can't be queued and close it, without waiting for a slow client.
+fn answer_busy(mut stream: Connection, answer: &[u8]) {
+    // The client may have gone already, it's been counted either way
+    let _ = stream
+        .set_write_timeout(Some(BUSY_WRITE_TIMEOUT))
+        .and_then(|_| stream.write_all(answer));
+    // Closing with an unread request resets the connection, which discards the answer
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buf = [0; 1024];
+        let mut left = MAX_REQUEST_LENGTH;
+        while left > 0 {
+            match stream.read(&mut buf) {
+                Ok(read) if read > 0 => left = left.saturating_sub(read),
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Wait until one of the fds is readable or the timeout passed, retrying when interrupted.
+fn wait_readable(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> std::io::Result<()> {
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+    loop {
+        // SAFETY: fds is a valid array of pollfd structs for the duration of the call.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret >= 0 {
+            return Ok(());
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Whether accepting failed because the listener itself is unusable, so retrying can't help.
+fn is_listener_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT)
+    )
+}
+
+#[derive(Clone, Debug)]
+struct Triplet {
+    sender_ip: IpAddr,
+    sender_email: Option<String>,
+    recipient_email: String,
+}
+
+impl Triplet {
+    /// The address of the subnet the sender IP belongs to, as compared with `onlysubnet`.
+    fn network_key(&self, prefix4: u8, prefix6: u8) -> IpAddr {
+        subnet_ip(self.sender_ip, prefix4, prefix6)
+    }
+}
+
+impl ListFilter {
+    /// Whether the entry meets all conditions, entries stored only by their hash don't match
+    /// any conditions on the addresses.
+    fn matches(&self, entry: &GreylistEntry, now: SystemTime) -> bool {
+        let contains = |address: Option<&str>, part: &Option<String>| {
+            part.as_ref().map_or(true, |part| {
+                address.is_some_and(|address| address.to_lowercase().contains(part.as_str()))
+            })
+        };
+        let (network, sender, recipient) = match &entry.key {
+            Key::Triplet(triplet) => (
+                Some(Network::host(triplet.sender_ip)),
+                triplet.sender_email.as_deref(),
+                Some(triplet.recipient_email.as_str()),
+            ),
+            Key::Network(network) => (Some(*network), None, None),
+            Key::Hash(_) => (None, None, None),
         };
-        Ok(false)
+        self.ip.map_or(true, |ip| {
+            network.is_some_and(|network| network.prefix >= ip.prefix && ip.contains(network.addr))
+        }) && contains(sender, &self.sender)
+            && contains(recipient, &self.recipient)
+            && self.since.map_or(true, |since| {
+                now.duration_since(entry.triplet_status.last_seen)
+                    .map_or(true, |age| age <= since)
+            })
     }
+}
 
-    fn get_entry(&self, triplet: &Triplet) -> Option<&GreylistEntry> {
-        let hash = self.hash_triplet(triplet);
-        self.triplets.get(&hash)
+/// The address of the subnet an IP belongs to, with the prefix length of its address family.
+fn subnet_ip(ip: IpAddr, prefix4: u8, prefix6: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => mask_ip(ip, prefix4),
+        IpAddr::V6(_) => mask_ip(ip, prefix6),
     }
+}
 
-    fn hash_triplet(&self, triplet: &Triplet) -> u64 {
-        triplet.hash(self.config.data.onlysubnet)
+/// Set all bits after the first `prefix` bits of the address to zero.
+fn mask_ip(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
     }
+}
 
-    fn check_triplet(&self, triplet: Triplet) -> ListingStatus {
-        let Some(entry) = self.get_entry(&triplet) else {
-            return ListingStatus::Grey;
-        };
-        if entry.listing_status == ListingStatus::Grey {
-            let now = SystemTime::now();
-            let diff = now.duration_since(entry.triplet_status.first_seen).unwrap();
-            if diff <= self.config.timeouts.retry_max && diff >= self.config.timeouts.retry_min {
-                return ListingStatus::White;
-            }
+/// An IP network given as a bare IP address or in CIDR notation.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn max_prefix(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
         }
+    }
 
-        entry.listing_status.clone()
+    fn host(addr: IpAddr) -> Network {
+        Network {
+            addr,
+            prefix: Network::max_prefix(addr),
+        }
     }
 
-    fn add_triplet(&mut self, triplet: Triplet, listing_status: ListingStatus) -> &GreylistEntry {
-        let now = SystemTime::now();
-        let hash = self.hash_triplet(&triplet);
-        let entry = self
-            .triplets
-            .entry(hash)
-            .and_modify(|entry| {
-                entry.triplet_status.last_seen = now;
-                entry.listing_status = listing_status.clone();
-            })
-            .or_insert_with(|| GreylistEntry {
-                triplet,
-                listing_status,
-                triplet_status: TripletStatus {
-                    first_seen: now,
-                    last_seen: now,
-                    count: 0,
-                },
-            });
-        entry
+    fn is_host(&self) -> bool {
+        self.prefix == Network::max_prefix(self.addr)
     }
 
-    fn add_or_update_triplet(&mut self, triplet: Triplet) -> &GreylistEntry {
-        let now = SystemTime::now();
-        let hash = self.hash_triplet(&triplet);
-        let entry = self
-            .triplets
-            .entry(hash)
-            .and_modify(|entry| {
-                entry.triplet_status.last_seen = now;
-                entry.triplet_status.count += 1;
-                if let ListingStatus::Grey = entry.listing_status {
-                    let diff = now.duration_since(entry.triplet_status.first_seen).unwrap();
-                    if diff > self.config.timeouts.retry_max {
-                        entry.triplet_status.first_seen = now;
-                    } else if diff >= self.config.timeouts.retry_min {
-                        self.statistics.white += 1;
-                        entry.listing_status = ListingStatus::White;
-                    }
-                }
-            })
-            .or_insert_with(|| {
-                self.statistics.grey += 1;
-                GreylistEntry {
-                    triplet,
-                    listing_status: ListingStatus::Grey,
-                    triplet_status: TripletStatus {
-                        first_seen: now,
-                        last_seen: now,
-                        count: 1,
-                    },
-                }
-            });
-        entry
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.addr.is_ipv4() && mask_ip(ip, self.prefix) == self.addr
     }
 }
 
-#[derive(Debug)]
-struct Triplet {
-    sender_ip: IpAddr,
-    sender_email: Option<String>,
-    recipient_email: String,
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| anyhow!("Invalid IP address: {}", addr))?;
+        let max_prefix = Network::max_prefix(addr);
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| anyhow!("Invalid network prefix length: {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Network {
+            addr: mask_ip(addr, prefix),
+            prefix,
+        })
+    }
 }
 
-impl Triplet {
-    fn hash(&self, only_subnet: bool) -> u64 {
-        let mut s = DefaultHasher::new();
-        if !only_subnet {
-            self.sender_ip.hash(&mut s);
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_host() {
+            f.write_fmt(format_args!("{}", self.addr))
         } else {
-            let subnet_ip = match self.sender_ip {
-                IpAddr::V4(ip) => {
-                    let mut octets = ip.octets();
-                    octets[3] = 0;
-                    IpAddr::V4(Ipv4Addr::from(octets))
-                }
-                IpAddr::V6(ip) => {
-                    let mut octets = ip.octets();
-                    for octet in octets.iter_mut().skip(7) {
-                        *octet = 0;
-                    }
-                    IpAddr::V6(Ipv6Addr::from(octets))
-                }
-            };
-            subnet_ip.hash(&mut s);
+            f.write_fmt(format_args!("{}/{}", self.addr, self.prefix))
         }
-        self.sender_email.hash(&mut s);
-        self.recipient_email.hash(&mut s);
-        s.finish()
     }
 }
 
-impl FromStr for Triplet {
-    type Err = anyhow::Error;
+/// The data an entry is stored for, either a full triplet or a bare IP address/network.
+#[derive(Clone, Debug)]
+enum Key {
+    Triplet(Triplet),
+    Network(Network),
+    /// Only the hash of an entry loaded from the state file, without its data in the triplet
+    /// file, as with savetriplets disabled.
+    Hash(u64),
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts = s.split(" ").collect::<Vec<_>>();
-        if parts.len() == 2 {
-            Ok(Triplet {
-                sender_ip: IpAddr::from_str(parts.first().unwrap())?,
-                sender_email: None,
-                recipient_email: parts.get(1).unwrap().to_string(),
-            })
-        } else if parts.len() == 3 {
-            Ok(Triplet {
-                sender_ip: IpAddr::from_str(parts.first().unwrap())?,
-                sender_email: Some(parts.get(1).unwrap().to_string()),
-                recipient_email: parts.get(2).unwrap().to_string(),
-            })
-        } else {
-            Err(anyhow!("Invalid triplet: {}", s))
+impl Key {
+    /// The IP address of a triplet or of a network that is a single host.
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Key::Triplet(triplet) => Some(triplet.sender_ip),
+            Key::Network(network) if network.is_host() => Some(network.addr),
+            Key::Network(_) | Key::Hash(_) => None,
+        }
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Triplet(triplet) => triplet.fmt(f),
+            Key::Network(network) => network.fmt(f),
+            Key::Hash(hash) => hash.fmt(f),
         }
     }
 }
-derive_deserialize_from_fromstr!(Triplet, "Invalid triplet");
+derive_serialize_from_display!(Key);
 
 impl Display for Triplet {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -557,18 +2631,91 @@ impl Display for TripletStatus {
 }
 derive_serialize_from_display!(TripletStatus);
 
+/// The status of an entry on a custom list, stored as the list name followed by the status.
+#[derive(Clone, Debug)]
+struct CustomStatus {
+    list: String,
+    status: TripletStatus,
+}
+
+impl FromStr for CustomStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (list, status) = s
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid custom list status: {}", s))?;
+        Ok(CustomStatus {
+            list: list.to_string(),
+            status: status.parse()?,
+        })
+    }
+}
+derive_deserialize_from_fromstr!(CustomStatus, "Invalid custom list status");
+
+impl Display for CustomStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.list, self.status)
+    }
+}
+derive_serialize_from_display!(CustomStatus);
+
 #[derive(Debug)]
 pub struct GreylistEntry {
-    triplet: Triplet,
+    key: Key,
     triplet_status: TripletStatus,
     listing_status: ListingStatus,
+    reason: Reason,
+    lifetime: Lifetime,
+}
+
+impl GreylistEntry {
+    /// Time since the entry was first seen, fails if that lies in the future.
+    fn age(&self, now: SystemTime) -> Result<Duration, anyhow::Error> {
+        now.duration_since(self.triplet_status.first_seen)
+            .map_err(|_| anyhow!("Entry {} was first seen in the future", self.key))
+    }
+
+    /// Deterministic precedence between two entries sharing an id, independent of load order:
+    /// the stronger listing status wins, then the most recently seen entry, then the triplet text.
+    fn wins_over(&self, other: &GreylistEntry) -> bool {
+        let rank = |status: &ListingStatus| match status {
+            ListingStatus::Grey => 0,
+            ListingStatus::White => 1,
+            ListingStatus::Custom(_) => 2,
+            ListingStatus::Quarantine => 3,
+            ListingStatus::Black => 4,
+        };
+        (
+            rank(&self.listing_status),
+            self.triplet_status.last_seen,
+            self.key.to_string(),
+        ) > (
+            rank(&other.listing_status),
+            other.triplet_status.last_seen,
+            other.key.to_string(),
+        )
+    }
 }
 
+/// Counters of status changes, which only go up until the statistics are cleared.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct StoredStatistics {
+    /// Greylisted entries that were whitelisted by a retry
     white: u32,
+    /// Entries that were greylisted when first seen
     grey: u32,
+    /// Entries that were put on the blacklist
     black: u32,
+    /// Greylisted entries that expired without a retry in time
+    #[serde(default)]
+    expired: u32,
+    /// Checks that found a greylisted entry an update would have whitelisted, without changing it
+    #[serde(default)]
+    would_pass: u32,
+    /// Entries that were put into quarantine
+    #[serde(default)]
+    quarantine: u32,
     #[serde(
         deserialize_with = "deserialize_systemtime_seconds",
         serialize_with = "serialize_systemtime_seconds"
@@ -579,6 +2726,9 @@ pub struct StoredStatistics {
         serialize_with = "serialize_systemtime_seconds"
     )]
     lastsave: SystemTime,
+    /// The database at the last report, for the growth in the next one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lastreport: Option<ReportBaseline>,
 }
 
 impl Default for StoredStatistics {
@@ -587,8 +2737,12 @@ impl Default for StoredStatistics {
             white: 0,
             grey: 0,
             black: 0,
+            expired: 0,
+            would_pass: 0,
+            quarantine: 0,
             start: SystemTime::now(),
             lastsave: SystemTime::UNIX_EPOCH,
+            lastreport: None,
         }
     }
 }
@@ -598,203 +2752,269 @@ struct StoredStates {
     white: HashMap<String, TripletStatus>,
     grey: HashMap<String, TripletStatus>,
     black: HashMap<String, TripletStatus>,
+    #[serde(default)]
+    quarantine: HashMap<String, TripletStatus>,
+    #[serde(default)]
+    custom: HashMap<String, CustomStatus>,
+    /// Reasons of the entries that differ from the usual one for their status
+    #[serde(default, alias = "sources")]
+    reasons: HashMap<String, Reason>,
+    /// Lifetimes of manually added entries, all others expire by the timeouts
+    #[serde(default)]
+    lifetimes: HashMap<String, Lifetime>,
     statistics: StoredStatistics,
 }
 
+/// The path of the previous version of a data file.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Replace a data file atomically, so a crash while saving leaves either the old or the new
+/// version. The old version is kept as backup.
+fn write_data_file(path: &Path, data: &impl Serialize) -> Result<(), anyhow::Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    to_writer(&mut writer, data)?;
+    writer.into_inner()?.sync_all()?;
+
+    if exists(path)? {
+        let backup = backup_path(path);
+        if exists(&backup)? {
+            remove_file(&backup)?;
+        }
+        hard_link(path, &backup)?;
+    }
+    rename(&tmp, path)?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Read a data file, a missing file is read as empty.
+fn parse_data_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T, anyhow::Error> {
+    if !exists(path)? {
+        return Ok(T::default());
+    }
+    from_read(File::open(path)?)
+        .map_err(|e| anyhow!(e).context(format!("Failed to read {}", path.display())))
+}
+
+/// Read a data file, or its backup if it can't be parsed. A missing file is read as empty.
+fn read_data_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T, anyhow::Error> {
+    let error = match parse_data_file(path) {
+        Ok(data) => return Ok(data),
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    if !exists(&backup)? {
+        return Err(error);
+    }
+    log::warn!("{:#}, using the backup {}", error, backup.display());
+    parse_data_file(&backup)
+}
+
+/// Read the triplets and states of a tripletfile and statefile.
+fn read_triplet_states(
+    file_triplets: &Path,
+    file_states: &Path,
+) -> Result<(HashMap<String, String>, StoredStates), anyhow::Error> {
+    Ok((
+        parse_data_file(file_triplets)?,
+        parse_data_file(file_states)?,
+    ))
+}
+
+/// Load the entries of a tripletfile and statefile.
+///
+/// If either file can't be parsed, both are read from their backups, which were written by the
+/// same save, so the triplets of one save aren't joined with the states of another. Without a
+/// backup of the statefile loading fails instead.
 pub fn load_triplet_states(
     file_triplets: impl AsRef<Path>,
     file_states: impl AsRef<Path>,
 ) -> Result<(Vec<GreylistEntry>, StoredStatistics), anyhow::Error> {
-    let triplets = if !exists(&file_triplets)? {
-        Default::default()
-    } else {
-        from_read::<_, HashMap<String, Triplet>>(File::open(file_triplets)?)?
-    };
-    let mut states = if !exists(&file_states)? {
-        Default::default()
-    } else {
-        from_read::<_, StoredStates>(File::open(file_states)?)?
+    let (file_triplets, file_states) = (file_triplets.as_ref(), file_states.as_ref());
+    let (triplets, states) = match read_triplet_states(file_triplets, file_states) {
+        Ok(data) => data,
+        Err(error) => {
+            let backups = (backup_path(file_triplets), backup_path(file_states));
+            if !exists(&backups.1)? {
+                return Err(error);
+            }
+            log::warn!(
+                "{:#}, using the backups {} and {}",
+                error,
+                backups.0.display(),
+                backups.1.display()
+            );
+            read_triplet_states(&backups.0, &backups.1)?
+        }
     };
-    let entries = triplets
+    let triplets = triplets
+        .into_iter()
+        .filter_map(|(hash, key)| match Key::from_stored(&key) {
+            Ok(key) => Some((hash, key)),
+            // The entry is still loaded by its hash from the states
+            Err(e) => {
+                log::warn!(
+                    "Skipping the triplet of {} in {}: {}",
+                    hash,
+                    file_triplets.display(),
+                    e
+                );
+                None
+            }
+        })
+        .collect();
+    entries_from_states(triplets, states)
+}
+
+/// Join the states with their triplets by hash, states without a triplet are kept by their hash
+/// alone.
+fn entries_from_states(
+    triplets: HashMap<String, Key>,
+    mut states: StoredStates,
+) -> Result<(Vec<GreylistEntry>, StoredStatistics), anyhow::Error> {
+    let mut entries = triplets
         .into_iter()
-        .map(|(hash, triplet)| {
+        .filter_map(|(hash, key)| {
             let (listing_status, triplet_status) = if let Some(state) = states.white.remove(&hash) {
                 (ListingStatus::White, state)
             } else if let Some(state) = states.grey.remove(&hash) {
                 (ListingStatus::Grey, state)
             } else if let Some(state) = states.black.remove(&hash) {
                 (ListingStatus::Black, state)
+            } else if let Some(state) = states.quarantine.remove(&hash) {
+                (ListingStatus::Quarantine, state)
+            } else if let Some(state) = states.custom.remove(&hash) {
+                (ListingStatus::Custom(state.list), state.status)
             } else {
-                return Err(anyhow!("Triplet status not found: {}", triplet));
+                // Saving was interrupted after writing the triplets, the entry is new or pruned
+                return None;
             };
-            Ok(GreylistEntry {
-                triplet,
+            let reason = states
+                .reasons
+                .remove(&hash)
+                .unwrap_or_else(|| Reason::default_for(&listing_status));
+            let lifetime = states.lifetimes.remove(&hash).unwrap_or(Lifetime::Timeout);
+            Some(GreylistEntry {
+                key,
                 triplet_status,
                 listing_status,
+                reason,
+                lifetime,
             })
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect::<Vec<_>>();
 
-    Ok((entries, states.statistics))
-}
+    // The remaining states have no triplet, they are kept by their hash alone
+    let StoredStates {
+        statistics,
+        white,
+        grey,
+        black,
+        quarantine,
+        custom,
+        mut reasons,
+        mut lifetimes,
+    } = states;
+    let custom = custom
+        .into_iter()
+        .map(|(hash, state)| (ListingStatus::Custom(state.list), hash, state.status));
+    let states = [
+        (ListingStatus::White, white),
+        (ListingStatus::Grey, grey),
+        (ListingStatus::Black, black),
+        (ListingStatus::Quarantine, quarantine),
+    ]
+    .into_iter()
+    .flat_map(|(listing_status, states)| {
+        states
+            .into_iter()
+            .map(move |(hash, state)| (listing_status.clone(), hash, state))
+    })
+    .chain(custom);
+    for (listing_status, hash, triplet_status) in states {
+        let reason = reasons
+            .remove(&hash)
+            .unwrap_or_else(|| Reason::default_for(&listing_status));
+        let lifetime = lifetimes.remove(&hash).unwrap_or(Lifetime::Timeout);
+        let hash = hash
+            .parse()
+            .map_err(|_| anyhow!("Invalid hash in state file: {}", hash))?;
+        entries.push(GreylistEntry {
+            key: Key::Hash(hash),
+            triplet_status,
+            listing_status,
+            reason,
+            lifetime,
+        });
+    }
 
-#[derive(Debug)]
-enum Command {
-    Add {
-        triplet: Triplet,
-        add_status: ListingStatus,
-    },
-    Delete {
-        triplet: Triplet,
-    },
-    Check {
-        triplet: Triplet,
-        check_status: Option<ListingStatus>,
-    },
-    Update {
-        triplet: Triplet,
-        check_status: Option<ListingStatus>,
-    },
-    Stats,
-    Status {
-        triplet: Triplet,
-    },
-    Mrtg,
-    List {
-        status: Vec<ListingStatus>,
-    },
-    Save,
-    Reload,
-    Clear {
-        status: Vec<ListingStatus>,
-    },
+    Ok((entries, statistics))
 }
 
-fn parse_cmd_input(mut input: &str) -> Result<(Vec<&str>, &str), anyhow::Error> {
-    let mut args = Vec::new();
-    while input.starts_with("--") {
-        let (arg, rest) = input.split_once(" ").ok_or(anyhow!("Invalid command"))?;
-        args.push(arg);
-        input = rest;
-    }
-    Ok((args, input))
+/// Result of a check, which unlike an update never changes the stored state.
+#[derive(Debug, PartialEq)]
+enum CheckResult {
+    Listed(ListingStatus),
+    /// A greylisted entry inside the retry window, that the next update would whitelist.
+    WouldPass,
 }
 
-impl FromStr for Command {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts = s.split_once(" ").unwrap_or((s, ""));
-        let cmd = match parts.0 {
-            "add" => {
-                let (args, rest) = parse_cmd_input(parts.1)?;
-                let mut add_status = None;
-                for arg in args {
-                    let status = status_from_arg(arg);
-                    if let Some(status) = status {
-                        add_status = Some(status)
-                    }
-                }
-                let triplet = rest.parse()?;
-                Command::Add {
-                    triplet,
-                    add_status: add_status.unwrap_or(ListingStatus::White),
-                }
-            }
-            "delete" => {
-                let (_, rest) = parse_cmd_input(parts.1)?;
-                let triplet = rest.parse()?;
-                Command::Delete { triplet }
-            }
-            "check" => {
-                let (args, rest) = parse_cmd_input(parts.1)?;
-                let mut check_status = None;
-                for arg in args {
-                    let status = status_from_arg(arg);
-                    if let Some(status) = status {
-                        check_status = Some(status)
-                    }
-                }
-                let triplet = rest.parse()?;
-                Command::Check {
-                    triplet,
-                    check_status,
-                }
-            }
-            "stats" => Command::Stats,
-            "status" => {
-                let (_, rest) = parse_cmd_input(parts.1)?;
-                let triplet = rest.parse()?;
-                Command::Status { triplet }
-            }
-            "mrtg" => Command::Mrtg,
-            "list" => {
-                let (args, _) = parse_cmd_input(parts.1)?;
-                let mut status_list = Vec::new();
-                for arg in args {
-                    let status = status_from_arg(arg);
-                    if let Some(status) = status {
-                        status_list.push(status);
-                    }
-                }
-                Command::List {
-                    status: status_list,
-                }
-            }
-            "save" => Command::Save,
-            "clear" => {
-                let (args, _) = parse_cmd_input(parts.1)?;
-                let mut status_list = Vec::new();
-                for arg in args {
-                    let status = status_from_arg(arg);
-                    if let Some(status) = status {
-                        status_list.push(status);
-                    }
-                }
-                Command::Clear {
-                    status: status_list,
-                }
-            }
-            "reload" => Command::Reload,
-            // "update" |
-            _ => {
-                let input = if parts.0 == "update" { parts.1 } else { s };
-                let (args, rest) = parse_cmd_input(input)?;
-                let mut check_status = None;
-                for arg in args {
-                    let status = status_from_arg(arg);
-                    if let Some(status) = status {
-                        check_status = Some(status)
-                    }
-                }
-                let triplet = rest.parse()?;
-                Command::Update {
-                    triplet,
-                    check_status,
-                }
-            }
-        };
-        Ok(cmd)
+impl CheckResult {
+    /// The status the entry effectively has for mail passing through.
+    fn status(&self) -> ListingStatus {
+        match self {
+            CheckResult::Listed(status) => status.clone(),
+            CheckResult::WouldPass => ListingStatus::White,
+        }
     }
 }
 
-derive_deserialize_from_fromstr!(Command, "Invalid command");
-
-fn status_from_arg(arg: &str) -> Option<ListingStatus> {
-    match arg {
-        "--white" => Some(ListingStatus::White),
-        "--grey" => Some(ListingStatus::Grey),
-        "--black" => Some(ListingStatus::Black),
-        _ => None,
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckResult::Listed(status) => status.fmt(f),
+            CheckResult::WouldPass => f.write_str("would-pass"),
+        }
     }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-enum ListingStatus {
+pub enum ListingStatus {
     White,
     Grey,
     Black,
+    /// Accepted, but to be filed into a quarantine folder by the MTA
+    Quarantine,
+    /// One of the custom lists of the configuration, answered with its configured answer
+    Custom(String),
+}
+
+impl FromStr for ListingStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "white" => Ok(ListingStatus::White),
+            "grey" => Ok(ListingStatus::Grey),
+            "black" => Ok(ListingStatus::Black),
+            "quarantine" => Ok(ListingStatus::Quarantine),
+            _ => Err(anyhow!("Invalid listing status: {}", s)),
+        }
+    }
 }
+derive_deserialize_from_fromstr!(ListingStatus, "Invalid listing status");
+derive_serialize_from_display!(ListingStatus);
 
 impl Display for ListingStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -804,7 +3024,351 @@ impl Display for ListingStatus {
                 ListingStatus::White => "white",
                 ListingStatus::Grey => "grey",
                 ListingStatus::Black => "black",
+                ListingStatus::Quarantine => "quarantine",
+                ListingStatus::Custom(name) => name,
             }
         ))
     }
 }
+
+impl ListingStatus {
+    /// Name of the list holding entries with this status, as used in messages.
+    fn list_name(&self) -> &str {
+        match self {
+            ListingStatus::White => "whitelist",
+            ListingStatus::Grey => "greylist",
+            ListingStatus::Black => "blacklist",
+            ListingStatus::Quarantine => "quarantine",
+            ListingStatus::Custom(name) => name,
+        }
+    }
+
+    fn participle(&self) -> Cow<'static, str> {
+        match self {
+            ListingStatus::White => "whitelisted".into(),
+            ListingStatus::Grey => "greylisted".into(),
+            ListingStatus::Black => "blacklisted".into(),
+            ListingStatus::Quarantine => "quarantined".into(),
+            ListingStatus::Custom(name) => format!("on the {} list", name).into(),
+        }
+    }
+}
+
+/// Why an entry has its listing status.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Reason {
+    /// Greylisted by an update from the MTA
+    FirstSeen,
+    /// Whitelisted by an update after the retry delay
+    RetryPromoted,
+    /// Still greylisted after a retry, until the retry delay elapsed (pendingWhite)
+    PendingWhite,
+    /// Added by an admin
+    Manual,
+    /// Whitelisted as reply to outgoing mail
+    Outbound,
+    /// Blacklisted by the feed with this name
+    Feed(String),
+}
+
+impl Reason {
+    /// The reason an entry with the given status usually has, which isn't stored.
+    fn default_for(status: &ListingStatus) -> Reason {
+        match status {
+            ListingStatus::Grey => Reason::FirstSeen,
+            ListingStatus::White => Reason::RetryPromoted,
+            ListingStatus::Black | ListingStatus::Quarantine | ListingStatus::Custom(_) => {
+                Reason::Manual
+            }
+        }
+    }
+}
+
+impl FromStr for Reason {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-seen" => Ok(Reason::FirstSeen),
+            "retry-promoted" => Ok(Reason::RetryPromoted),
+            "pending-white" => Ok(Reason::PendingWhite),
+            "manual" => Ok(Reason::Manual),
+            "outbound" => Ok(Reason::Outbound),
+            _ => match s.strip_prefix("feed:") {
+                Some(name) if !name.is_empty() => Ok(Reason::Feed(name.to_string())),
+                _ => Err(anyhow!("Invalid entry reason: {}", s)),
+            },
+        }
+    }
+}
+derive_deserialize_from_fromstr!(Reason, "Invalid entry reason");
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::FirstSeen => f.write_str("first-seen"),
+            Reason::RetryPromoted => f.write_str("retry-promoted"),
+            Reason::PendingWhite => f.write_str("pending-white"),
+            Reason::Manual => f.write_str("manual"),
+            Reason::Outbound => f.write_str("outbound"),
+            Reason::Feed(name) => write!(f, "feed:{}", name),
+        }
+    }
+}
+derive_serialize_from_display!(Reason);
+
+/// The lifetime of an entry added by an admin, forever unless a ttl is given.
+fn manual_lifetime(ttl: Option<Duration>, now: SystemTime) -> Lifetime {
+    match ttl {
+        Some(ttl) => Lifetime::Until(now + ttl),
+        None => Lifetime::Permanent,
+    }
+}
+
+/// How long an entry is kept.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Lifetime {
+    /// Until it wasn't seen for the timeout of its list, the usual lifetime which isn't stored
+    Timeout,
+    /// Forever, for entries added by an admin
+    Permanent,
+    /// Until the given time, for entries added with a ttl
+    Until(SystemTime),
+}
+
+impl Lifetime {
+    /// Whether the entry is pruned by the timeouts and its status changed by updates.
+    fn is_automatic(&self) -> bool {
+        *self == Lifetime::Timeout
+    }
+}
+
+impl FromStr for Lifetime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timeout" => Ok(Lifetime::Timeout),
+            "permanent" => Ok(Lifetime::Permanent),
+            _ => s
+                .strip_prefix("until:")
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .map(|seconds| Lifetime::Until(UNIX_EPOCH + Duration::from_secs(seconds)))
+                .ok_or_else(|| anyhow!("Invalid entry lifetime: {}", s)),
+        }
+    }
+}
+derive_deserialize_from_fromstr!(Lifetime, "Invalid entry lifetime");
+
+impl Display for Lifetime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lifetime::Timeout => f.write_str("timeout"),
+            Lifetime::Permanent => f.write_str("permanent"),
+            Lifetime::Until(time) => write!(
+                f,
+                "until:{}",
+                time.duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ),
+        }
+    }
+}
+derive_serialize_from_display!(Lifetime);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 24 * HOUR;
+
+    /// An app whose lists each expire after a different time: grey after retryMax (1h), white
+    /// after 2 days, black after 3 and quarantine after 4.
+    fn app() -> App {
+        let config: Config = serde_ini::from_str(
+            "[timeouts]\nretryMin = 1m\nretryMax = 1h\nexpire = 1d\nwhiteExpire = 2d\nblackExpire = 3d\nquarantineExpire = 4d\n\n[socket]\npath = /nonexistent/socket\nmode = 0600\n\n[data]\n",
+        )
+        .unwrap();
+        App::with_config(config)
+    }
+
+    fn add(app: &mut App, ip: &str, status: ListingStatus, now: SystemTime) {
+        let key = Key::Triplet(
+            format!("{} s@example.com r@example.org", ip)
+                .parse()
+                .unwrap(),
+        );
+        let reason = Reason::default_for(&status);
+        app.add_entry(key, status, reason, Lifetime::Timeout, now);
+    }
+
+    fn remaining(app: &App) -> Vec<ListingStatus> {
+        let mut statuses = app
+            .triplets
+            .values()
+            .map(|entry| entry.listing_status.clone())
+            .collect::<Vec<_>>();
+        statuses.sort_by_key(|status| status.to_string());
+        statuses
+    }
+
+    #[test]
+    fn lists_expire_by_their_own_timeout() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut app = app();
+        add(&mut app, "192.0.2.1", ListingStatus::Grey, start);
+        add(&mut app, "198.51.100.1", ListingStatus::White, start);
+        add(&mut app, "203.0.113.1", ListingStatus::Black, start);
+        add(&mut app, "10.0.0.1", ListingStatus::Quarantine, start);
+        // The IPs are in distinct /24 subnets, so they don't share entries
+        assert_eq!(app.triplets.len(), 4);
+
+        let expected = [
+            (
+                HOUR - 1,
+                vec![
+                    ListingStatus::Black,
+                    ListingStatus::Grey,
+                    ListingStatus::Quarantine,
+                    ListingStatus::White,
+                ],
+            ),
+            (
+                HOUR,
+                vec![
+                    ListingStatus::Black,
+                    ListingStatus::Quarantine,
+                    ListingStatus::White,
+                ],
+            ),
+            (
+                2 * DAY - 1,
+                vec![
+                    ListingStatus::Black,
+                    ListingStatus::Quarantine,
+                    ListingStatus::White,
+                ],
+            ),
+            (
+                2 * DAY,
+                vec![ListingStatus::Black, ListingStatus::Quarantine],
+            ),
+            (3 * DAY, vec![ListingStatus::Quarantine]),
+            (4 * DAY - 1, vec![ListingStatus::Quarantine]),
+            (4 * DAY, vec![]),
+        ];
+        for (elapsed, statuses) in expected {
+            app.prune_expired_entries(start + Duration::from_secs(elapsed));
+            assert_eq!(remaining(&app), statuses, "after {}s", elapsed);
+        }
+        assert_eq!(app.statistics.expired, 1);
+    }
+
+    #[test]
+    fn lists_expire_after_last_seen() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut app = app();
+        add(&mut app, "198.51.100.1", ListingStatus::White, start);
+        add(&mut app, "203.0.113.1", ListingStatus::Black, start);
+        add(&mut app, "10.0.0.1", ListingStatus::Quarantine, start);
+        // Seeing the entries again a day later extends each of them by a day
+        let seen = start + Duration::from_secs(DAY);
+        add(&mut app, "198.51.100.1", ListingStatus::White, seen);
+        add(&mut app, "203.0.113.1", ListingStatus::Black, seen);
+        add(&mut app, "10.0.0.1", ListingStatus::Quarantine, seen);
+
+        app.prune_expired_entries(start + Duration::from_secs(3 * DAY - 1));
+        assert_eq!(
+            remaining(&app),
+            [
+                ListingStatus::Black,
+                ListingStatus::Quarantine,
+                ListingStatus::White
+            ]
+        );
+        app.prune_expired_entries(start + Duration::from_secs(3 * DAY));
+        assert_eq!(
+            remaining(&app),
+            [ListingStatus::Black, ListingStatus::Quarantine]
+        );
+        let (_, dropped) = app.last_prune.as_ref().unwrap();
+        assert_eq!(dropped.get("white"), Some(&1));
+        app.prune_expired_entries(start + Duration::from_secs(5 * DAY));
+        assert!(remaining(&app).is_empty());
+    }
+
+    #[test]
+    fn permanent_entries_do_not_expire() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut app = app();
+        let key = Key::Network("192.0.2.0/24".parse().unwrap());
+        app.add_entry(
+            key,
+            ListingStatus::Black,
+            Reason::Manual,
+            Lifetime::Permanent,
+            start,
+        );
+        app.prune_expired_entries(start + Duration::from_secs(365 * DAY));
+        assert_eq!(remaining(&app), [ListingStatus::Black]);
+    }
+
+    /// An app with its data files in a new scratch directory.
+    /// An app with its data files in a new scratch directory.
+    fn scratch_app() -> (App, testing::ScratchDir) {
+        let (config, dir) = testing::scratch_config("").unwrap();
+        (App::with_config(config), dir)
+    }
+
+    #[test]
+    fn damaged_files_fall_back_to_both_backups() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (mut app, dir) = scratch_app();
+        add(&mut app, "192.0.2.1", ListingStatus::White, start);
+        app.save_at(start).unwrap();
+        add(&mut app, "198.51.100.1", ListingStatus::Black, start);
+        app.save_at(start).unwrap();
+
+        let statefile = dir.path().join("states");
+        let tripletfile = dir.path().join("triplets");
+        let (entries, _) = load_triplet_states(&tripletfile, &statefile).unwrap();
+        assert_eq!(entries.len(), 2);
+        let saved = [
+            fs::read(&tripletfile).unwrap(),
+            fs::read(&statefile).unwrap(),
+        ];
+
+        let half = saved[1].len() / 2;
+        for (file, content) in [
+            (&statefile, &saved[1][..half]),
+            (&statefile, &b""[..]),
+            (&tripletfile, &b"not a data file\n"[..]),
+        ] {
+            fs::write(&tripletfile, &saved[0]).unwrap();
+            fs::write(&statefile, &saved[1]).unwrap();
+            fs::write(file, content).unwrap();
+            // Both files are read from the backups of the first save, a damaged tripletfile
+            // isn't paired with the states of the second one
+            let (entries, _) = load_triplet_states(&tripletfile, &statefile).unwrap();
+            let keys = entries
+                .iter()
+                .map(|entry| entry.key.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                ["192.0.2.1 s@example.com r@example.org"],
+                "{}: {:?}",
+                file.display(),
+                String::from_utf8_lossy(content)
+            );
+            assert_eq!(entries[0].listing_status, ListingStatus::White);
+        }
+
+        // Without a backup the damaged file isn't silently dropped
+        fs::remove_file(backup_path(&statefile)).unwrap();
+        assert!(load_triplet_states(&tripletfile, &statefile).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::config::{Log, LogTarget};
+use anyhow::anyhow;
+use log::{Level, LevelFilter, Metadata, Record};
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once, OnceLock};
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Writes log messages to stderr, which may be redirected to the log file, or to syslog.
+struct Logger {
+    syslog: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    syslog: AtomicBool::new(false),
+};
+static OPEN_SYSLOG: Once = Once::new();
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.syslog.load(Ordering::Relaxed) {
+            write_syslog(record);
+        } else {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn write_syslog(record: &Record) {
+    let priority = match record.level() {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    };
+    let message = record.args().to_string().replace('\0', "");
+    let message = CString::new(message).unwrap_or_default();
+    // SAFETY: the format string takes exactly the one NUL terminated string passed.
+    unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+}
+
+/// Install the logger or apply a changed log configuration, the level can be overridden
+/// with the RUST_LOG environment variable.
+pub fn configure(config: &Log) -> Result<(), anyhow::Error> {
+    set_log_file(config.file.as_deref())?;
+    let syslog = match config.target {
+        LogTarget::Auto => config.file.is_none() && !std::io::stderr().is_terminal(),
+        LogTarget::Stderr => false,
+        LogTarget::Syslog => true,
+    };
+    if syslog {
+        // SAFETY: the identifier is a static NUL terminated string, as syslog keeps using it.
+        OPEN_SYSLOG.call_once(|| unsafe {
+            libc::openlog(c"greylistd".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON)
+        });
+    }
+    LOGGER.syslog.store(syslog, Ordering::Relaxed);
+    let level = match std::env::var("RUST_LOG") {
+        Ok(level) => level
+            .parse::<LevelFilter>()
+            .map_err(|_| anyhow!("Invalid log level in RUST_LOG: {}", level))?,
+        Err(_) => config.level,
+    };
+    log::set_max_level(level);
+    // Fails on reload, when the logger is installed already
+    let _ = log::set_logger(&LOGGER);
+    Ok(())
+}
+
+/// The file stderr is currently redirected to.
+static LOG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// Duplicate of the stderr the daemon was started with, to restore it.
+static ORIGINAL_STDERR: OnceLock<OwnedFd> = OnceLock::new();
+
+/// Write all log output to the given file instead of stderr, or back to stderr with None.
+fn set_log_file(path: Option<&Path>) -> Result<(), anyhow::Error> {
+    let mut current = LOG_FILE.lock().unwrap();
+    if current.as_deref() == path {
+        return Ok(());
+    }
+    match path {
+        Some(path) => {
+            if ORIGINAL_STDERR.get().is_none() {
+                let _ = ORIGINAL_STDERR.set(std::io::stderr().as_fd().try_clone_to_owned()?);
+            }
+            redirect_stderr(path)?;
+        }
+        None => {
+            if let Some(original) = ORIGINAL_STDERR.get() {
+                dup_to_stderr(original)?;
+            }
+        }
+    }
+    *current = path.map(Path::to_path_buf);
+    Ok(())
+}
+
+/// Reopen the log file, e.g. after it has been rotated.
+pub fn reopen_log_file() -> Result<(), anyhow::Error> {
+    if let Some(path) = &*LOG_FILE.lock().unwrap() {
+        redirect_stderr(path)?;
+    }
+    Ok(())
+}
+
+fn redirect_stderr(path: &Path) -> Result<(), anyhow::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    dup_to_stderr(&file)
+}
+
+fn dup_to_stderr(fd: &impl AsRawFd) -> Result<(), anyhow::Error> {
+    // SAFETY: both are valid open file descriptors, dup2 atomically replaces stderr.
+    if unsafe { libc::dup2(fd.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Rate limit for one kind of log message, so a misbehaving client can't fill the log.
+///
+/// At most `limit` messages are logged per minute, further ones are only counted and the count
+/// is logged when the minute is over.
+pub(crate) struct Throttle {
+    category: &'static str,
+    level: Level,
+    limit: u32,
+    window_start: Instant,
+    logged: u32,
+    suppressed: u32,
+}
+
+impl Throttle {
+    /// Create a throttle, a limit of 0 logs all messages.
+    pub(crate) fn new(category: &'static str, level: Level, limit: u32) -> Throttle {
+        Throttle {
+            category,
+            level,
+            limit,
+            window_start: Instant::now(),
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+
+    pub(crate) fn log(&mut self, message: impl Display) {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.flush();
+            self.window_start = now;
+            self.logged = 0;
+        }
+        if self.limit == 0 || self.logged < self.limit {
+            self.logged += 1;
+            log::log!(self.level, "{}", message);
+        } else {
+            if self.suppressed == 0 {
+                log::log!(
+                    self.level,
+                    "Too many {} messages, suppressing them for the rest of the minute",
+                    self.category
+                );
+            }
+            self.suppressed += 1;
+        }
+    }
+
+    /// Log the number of messages suppressed since the last flush.
+    pub(crate) fn flush(&mut self) {
+        if self.suppressed > 0 {
+            log::log!(
+                self.level,
+                "Suppressed {} {} messages",
+                self.suppressed,
+                self.category
+            );
+            self.suppressed = 0;
+        }
+    }
+}
+
+/// Throttles for the messages that clients can trigger.
+pub(crate) struct Throttles {
+    pub(crate) invalid_command: Throttle,
+    pub(crate) failed_request: Throttle,
+    pub(crate) failed_lookup: Throttle,
+    pub(crate) refused_connection: Throttle,
+    pub(crate) denied_command: Throttle,
+    pub(crate) over_budget: Throttle,
+}
+
+impl Throttles {
+    pub(crate) fn new(limit: u32) -> Throttles {
+        Throttles {
+            invalid_command: Throttle::new("invalid command", Level::Warn, limit),
+            failed_request: Throttle::new("failed request", Level::Error, limit),
+            failed_lookup: Throttle::new("failed lookup", Level::Error, limit),
+            refused_connection: Throttle::new("refused connection", Level::Warn, limit),
+            denied_command: Throttle::new("denied command", Level::Warn, limit),
+            over_budget: Throttle::new("request over budget", Level::Warn, limit),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.invalid_command.flush();
+        self.failed_request.flush();
+        self.failed_lookup.flush();
+        self.refused_connection.flush();
+        self.denied_command.flush();
+        self.over_budget.flush();
+    }
+}
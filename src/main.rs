@@ -1,54 +1,225 @@
+use anyhow::anyhow;
 use crossbeam_channel::unbounded;
 use greylistd::config::Config;
-use greylistd::App;
-use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use greylistd::logging;
+use greylistd::{App, ExitReason, Listener, Stop};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
 use signal_hook::iterator::Signals;
+use std::ffi::OsString;
 use std::fs;
+use std::net::TcpListener;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-fn main() -> Result<(), anyhow::Error> {
-    let file_config = "/etc/greylistd/config";
+const USAGE: &str =
+    "Usage: greylistd [--config <path>] [--socket <path>] [--check-config] [--dump-on-exit]
+       greylistd --diff <old snapshot dir> <new snapshot dir>";
 
-    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+struct Args {
+    config: PathBuf,
+    socket: Option<PathBuf>,
+    check_config: bool,
+    dump_on_exit: bool,
+    diff: Option<(PathBuf, PathBuf)>,
+}
+
+fn parse_args() -> Result<Args, anyhow::Error> {
+    let mut args = Args {
+        config: PathBuf::from("/etc/greylistd/config"),
+        socket: None,
+        check_config: false,
+        dump_on_exit: false,
+        diff: None,
+    };
+    let mut iter = std::env::args_os().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.to_str() {
+            Some("--config") => args.config = value(&mut iter, "--config")?,
+            Some("--socket") => args.socket = Some(value(&mut iter, "--socket")?),
+            Some("--check-config") => args.check_config = true,
+            Some("--dump-on-exit") => args.dump_on_exit = true,
+            Some("--diff") => {
+                let old = value(&mut iter, "--diff")?;
+                args.diff = Some((old, value(&mut iter, "--diff")?));
+            }
+            Some("-h" | "--help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown argument {}\n{}",
+                    arg.to_string_lossy(),
+                    USAGE
+                ))
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn value(iter: &mut impl Iterator<Item = OsString>, name: &str) -> Result<PathBuf, anyhow::Error> {
+    iter.next()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Missing value for {}\n{}", name, USAGE))
+}
+
+fn load_config(args: &Args) -> Result<Config, anyhow::Error> {
+    let mut config = Config::load(&args.config)?;
+    if let Some(socket) = &args.socket {
+        config.override_socket_path(socket.clone());
+    }
+    Ok(config)
+}
+
+fn main() -> Result<ExitCode, anyhow::Error> {
+    let args = parse_args()?;
+
+    if let Some((old, new)) = &args.diff {
+        greylistd::snapshot::diff(old, new, &mut std::io::stdout().lock())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.check_config {
+        let config = load_config(&args)?;
+        App::check(&config, &mut std::io::stdout().lock())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR2])?;
     let (stop_sender, stop_receiver) = unbounded();
     std::thread::spawn(move || {
-        if signals.forever().next().is_some() {
-            stop_sender.send(()).unwrap();
+        for signal in signals.forever() {
+            let stop = match signal {
+                SIGUSR2 => {
+                    if let Err(e) = logging::reopen_log_file() {
+                        log::error!("Failed to reopen log file: {}", e);
+                    }
+                    continue;
+                }
+                SIGHUP => Stop::Reload,
+                _ => Stop::Shutdown,
+            };
+            // Signals during a save are handled once it's done, the receiver lives until exit
+            if stop_sender.send(stop).is_err() {
+                break;
+            }
         }
     });
 
-    loop {
-        let config = Config::load(file_config)?;
+    let mut config = load_config(&args)?;
+    logging::configure(&config.log)?;
+    log::info!("greylistd {} starting", env!("CARGO_PKG_VERSION"));
+    let (systemd_unix, systemd_tcp) = get_systemd_listeners()?;
+    let (mut listener, mut socket_path) = match systemd_unix {
+        Some(listener) => (listener, None),
+        None => (bind_socket(&config)?, Some(config.socket.path.clone())),
+    };
+    // A TCP socket passed by systemd is kept, otherwise tcpListen is bound and followed on reload
+    let (mut tcp_listener, mut tcp_address) = match systemd_tcp {
+        Some(listener) => (Some(listener), None),
+        None => (bind_tcp(&config)?, config.socket.tcp_listen),
+    };
+    let from_systemd = tcp_address.is_none() && tcp_listener.is_some();
 
-        let socket_path;
-        let listener = if let Some(listener) = get_systemd_unix_listener()? {
-            socket_path = None;
-            listener
-        } else {
-            socket_path = Some(config.socket.path.clone());
-            let mode = u32::from_str_radix(&config.socket.mode, 8)?;
-            let listener = UnixListener::bind(&config.socket.path)?;
-            fs::set_permissions(&config.socket.path, fs::Permissions::from_mode(mode))?;
-            listener
-        };
-
-        let app = App::new(config)?;
+    let exit = loop {
+        let result = App::new_loading(config).and_then(|mut app| {
+            app.set_dump_on_exit(args.dump_on_exit);
+            let listeners = std::iter::once(Listener::Unix(&listener))
+                .chain(tcp_listener.as_ref().map(Listener::Tcp))
+                .collect::<Vec<_>>();
+            app.run(&listeners, stop_receiver.clone())
+        });
+        match result {
+            Ok(None) => {}
+            Ok(Some(exit)) => break exit,
+            Err(e) => break failed(e),
+        }
 
-        let reload = app.run(listener, stop_receiver.clone())?;
+        log::info!("Reloading the configuration and data");
+        let reloaded = (|| -> Result<Config, anyhow::Error> {
+            let config = load_config(&args)?;
+            logging::configure(&config.log)?;
+            if let Some(path) = &socket_path {
+                if *path != config.socket.path {
+                    fs::remove_file(path)?;
+                    socket_path = None;
+                    listener = bind_socket(&config)?;
+                    socket_path = Some(config.socket.path.clone());
+                } else {
+                    set_socket_mode(&config)?;
+                }
+            }
+            if !from_systemd && tcp_address != config.socket.tcp_listen {
+                // Close the old socket first, the new address may use the same port
+                drop(tcp_listener.take());
+                tcp_listener = bind_tcp(&config)?;
+                tcp_address = config.socket.tcp_listen;
+            }
+            Ok(config)
+        })();
+        match reloaded {
+            Ok(reloaded) => config = reloaded,
+            Err(e) => break failed(e.context(ExitReason::Reload)),
+        }
+    };
 
-        if let Some(socket_path) = socket_path {
-            fs::remove_file(&socket_path)?;
+    if let Some(socket_path) = socket_path {
+        if let Err(e) = fs::remove_file(&socket_path) {
+            log::error!("Failed to remove {}: {}", socket_path.display(), e);
         }
-        if !reload {
-            break;
+    }
+    log::info!("greylistd stopped: {}", exit);
+    notify_status(&format!("Stopped: {}", exit));
+
+    Ok(ExitCode::from(exit.exit_code()))
+}
+
+/// Log the error the daemon stops with and return its reason.
+fn failed(error: anyhow::Error) -> ExitReason {
+    log::error!("{:#}", error);
+    ExitReason::of(&error)
+}
+
+/// Report the status line to systemd, for `systemctl status`.
+#[allow(unused_variables)]
+fn notify_status(status: &str) {
+    #[cfg(feature = "systemd")]
+    {
+        use systemd::daemon::{STATE_STATUS, STATE_STOPPING};
+
+        let state = [(STATE_STOPPING, "1"), (STATE_STATUS, status)];
+        if let Err(e) = systemd::daemon::notify(false, state.iter()) {
+            log::warn!("Failed to notify systemd: {}", e);
         }
     }
+}
+
+fn bind_socket(config: &Config) -> Result<UnixListener, anyhow::Error> {
+    let listener = UnixListener::bind(&config.socket.path)?;
+    set_socket_mode(config)?;
+    Ok(listener)
+}
+
+fn bind_tcp(config: &Config) -> Result<Option<TcpListener>, anyhow::Error> {
+    match config.socket.tcp_listen {
+        Some(address) => Ok(Some(TcpListener::bind(address)?)),
+        None => Ok(None),
+    }
+}
 
+fn set_socket_mode(config: &Config) -> Result<(), anyhow::Error> {
+    let mode = u32::from_str_radix(&config.socket.mode, 8)?;
+    fs::set_permissions(&config.socket.path, fs::Permissions::from_mode(mode))?;
     Ok(())
 }
 
-fn get_systemd_unix_listener() -> Result<Option<UnixListener>, anyhow::Error> {
+/// The UNIX domain and TCP stream sockets passed by systemd socket activation, if any.
+fn get_systemd_listeners() -> Result<(Option<UnixListener>, Option<TcpListener>), anyhow::Error> {
+    #[allow(unused_mut)]
+    let mut listeners = (None, None);
     #[cfg(feature = "systemd")]
     {
         use std::os::fd::FromRawFd;
@@ -57,16 +228,28 @@ fn get_systemd_unix_listener() -> Result<Option<UnixListener>, anyhow::Error> {
         let fds = systemd::daemon::listen_fds(true)?;
 
         for fd in fds.iter() {
-            if systemd::daemon::is_socket_unix::<String>(
-                fd,
-                Some(SocketType::Stream),
-                Listening::IsListening,
-                None,
-            )? {
-                return Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }));
+            if listeners.0.is_none()
+                && systemd::daemon::is_socket_unix::<String>(
+                    fd,
+                    Some(SocketType::Stream),
+                    Listening::IsListening,
+                    None,
+                )?
+            {
+                listeners.0 = Some(unsafe { UnixListener::from_raw_fd(fd) });
+            } else if listeners.1.is_none()
+                && systemd::daemon::is_socket_inet(
+                    fd,
+                    None,
+                    Some(SocketType::Stream),
+                    Listening::IsListening,
+                    None,
+                )?
+            {
+                listeners.1 = Some(unsafe { TcpListener::from_raw_fd(fd) });
             }
         }
     }
 
-    Ok(None)
+    Ok(listeners)
 }
@@ -0,0 +1,255 @@
+//! The stages check and update requests are decided in, in this order:
+//!
+//! 1. Exceptions: maintenance mode, loading the data and the request budget answer right away.
+//! 2. Middleware added with [App::add_middleware], e.g. to consult a reputation service, each
+//!    may decide the request or pass it on.
+//! 3. The greylist itself, which records updates.
+//! 4. Shaping: the middleware before the deciding stage may adjust the status in reverse order,
+//!    and while greylisting is paused everything is answered with white.
+
+use crate::config::{Role, RolePolicy};
+use crate::connection::Peer;
+use crate::subnets::SubnetHistory;
+use crate::{App, CheckResult, Key, ListingStatus, Reason};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+
+/// The kind of request being decided.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operation {
+    /// Look up the status without recording anything
+    Check,
+    /// Record the attempt and decide it
+    Update,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operation::Check => "check",
+            Operation::Update => "update",
+        })
+    }
+}
+
+/// What middleware gets to see of a check or update request.
+#[derive(Debug)]
+pub struct Envelope<'a> {
+    pub operation: Operation,
+    /// The sender IP, missing for checks of a network
+    pub sender_ip: Option<IpAddr>,
+    /// The sender address, missing for bounces and checks of a bare IP address
+    pub sender: Option<&'a str>,
+    pub recipient: Option<&'a str>,
+    /// Time of the request, which is given by the client when replaying requests
+    pub time: SystemTime,
+    /// The client the request was received from, like "uid=0" or "ip=192.0.2.1"
+    pub client: &'a str,
+    /// Retry history of the sender's subnet, if a subnetfile is configured and it was seen
+    pub subnet: Option<SubnetHistory>,
+}
+
+/// A stage between the built-in exceptions and the greylist. It's sent along with the app to
+/// the thread that serves the requests.
+pub trait Middleware: Send {
+    /// Decide the request, which skips the later middleware and the greylist, or return None
+    /// to pass it on. Updates decided here aren't recorded.
+    fn decide(&mut self, _envelope: &Envelope) -> Option<ListingStatus> {
+        None
+    }
+
+    /// Adjust the status a later stage decided the request with.
+    fn shape(&mut self, _envelope: &Envelope, _status: &mut ListingStatus) {}
+}
+
+/// A check or update request as passed through the stages.
+pub(crate) struct DecisionRequest {
+    pub(crate) operation: Operation,
+    pub(crate) key: Key,
+    pub(crate) now: SystemTime,
+    pub(crate) role: Role,
+}
+
+/// The stage that decided a request.
+#[derive(PartialEq)]
+enum Stage {
+    Exception,
+    Middleware,
+    Greylist,
+}
+
+pub(crate) struct Decision {
+    result: CheckResult,
+    stage: Stage,
+}
+
+impl App {
+    /// Add a stage that is asked before the greylist, after the ones added before. Middleware
+    /// has to be added again after a reload, which creates a new app.
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Decide a check or update request through all stages.
+    pub(crate) fn decide(
+        &mut self,
+        request: &DecisionRequest,
+        peer: &Peer,
+        received: Instant,
+    ) -> Decision {
+        if let Some(status) = self.exception(peer, received) {
+            return Decision {
+                result: CheckResult::Listed(status),
+                stage: Stage::Exception,
+            };
+        }
+
+        let (sender_ip, sender, recipient) = match &request.key {
+            Key::Triplet(triplet) => (
+                Some(triplet.sender_ip),
+                triplet.sender_email.as_deref(),
+                Some(triplet.recipient_email.as_str()),
+            ),
+            key => (key.ip(), None, None),
+        };
+        let client = peer.to_string();
+        let envelope = Envelope {
+            operation: request.operation,
+            sender_ip,
+            sender,
+            recipient,
+            time: request.now,
+            client: &client,
+            subnet: self.subnet_history(&request.key),
+        };
+        // Taken out while the stages run, so the greylist stage can borrow the app
+        let mut middleware = std::mem::take(&mut self.middleware);
+        let decided = middleware
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, stage)| stage.decide(&envelope).map(|status| (index, status)));
+        let (shaping, mut decision) = match decided {
+            Some((index, status)) => (
+                index,
+                Decision {
+                    result: CheckResult::Listed(status),
+                    stage: Stage::Middleware,
+                },
+            ),
+            None => (
+                middleware.len(),
+                Decision {
+                    result: self.greylist_stage(request, peer),
+                    stage: Stage::Greylist,
+                },
+            ),
+        };
+        for stage in middleware[..shaping].iter_mut().rev() {
+            let mut status = decision.result.status();
+            stage.shape(&envelope, &mut status);
+            if status != decision.result.status() {
+                decision.result = CheckResult::Listed(status);
+            }
+        }
+        self.middleware = middleware;
+
+        if self.paused {
+            decision.result = CheckResult::Listed(ListingStatus::White);
+        }
+        self.log_decision(
+            &request.operation.to_string(),
+            &request.key,
+            &decision.result,
+            request.now,
+            request.role,
+        );
+        decision
+    }
+
+    /// The answer of maintenance mode, while loading the data or for requests over budget.
+    fn exception(&mut self, peer: &Peer, received: Instant) -> Option<ListingStatus> {
+        if let Some(status) = &self.maintenance {
+            return Some(status.clone());
+        }
+        if self.loading.is_some() {
+            return Some(self.config.policy.loading_answer.clone());
+        }
+        if self.is_over_budget(received) {
+            return Some(self.over_budget_answer(peer, received));
+        }
+        None
+    }
+
+    /// Look up or update the entry of the key with the policy of its role.
+    fn greylist_stage(&mut self, request: &DecisionRequest, peer: &Peer) -> CheckResult {
+        let (key, now, role) = (&request.key, request.now, request.role);
+        if self.role_policy(role) == RolePolicy::Pass {
+            let status = self
+                .passed_status(key, now)
+                .unwrap_or_else(|e| self.lookup_failed(peer, e));
+            return CheckResult::Listed(status);
+        }
+        match (request.operation, key) {
+            (Operation::Update, Key::Triplet(triplet)) => {
+                match self.add_or_update_triplet(triplet.clone(), now, role) {
+                    Ok(entry) => {
+                        let status = entry.listing_status.clone();
+                        if let Reason::Feed(feed) = entry.reason.clone() {
+                            *self.feed_hits.entry(feed).or_default() += 1;
+                        }
+                        CheckResult::Listed(status)
+                    }
+                    Err(e) => CheckResult::Listed(self.lookup_failed(peer, e)),
+                }
+            }
+            // Updates are only parsed with a triplet
+            (_, key) => {
+                let result = self
+                    .check_key(key, now, role)
+                    .unwrap_or_else(|e| CheckResult::Listed(self.lookup_failed(peer, e)));
+                if result == CheckResult::WouldPass {
+                    self.statistics.would_pass += 1;
+                }
+                result
+            }
+        }
+    }
+
+    /// Write the answer to a decided request, "true" or "false" when asked for a status.
+    /// Exceptions are answered with the status itself, not the answer of its list.
+    ///
+    /// Only decisions of the greylist get the retry hint, as the others don't depend on the
+    /// stored entry.
+    pub(crate) fn write_decision(
+        &self,
+        writer: &mut impl Write,
+        request: &DecisionRequest,
+        decision: &Decision,
+        check_status: Option<ListingStatus>,
+        detailed: bool,
+        retry_hint: bool,
+    ) -> Result<(), anyhow::Error> {
+        let status = decision.result.status();
+        if let Some(check_status) = check_status {
+            write!(writer, "{}", status == check_status)?;
+            return Ok(());
+        }
+        if detailed && decision.result == CheckResult::WouldPass {
+            write!(writer, "{}", decision.result)?;
+        } else if decision.stage == Stage::Exception {
+            write!(writer, "{}", status)?;
+        } else {
+            write!(writer, "{}", self.answer(&status))?;
+        }
+        if retry_hint && decision.stage == Stage::Greylist && status == ListingStatus::Grey {
+            write!(
+                writer,
+                " {}",
+                self.retry_after(&request.key, request.now, request.role)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{App, Key, Lifetime, ListingStatus};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+impl App {
+    /// Run the configured hook for whitelisted entries of important senders that will expire
+    /// within the notification window. Each entry is only reported once, unless it has been seen
+    /// again in the meantime.
+    pub(crate) fn notify_expiring_entries(&mut self, now: SystemTime) {
+        let notify = &self.config.notify;
+        let (Some(hook), Some(important)) = (&notify.hook, &notify.important) else {
+            return;
+        };
+        let expire = self.config.timeouts.white_expire();
+
+        self.notified
+            .retain(|id, last_seen| match self.triplets.get(id) {
+                Some(entry) => entry.triplet_status.last_seen == *last_seen,
+                None => false,
+            });
+        for (id, entry) in &self.triplets {
+            if entry.listing_status != ListingStatus::White || self.notified.contains_key(id) {
+                continue;
+            }
+            let expires = match entry.lifetime {
+                Lifetime::Timeout => entry.triplet_status.last_seen + expire,
+                Lifetime::Permanent => continue,
+                Lifetime::Until(until) => until,
+            };
+            if expires > now + notify.before {
+                continue;
+            }
+            let candidates = match &entry.key {
+                Key::Triplet(triplet) => vec![
+                    triplet.sender_ip.to_string(),
+                    triplet.sender_email.clone().unwrap_or_default(),
+                ],
+                Key::Network(network) => vec![network.to_string()],
+                Key::Hash(_) => continue,
+            };
+            if !important.iter().any(|pattern| {
+                candidates
+                    .iter()
+                    .any(|candidate| matches_pattern(pattern, candidate))
+            }) {
+                continue;
+            }
+
+            let expires = expires.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let mut command = Command::new(hook);
+            command.arg(entry.key.to_string()).arg(expires.to_string());
+            match command.spawn() {
+                Ok(mut child) => {
+                    // Reap the child without blocking request handling
+                    std::thread::spawn(move || child.wait());
+                    self.notified
+                        .insert(id.clone(), entry.triplet_status.last_seen);
+                }
+                Err(e) => log::error!("Failed to run expiry hook {}: {}", hook.display(), e),
+            }
+        }
+    }
+}
+
+/// Case-insensitive match of a pattern where "*" matches any number of characters.
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,59 @@
+//! Answering read-only requests from the data files while the daemon is down, for
+//! `greylist --offline`, so looking at the entries doesn't require starting the service.
+
+use crate::codes::ResponseCode;
+use crate::config::Config;
+use crate::connection::Peer;
+use crate::parser::Request;
+use crate::{check_config, legacy, load_data, App};
+use anyhow::anyhow;
+use std::fs::exists;
+use std::io::Write;
+use std::time::Instant;
+
+impl App {
+    /// Load the data files without changing them and answer the request like the daemon would.
+    /// Nothing is saved, so only requests that read the entries are accepted.
+    pub fn offline(
+        mut config: Config,
+        request: &Request,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
+        if !request.is_read_only() {
+            let line = request.to_string();
+            return Err(anyhow!(
+                "{} can't be answered offline, only check, status, list, stats, mrtg, subnet \
+                 and dump without --to",
+                line.split_whitespace().next().unwrap_or_default()
+            ));
+        }
+        config.data.read_only = true;
+        check_config(&config)?;
+        // Unlike the daemon on its first start, don't answer as if there were no entries
+        if !exists(&config.data.statefile)? && legacy::legacy_files(&config).is_none() {
+            return Err(anyhow!(
+                "The data file {} doesn't exist",
+                config.data.statefile.display()
+            ));
+        }
+        let data = load_data(&config)?;
+        let mut app = App::with_config(config);
+        app.install(data);
+
+        let mut answer = Vec::new();
+        let handled = app.handle_command(
+            request.to_string().as_bytes(),
+            &mut answer,
+            &Peer::local(),
+            Instant::now(),
+        )?;
+        match handled.code {
+            ResponseCode::Ok | ResponseCode::Unseen => {
+                writer.write_all(&answer)?;
+                writeln!(writer)?;
+                Ok(())
+            }
+            _ => Err(anyhow!("{}", String::from_utf8_lossy(&answer))),
+        }
+    }
+}
@@ -0,0 +1,1294 @@
+//! Parsing of the requests received on the socket.
+//!
+//! Requests contain whatever a remote sender put into the envelope, so the grammar is strict:
+//! every command only accepts its own options, commands without arguments reject trailing input
+//! and the number and length of words are bounded.
+
+use crate::config::Role;
+use crate::serde_utils::parse_duration;
+use crate::{Key, ListingStatus, Network, Triplet};
+use anyhow::anyhow;
+use serde_plain::derive_deserialize_from_fromstr;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of options before the arguments of a command.
+const MAX_OPTIONS: usize = 16;
+/// Maximum number of words in a triplet or network argument.
+const MAX_WORDS: usize = 3;
+/// Maximum length of a single word in bytes, well above the 256 octets RFC 5321 allows for a path.
+const MAX_WORD_LENGTH: usize = 1024;
+
+/// Number of whitelisted triplets in a subnet from which summarize proposes it by default.
+const DEFAULT_SUMMARY_MIN_ENTRIES: usize = 10;
+
+/// Maximum length of a request line in bytes.
+pub(crate) const MAX_REQUEST_LENGTH: usize = 4096;
+const STATUS_OPTIONS: &[&str] = &["--white", "--grey", "--black", "--quarantine", "--list"];
+const ADD_OPTIONS: &[&str] = &[
+    "--white",
+    "--grey",
+    "--black",
+    "--quarantine",
+    "--list",
+    "--ttl",
+];
+/// Options followed by a value
+const VALUE_OPTIONS: &[&str] = &[
+    "--list",
+    "--ttl",
+    "--ip",
+    "--recipient",
+    "--sender",
+    "--since",
+    "--sort",
+    "--limit",
+    "--role",
+];
+const UPDATE_OPTIONS: &[&str] = &[
+    "--white",
+    "--grey",
+    "--black",
+    "--quarantine",
+    "--list",
+    "--retry-hint",
+    "--role",
+];
+
+/// Whether a raw request is binary data instead of a command line, i.e. not UTF-8 or containing
+/// control characters other than tabs, e.g. when a TLS or other client connects to the socket.
+pub(crate) fn is_binary(input: &[u8]) -> bool {
+    match std::str::from_utf8(input) {
+        Ok(line) => line.chars().any(|c| c.is_control() && c != '\t'),
+        Err(_) => true,
+    }
+}
+
+/// Parse a raw request as received on the socket.
+pub(crate) fn parse_request(input: &[u8]) -> Result<Command, anyhow::Error> {
+    let line = std::str::from_utf8(input)?;
+    if line
+        .split_whitespace()
+        .any(|word| word.len() > MAX_WORD_LENGTH)
+    {
+        return Err(anyhow!(
+            "Word too long, at most {} bytes allowed",
+            MAX_WORD_LENGTH
+        ));
+    }
+    line.parse()
+}
+
+/// Parse a raw request like the daemon does and discard the result, as entry point for fuzzing.
+pub fn check_request(input: &[u8]) -> Result<(), anyhow::Error> {
+    parse_request(input).map(|_| ())
+}
+
+/// A request parsed like the daemon does, so clients can reject invalid requests before
+/// sending them.
+pub struct Request {
+    line: String,
+    command: Command,
+}
+
+impl Request {
+    /// Whether the answer is "true" or "false", for checks with a status like `check --white`.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self.command,
+            Command::Check {
+                check_status: Some(_),
+                ..
+            } | Command::Update {
+                check_status: Some(_),
+                ..
+            }
+        )
+    }
+
+    /// Whether the request only reads the entries, see `greylist --offline`.
+    pub fn is_read_only(&self) -> bool {
+        self.command.is_read_only()
+    }
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Request {
+            line: s.to_string(),
+            command: parse_request(s.as_bytes())?,
+        })
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.line)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Command {
+    Add {
+        key: Key,
+        add_status: ListingStatus,
+        /// How long the entry is kept with `--ttl`, instead of forever
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: Key,
+    },
+    Check {
+        key: Key,
+        check_status: Option<ListingStatus>,
+        /// Answer "would-pass" instead of "white" for greylisted entries that only the next
+        /// update would whitelist.
+        detailed: bool,
+        /// Append the seconds until a retry would pass to grey answers
+        retry_hint: bool,
+        /// Time of the request given with `time=<epoch>`, instead of the current time
+        time: Option<SystemTime>,
+        /// Role of the receiving mail server, instead of the one of the recipient domain
+        role: Option<Role>,
+    },
+    Update {
+        triplet: Triplet,
+        check_status: Option<ListingStatus>,
+        /// Append the seconds until a retry would pass to grey answers
+        retry_hint: bool,
+        /// Time of the request given with `time=<epoch>`, instead of the current time
+        time: Option<SystemTime>,
+        /// Role of the receiving mail server, instead of the one of the recipient domain
+        role: Option<Role>,
+    },
+    Stats {
+        /// Show request counters per client instead of the greylist statistics
+        clients: bool,
+        /// Show the listed entries by source instead of the greylist statistics
+        sources: bool,
+        /// Show the entries and rejected requests per feed instead of the greylist statistics
+        feeds: bool,
+        /// Answer with a JSON document instead of text
+        json: bool,
+    },
+    Status {
+        key: Key,
+        /// Answer with a JSON object including the timestamps of the entry
+        json: bool,
+    },
+    Mrtg {
+        /// Report the automatic and manual entries with this status instead of the counters
+        status: Option<ListingStatus>,
+    },
+    List {
+        status: Vec<ListingStatus>,
+        /// Show why each entry has its status
+        reasons: bool,
+        /// Answer with a JSON array of the entries instead of text
+        json: bool,
+        /// Only list a page of the entries ordered by hash, starting at this hash
+        cursor: Option<u64>,
+        /// Which of the entries are listed and in which order
+        filter: ListFilter,
+        /// Only answer with the number of matching entries per list
+        count: bool,
+    },
+    Save,
+    Pause,
+    Resume,
+    Maintenance {
+        /// The status to answer with, or None to leave maintenance mode
+        status: Option<ListingStatus>,
+    },
+    Dump {
+        to: Option<PathBuf>,
+    },
+    Reload {
+        force: bool,
+    },
+    Debug(DebugCommand),
+    PolicyTest {
+        file: PathBuf,
+    },
+    Clear {
+        status: Vec<ListingStatus>,
+    },
+    Summarize {
+        /// Minimum number of whitelisted triplets in a subnet to propose it
+        min_entries: usize,
+        /// Add the proposed subnets to the whitelist
+        apply: bool,
+    },
+    /// Mail was sent to a remote address, whitelist the triplet of replies to it
+    Outbound {
+        reply: Triplet,
+    },
+    /// Show the retry history of the subnets within the network, or the one containing it
+    Subnet {
+        network: Network,
+        /// Answer with a JSON array of the subnets instead of text
+        json: bool,
+    },
+}
+
+/// Names of all commands, as used in the socket allow list.
+pub(crate) const COMMAND_NAMES: &[&str] = &[
+    "add",
+    "delete",
+    "check",
+    "update",
+    "stats",
+    "status",
+    "mrtg",
+    "list",
+    "save",
+    "pause",
+    "resume",
+    "maintenance",
+    "dump",
+    "reload",
+    "debug",
+    "policy",
+    "clear",
+    "summarize",
+    "outbound",
+    "subnet",
+];
+
+impl Command {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Add { .. } => "add",
+            Command::Delete { .. } => "delete",
+            Command::Check { .. } => "check",
+            Command::Update { .. } => "update",
+            Command::Stats { .. } => "stats",
+            Command::Status { .. } => "status",
+            Command::Mrtg { .. } => "mrtg",
+            Command::List { .. } => "list",
+            Command::Save => "save",
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::Maintenance { .. } => "maintenance",
+            Command::Dump { .. } => "dump",
+            Command::Reload { .. } => "reload",
+            Command::Debug(_) => "debug",
+            Command::PolicyTest { .. } => "policy",
+            Command::Clear { .. } => "clear",
+            Command::Summarize { .. } => "summarize",
+            Command::Outbound { .. } => "outbound",
+            Command::Subnet { .. } => "subnet",
+        }
+    }
+
+    /// Whether the command changes entries, the daemon state or files, so it's only allowed for
+    /// admins.
+    pub(crate) fn needs_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::Add { .. }
+                | Command::Delete { .. }
+                | Command::Clear { .. }
+                | Command::Save
+                | Command::Reload { .. }
+                | Command::Pause
+                | Command::Resume
+                | Command::Maintenance { .. }
+                | Command::Dump { to: Some(_) }
+                | Command::PolicyTest { .. }
+                | Command::Summarize { apply: true, .. }
+        )
+    }
+
+    /// Whether the command only reads the entries, so it can be answered from the data files
+    /// with `greylist --offline`.
+    pub(crate) fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Check { .. }
+                | Command::Stats { .. }
+                | Command::Status { .. }
+                | Command::Mrtg { .. }
+                | Command::List { .. }
+                | Command::Dump { to: None }
+                | Command::Subnet { .. }
+        )
+    }
+
+    /// The listing statuses given as options.
+    pub(crate) fn statuses(&self) -> Vec<&ListingStatus> {
+        match self {
+            Command::Add { add_status, .. } => vec![add_status],
+            Command::Check { check_status, .. }
+            | Command::Update { check_status, .. }
+            | Command::Mrtg {
+                status: check_status,
+            } => check_status.iter().collect(),
+            Command::List { status, .. } | Command::Clear { status } => status.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Conditions the entries shown by `list` have to meet, their order and number.
+#[derive(Debug, Default)]
+pub(crate) struct ListFilter {
+    /// Only entries whose sender IP or network is within this network
+    pub(crate) ip: Option<Network>,
+    /// Only entries whose recipient contains this, in lowercase
+    pub(crate) recipient: Option<String>,
+    /// Only entries whose sender contains this, in lowercase
+    pub(crate) sender: Option<String>,
+    /// Only entries seen within this time
+    pub(crate) since: Option<Duration>,
+    pub(crate) sort: Option<ListSort>,
+    /// Only the first entries in the sort order
+    pub(crate) limit: Option<usize>,
+}
+
+/// Order of the listed entries, the largest first.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ListSort {
+    LastSeen,
+    Count,
+}
+
+impl FromStr for ListSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last_seen" => Ok(ListSort::LastSeen),
+            "count" => Ok(ListSort::Count),
+            _ => Err(anyhow!(
+                "Invalid sort order: {}, use last_seen or count",
+                s.escape_debug()
+            )),
+        }
+    }
+}
+
+impl ListFilter {
+    fn from_args(args: &[&str]) -> Result<ListFilter, anyhow::Error> {
+        let lowercase = |option| option_value(args, option).map(str::to_lowercase);
+        Ok(ListFilter {
+            ip: option_value(args, "--ip").map(parse_key).transpose()?,
+            recipient: lowercase("--recipient"),
+            sender: lowercase("--sender"),
+            since: option_value(args, "--since")
+                .map(|since| parse_duration(since).map_err(|e| anyhow!(e)))
+                .transpose()?,
+            sort: option_value(args, "--sort").map(str::parse).transpose()?,
+            limit: option_value(args, "--limit")
+                .map(|limit| {
+                    limit
+                        .parse()
+                        .ok()
+                        .filter(|limit| *limit > 0)
+                        .ok_or_else(|| anyhow!("Invalid limit: {}", limit.escape_debug()))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum DebugCommand {
+    Conflicts,
+    /// Open file descriptors, threads and the connection backlog
+    Resources,
+}
+
+/// Split the leading `--options` from the arguments, rejecting options not in `allowed`.
+///
+/// Options in [VALUE_OPTIONS] take a value, it's returned together with the option as one
+/// option like `--list partner`.
+fn parse_cmd_input<'a>(
+    mut input: &'a str,
+    allowed: &[&str],
+) -> Result<(Vec<&'a str>, &'a str), anyhow::Error> {
+    let mut args = Vec::new();
+    while input.starts_with("--") {
+        if args.len() == MAX_OPTIONS {
+            return Err(anyhow!("Too many options"));
+        }
+        let (arg, rest) = input.split_once(" ").unwrap_or((input, ""));
+        if !allowed.contains(&arg) {
+            return Err(anyhow!("Unknown option: {}", arg.escape_debug()));
+        }
+        if VALUE_OPTIONS.contains(&arg) {
+            let rest = rest.trim_start();
+            let (value, rest) = rest.split_once(" ").unwrap_or((rest, ""));
+            if value.is_empty() || value.starts_with("--") {
+                return Err(anyhow!("Missing value after {}", arg));
+            }
+            args.push(&input[..input.len() - rest.len()]);
+            input = rest.trim_start();
+            continue;
+        }
+        args.push(arg);
+        input = rest.trim_start();
+    }
+    Ok((args, input))
+}
+
+/// The value of an option parsed by [parse_cmd_input].
+fn option_value<'a>(args: &[&'a str], option: &str) -> Option<&'a str> {
+    args.iter().find_map(|arg| {
+        arg.strip_prefix(option)
+            .and_then(|value| value.strip_prefix(' '))
+            .map(str::trim)
+    })
+}
+
+/// Like [parse_cmd_input], for commands that take no arguments besides their options.
+fn parse_options<'a>(input: &'a str, allowed: &[&str]) -> Result<Vec<&'a str>, anyhow::Error> {
+    let (args, rest) = parse_cmd_input(input, allowed)?;
+    if !rest.trim().is_empty() {
+        return Err(anyhow!("Unexpected arguments"));
+    }
+    Ok(args)
+}
+
+/// Split off a leading `time=<epoch>` argument, which replays a request at that time.
+fn parse_time(input: &str) -> Result<(Option<SystemTime>, &str), anyhow::Error> {
+    let Some(rest) = input.strip_prefix("time=") else {
+        return Ok((None, input));
+    };
+    let (seconds, rest) = rest.split_once(" ").unwrap_or((rest, ""));
+    let seconds = seconds
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Invalid time: {}", seconds.escape_debug()))?;
+    let time = UNIX_EPOCH
+        .checked_add(Duration::from_secs(seconds))
+        .ok_or_else(|| anyhow!("Invalid time: {}", seconds))?;
+    Ok((Some(time), rest.trim_start()))
+}
+
+/// Error of a request with an invalid triplet or network, as opposed to an invalid command.
+#[derive(Debug)]
+pub(crate) struct InvalidKey(anyhow::Error);
+
+impl Display for InvalidKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+/// Parse the triplet or network of a request.
+fn parse_key<T: FromStr<Err = anyhow::Error>>(s: &str) -> Result<T, anyhow::Error> {
+    s.parse().map_err(|e| InvalidKey(e).into())
+}
+
+/// The status selected by the options, at most one may be given.
+fn single_status(args: &[&str]) -> Result<Option<ListingStatus>, anyhow::Error> {
+    let mut statuses = args.iter().filter_map(|arg| status_from_arg(arg));
+    let status = statuses.next();
+    if statuses.next().is_some() {
+        return Err(anyhow!(
+            "Only one of --white, --grey, --black, --quarantine and --list may be given"
+        ));
+    }
+    Ok(status)
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split_once(" ").unwrap_or((s, ""));
+        let cmd = match parts.0 {
+            "add" => {
+                let (args, rest) = parse_cmd_input(parts.1, ADD_OPTIONS)?;
+                let key = parse_key(rest)?;
+                let ttl = option_value(&args, "--ttl")
+                    .map(|ttl| parse_duration(ttl).map_err(|e| anyhow!(e)))
+                    .transpose()?;
+                Command::Add {
+                    key,
+                    add_status: single_status(&args)?.unwrap_or(ListingStatus::White),
+                    ttl,
+                }
+            }
+            "delete" => {
+                let (_, rest) = parse_cmd_input(parts.1, &[])?;
+                let key = parse_key(rest)?;
+                Command::Delete { key }
+            }
+            "check" => {
+                let (args, rest) = parse_cmd_input(
+                    parts.1,
+                    &[
+                        "--white",
+                        "--grey",
+                        "--black",
+                        "--quarantine",
+                        "--list",
+                        "--detailed",
+                        "--retry-hint",
+                        "--role",
+                    ],
+                )?;
+                let (time, rest) = parse_time(rest)?;
+                let key = parse_key(rest)?;
+                Command::Check {
+                    key,
+                    check_status: single_status(&args)?,
+                    detailed: args.contains(&"--detailed"),
+                    retry_hint: args.contains(&"--retry-hint"),
+                    time,
+                    role: option_value(&args, "--role").map(str::parse).transpose()?,
+                }
+            }
+            "stats" => {
+                let args =
+                    parse_options(parts.1, &["--clients", "--sources", "--feeds", "--json"])?;
+                if args.iter().filter(|arg| **arg != "--json").count() > 1 {
+                    return Err(anyhow!(
+                        "Only one of --clients, --sources and --feeds may be given"
+                    ));
+                }
+                Command::Stats {
+                    clients: args.contains(&"--clients"),
+                    sources: args.contains(&"--sources"),
+                    feeds: args.contains(&"--feeds"),
+                    json: args.contains(&"--json"),
+                }
+            }
+            "status" => {
+                let (args, rest) = parse_cmd_input(parts.1, &["--json"])?;
+                let key = parse_key(rest)?;
+                Command::Status {
+                    key,
+                    json: args.contains(&"--json"),
+                }
+            }
+            "mrtg" => {
+                let args = parse_options(parts.1, STATUS_OPTIONS)?;
+                Command::Mrtg {
+                    status: single_status(&args)?,
+                }
+            }
+            "list" => {
+                let (args, rest) = parse_cmd_input(
+                    parts.1,
+                    &[
+                        "--white",
+                        "--grey",
+                        "--black",
+                        "--quarantine",
+                        "--list",
+                        "--reasons",
+                        "--json",
+                        "--cursor",
+                        "--ip",
+                        "--recipient",
+                        "--sender",
+                        "--since",
+                        "--sort",
+                        "--limit",
+                        "--count",
+                    ],
+                )?;
+                // The cursor token is the only argument, so --cursor has to be the last option
+                let cursor = match (args.last(), rest.trim()) {
+                    (Some(&"--cursor"), token) => Some(
+                        u64::from_str_radix(token, 16)
+                            .map_err(|_| anyhow!("Invalid cursor: {}", token.escape_debug()))?,
+                    ),
+                    (_, "") if !args.contains(&"--cursor") => None,
+                    (_, "") => return Err(anyhow!("--cursor must be the last option")),
+                    _ => return Err(anyhow!("Unexpected arguments")),
+                };
+                let filter = ListFilter::from_args(&args)?;
+                let count = args.contains(&"--count");
+                if cursor.is_some() && (filter.sort.is_some() || filter.limit.is_some() || count) {
+                    return Err(anyhow!(
+                        "--sort, --limit and --count can't be combined with --cursor"
+                    ));
+                }
+                Command::List {
+                    status: args.iter().filter_map(|arg| status_from_arg(arg)).collect(),
+                    reasons: args.contains(&"--reasons"),
+                    json: args.contains(&"--json"),
+                    cursor,
+                    filter,
+                    count,
+                }
+            }
+            "save" => {
+                parse_options(parts.1, &[])?;
+                Command::Save
+            }
+            "pause" => {
+                parse_options(parts.1, &[])?;
+                Command::Pause
+            }
+            "resume" => {
+                parse_options(parts.1, &[])?;
+                Command::Resume
+            }
+            "maintenance" => {
+                let (args, rest) =
+                    parse_cmd_input(parts.1, &["--white", "--grey", "--black", "--quarantine"])?;
+                let status = match (args.as_slice(), rest.trim()) {
+                    ([arg], "") => status_from_arg(arg),
+                    ([], "off") => None,
+                    _ => {
+                        return Err(anyhow!(
+                            "Use maintenance --white|--grey|--black|--quarantine or off"
+                        ))
+                    }
+                };
+                Command::Maintenance { status }
+            }
+            "dump" => {
+                let (args, rest) = parse_cmd_input(parts.1, &["--to"])?;
+                let to = if args.contains(&"--to") {
+                    if rest.is_empty() {
+                        return Err(anyhow!("Missing dump path"));
+                    }
+                    Some(PathBuf::from(rest))
+                } else if !rest.trim().is_empty() {
+                    return Err(anyhow!("Unexpected arguments, use: dump --to <path>"));
+                } else {
+                    None
+                };
+                Command::Dump { to }
+            }
+            "clear" => {
+                let args = parse_options(parts.1, STATUS_OPTIONS)?;
+                Command::Clear {
+                    status: args.iter().filter_map(|arg| status_from_arg(arg)).collect(),
+                }
+            }
+            "summarize" => {
+                let (args, rest) = parse_cmd_input(parts.1, &["--apply"])?;
+                let min_entries = match rest.trim() {
+                    "" => DEFAULT_SUMMARY_MIN_ENTRIES,
+                    min => min
+                        .parse()
+                        .ok()
+                        .filter(|min| *min > 0)
+                        .ok_or_else(|| anyhow!("Use summarize [--apply] [<min triplets>]"))?,
+                };
+                Command::Summarize {
+                    min_entries,
+                    apply: args.contains(&"--apply"),
+                }
+            }
+            "outbound" => {
+                let (_, rest) = parse_cmd_input(parts.1, &[])?;
+                let [remote, ip, local] = tokenize(rest)?
+                    .try_into()
+                    .map_err(|_| anyhow!("Use outbound <remote address> <ip> <local address>"))?;
+                // The reply comes from the remote side to the local sender
+                let reply = parse_key(&format!("{} {} {}", ip, remote, local))?;
+                Command::Outbound { reply }
+            }
+            "subnet" => {
+                let (args, rest) = parse_cmd_input(parts.1, &["--json"])?;
+                Command::Subnet {
+                    network: parse_key(rest)?,
+                    json: args.contains(&"--json"),
+                }
+            }
+            "reload" => {
+                let args = parse_options(parts.1, &["--force"])?;
+                Command::Reload {
+                    force: args.contains(&"--force"),
+                }
+            }
+            "policy" => {
+                let (sub, rest) = parts.1.split_once(" ").unwrap_or((parts.1, ""));
+                if sub != "test" {
+                    return Err(anyhow!("Invalid policy command"));
+                }
+                let (args, rest) = parse_cmd_input(rest, &["--file"])?;
+                if !args.contains(&"--file") || rest.is_empty() {
+                    return Err(anyhow!(
+                        "Missing scenario file, use: policy test --file <path>"
+                    ));
+                }
+                Command::PolicyTest {
+                    file: PathBuf::from(rest),
+                }
+            }
+            "debug" => match parts.1 {
+                "conflicts" => Command::Debug(DebugCommand::Conflicts),
+                "resources" => Command::Debug(DebugCommand::Resources),
+                _ => return Err(anyhow!("Invalid debug command")),
+            },
+            // "update" |
+            _ => {
+                let input = if parts.0 == "update" { parts.1 } else { s };
+                let (args, rest) = parse_cmd_input(input, UPDATE_OPTIONS)?;
+                let (time, rest) = parse_time(rest)?;
+                let triplet = parse_key(rest)?;
+                Command::Update {
+                    triplet,
+                    check_status: single_status(&args)?,
+                    retry_hint: args.contains(&"--retry-hint"),
+                    time,
+                    role: option_value(&args, "--role").map(str::parse).transpose()?,
+                }
+            }
+        };
+        Ok(cmd)
+    }
+}
+
+derive_deserialize_from_fromstr!(Command, "Invalid command");
+
+fn status_from_arg(arg: &str) -> Option<ListingStatus> {
+    match arg {
+        "--white" => Some(ListingStatus::White),
+        "--grey" => Some(ListingStatus::Grey),
+        "--black" => Some(ListingStatus::Black),
+        "--quarantine" => Some(ListingStatus::Quarantine),
+        _ => arg
+            .strip_prefix("--list ")
+            .map(|name| ListingStatus::Custom(name.trim().to_string())),
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if tokenize(s)?.len() == 1 {
+            Ok(Key::Network(s.trim().parse()?))
+        } else {
+            Ok(Key::Triplet(s.parse()?))
+        }
+    }
+}
+derive_deserialize_from_fromstr!(Key, "Invalid triplet or network");
+
+impl Key {
+    /// Parse a key as stored in the tripletfile. Unlike requests the addresses aren't
+    /// validated, so triplets saved by older versions, e.g. with a sender without domain, still
+    /// load, and words are split at whitespace if they can't be tokenized.
+    pub(crate) fn from_stored(s: &str) -> Result<Key, anyhow::Error> {
+        let words = tokenize(s)
+            .unwrap_or_else(|_| s.split_whitespace().map(str::to_string).collect::<Vec<_>>());
+        let (ip, sender_email, recipient_email) = match words.as_slice() {
+            [_] => return Ok(Key::Network(s.trim().parse()?)),
+            [ip, recipient] => (ip, None, recipient),
+            [ip, sender, recipient] => (ip, Some(sender), recipient),
+            _ => return Err(anyhow!("Invalid triplet: {}", s)),
+        };
+        Ok(Key::Triplet(Triplet {
+            sender_ip: IpAddr::from_str(ip)
+                .map_err(|_| anyhow!("Invalid IP address in triplet: {}", ip))?,
+            sender_email: sender_email.cloned(),
+            recipient_email: recipient_email.clone(),
+        }))
+    }
+}
+
+impl FromStr for Triplet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = tokenize(s)?;
+        let (ip, sender_email, recipient_email) = match parts.as_slice() {
+            [ip, recipient] => (ip, None, recipient),
+            [ip, sender, recipient] => (ip, Some(sender), recipient),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid triplet, expected 2 or 3 words but got {}: {}",
+                    parts.len(),
+                    s
+                ))
+            }
+        };
+        let sender_ip =
+            IpAddr::from_str(ip).map_err(|_| anyhow!("Invalid IP address in triplet: {}", ip))?;
+        if let Some(sender_email) = sender_email {
+            validate_sender(sender_email)
+                .map_err(|e| anyhow!("Invalid sender address {}: {}", sender_email, e))?;
+        }
+        validate_address(recipient_email)
+            .map_err(|e| anyhow!("Invalid recipient address {}: {}", recipient_email, e))?;
+        Ok(Triplet {
+            sender_ip,
+            sender_email: sender_email.cloned(),
+            recipient_email: recipient_email.clone(),
+        })
+    }
+}
+
+/// Split a line into whitespace separated words.
+///
+/// Whitespace inside double quotes doesn't split words and a backslash inside quotes escapes the
+/// next character, so RFC 5321 quoted local parts like `"john doe"@example.org` stay intact.
+/// Quotes and escapes are kept verbatim, as they are part of the address.
+fn tokenize(s: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_quotes = false;
+    let mut chars = s.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                word.push(c);
+            }
+            '\\' if in_quotes => {
+                let Some(escaped) = chars.next() else {
+                    return Err(anyhow!("Unterminated escape sequence: {}", s));
+                };
+                word.push(c);
+                word.push(escaped);
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !word.is_empty() {
+                    if words.len() == MAX_WORDS {
+                        return Err(anyhow!("Too many words, at most {} allowed", MAX_WORDS));
+                    }
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c if c.is_control() => {
+                return Err(anyhow!("Invalid control character in input: {:?}", c));
+            }
+            c => word.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(anyhow!("Unterminated quoted string: {}", s));
+    }
+    if !word.is_empty() {
+        if words.len() == MAX_WORDS {
+            return Err(anyhow!("Too many words, at most {} allowed", MAX_WORDS));
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Validate a sender address. MTAs pass on unqualified senders like `MAIL FROM:<foo>` as they
+/// were given, so a sender without domain is accepted as a local part.
+fn validate_sender(address: &str) -> Result<(), anyhow::Error> {
+    if address.contains('@') {
+        validate_address(address)
+    } else {
+        validate_local_part(address)
+    }
+}
+
+/// Validate a (possibly internationalized, RFC 6531) mail address of the form `local@domain`.
+fn validate_address(address: &str) -> Result<(), anyhow::Error> {
+    let (local, domain) = address
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow!("missing @domain"))?;
+    validate_local_part(local)?;
+    if domain.is_empty() {
+        return Err(anyhow!("empty domain"));
+    }
+    if domain.starts_with('[') {
+        if !domain.ends_with(']') {
+            return Err(anyhow!("malformed address literal"));
+        }
+    } else if domain
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '"' | '@' | '\\' | '[' | ']'))
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+        || domain.contains("..")
+    {
+        return Err(anyhow!("invalid domain"));
+    }
+    Ok(())
+}
+
+/// Validate the local part of an address, quoted or without quotes and whitespace.
+fn validate_local_part(local: &str) -> Result<(), anyhow::Error> {
+    if local.is_empty() {
+        return Err(anyhow!("empty local part"));
+    }
+    if local.starts_with('"') {
+        if local.len() < 2 || !local.ends_with('"') {
+            return Err(anyhow!("malformed quoted local part"));
+        }
+    } else if local.contains('"') {
+        return Err(anyhow!("quote in unquoted local part"));
+    } else if local.contains(char::is_whitespace) {
+        return Err(anyhow!("whitespace in unquoted local part"));
+    }
+    Ok(())
+}
+derive_deserialize_from_fromstr!(Triplet, "Invalid triplet");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn parse(s: &str) -> Result<Command, anyhow::Error> {
+        parse_request(s.as_bytes())
+    }
+
+    fn triplet(cmd: Command) -> Triplet {
+        match cmd {
+            Command::Update { triplet, .. } => triplet,
+            cmd => panic!("Not an update: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn tokenize_splits_at_whitespace() {
+        assert_eq!(
+            tokenize("  192.0.2.1 \t a@example.com  b@example.org ").unwrap(),
+            ["192.0.2.1", "a@example.com", "b@example.org"]
+        );
+        assert!(tokenize("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_words() {
+        assert_eq!(
+            tokenize(r#"192.0.2.1 "john doe"@example.com "a\"b c"@example.org"#).unwrap(),
+            [
+                "192.0.2.1",
+                r#""john doe"@example.com"#,
+                r#""a\"b c"@example.org"#
+            ]
+        );
+        assert!(tokenize(r#"192.0.2.1 "john doe@example.com"#).is_err());
+        assert!(tokenize(r#"192.0.2.1 "john\"#).is_err());
+    }
+
+    #[test]
+    fn tokenize_limits_words() {
+        assert_eq!(tokenize("a b c").unwrap().len(), MAX_WORDS);
+        assert!(tokenize("a b c d").is_err());
+        assert!(tokenize("a b c d ").is_err());
+    }
+
+    #[test]
+    fn tokenize_rejects_control_characters() {
+        assert!(tokenize("192.0.2.1 a\u{0}b@example.com c@example.org").is_err());
+        assert!(tokenize("192.0.2.1 a@example.com c@example.org\u{7f}").is_err());
+        assert!(tokenize("192.0.2.1 \"a\u{1b}\"@example.com c@example.org").is_err());
+        assert!(is_binary(b"update 192.0.2.1 a@example.com\r"));
+        assert!(is_binary(b"\x16\x03\x01"));
+        assert!(!is_binary(b"update\t192.0.2.1 a@example.com"));
+    }
+
+    #[test]
+    fn request_limits_word_length() {
+        let long = format!("192.0.2.1 {}@example.com r@example.org", "a".repeat(1024));
+        assert!(parse(&long).is_err());
+        let short = format!("192.0.2.1 {}@example.com r@example.org", "a".repeat(1000));
+        assert!(parse(&short).is_ok());
+    }
+
+    #[test]
+    fn triplet_addresses() {
+        let t = triplet(parse(r#"update 192.0.2.1 "john doe"@example.com r@example.org"#).unwrap());
+        assert_eq!(t.sender_email.as_deref(), Some(r#""john doe"@example.com"#));
+        // Unqualified senders are passed on by MTAs
+        let t = triplet(parse("update 192.0.2.1 foo r@example.org").unwrap());
+        assert_eq!(t.sender_email.as_deref(), Some("foo"));
+        let t = triplet(parse("192.0.2.1 r@[192.0.2.2]").unwrap());
+        assert_eq!(t.sender_email, None);
+        assert_eq!(t.recipient_email, "r@[192.0.2.2]");
+        for invalid in [
+            "update 192.0.2.1 foo r",
+            "update 192.0.2.1 a@ r@example.org",
+            "update 192.0.2.1 a@example..com r@example.org",
+            "update 192.0.2.1 a\"b@example.com r@example.org",
+            "update 192.0.2.1 s@example.com r@[192.0.2.2",
+            "update 192.0.2.300 s@example.com r@example.org",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn stored_keys_are_not_validated() {
+        let Key::Triplet(t) = Key::from_stored("192.0.2.1 foo r").unwrap() else {
+            panic!("Not a triplet");
+        };
+        assert_eq!(t.sender_email.as_deref(), Some("foo"));
+        assert_eq!(t.recipient_email, "r");
+        // Stray quotes from older versions are split at whitespace
+        assert!(matches!(
+            Key::from_stored("192.0.2.1 \"a b@example.com"),
+            Ok(Key::Triplet(_))
+        ));
+        assert!(matches!(
+            Key::from_stored("192.0.2.0/24"),
+            Ok(Key::Network(Network { prefix: 24, .. }))
+        ));
+        assert!(Key::from_stored("a b c d").is_err());
+    }
+
+    #[test]
+    fn add_delete_and_status() {
+        assert!(matches!(
+            parse("add --black --ttl 1h 192.0.2.0/24").unwrap(),
+            Command::Add {
+                key: Key::Network(_),
+                add_status: ListingStatus::Black,
+                ttl: Some(ttl),
+            } if ttl == Duration::from_secs(3600)
+        ));
+        assert!(matches!(
+            parse("add 192.0.2.1 r@example.org").unwrap(),
+            Command::Add {
+                key: Key::Triplet(_),
+                add_status: ListingStatus::White,
+                ttl: None,
+            }
+        ));
+        assert!(parse("add --white --black 192.0.2.1").is_err());
+        assert!(matches!(
+            parse("delete 192.0.2.1").unwrap(),
+            Command::Delete { .. }
+        ));
+        assert!(parse("delete --white 192.0.2.1").is_err());
+        assert!(matches!(
+            parse("status --json 192.0.2.1 r@example.org").unwrap(),
+            Command::Status { json: true, .. }
+        ));
+    }
+
+    #[test]
+    fn check_and_update() {
+        assert!(matches!(
+            parse("check --list partner --detailed --retry-hint --role backup time=100 192.0.2.1 r@example.org").unwrap(),
+            Command::Check {
+                check_status: Some(ListingStatus::Custom(ref name)),
+                detailed: true,
+                retry_hint: true,
+                time: Some(time),
+                role: Some(Role::Backup),
+                ..
+            } if name == "partner" && time == UNIX_EPOCH + Duration::from_secs(100)
+        ));
+        assert!(matches!(
+            parse("update --grey --role submission 192.0.2.1 s@example.com r@example.org").unwrap(),
+            Command::Update {
+                check_status: Some(ListingStatus::Grey),
+                retry_hint: false,
+                time: None,
+                role: Some(Role::Submission),
+                ..
+            }
+        ));
+        // Bare triplets are updates
+        assert!(matches!(
+            parse("--retry-hint 192.0.2.1 r@example.org").unwrap(),
+            Command::Update {
+                retry_hint: true,
+                ..
+            }
+        ));
+        assert!(parse("update --detailed 192.0.2.1 r@example.org").is_err());
+        assert!(parse("update --role other 192.0.2.1 r@example.org").is_err());
+        assert!(parse("check time=x 192.0.2.1 r@example.org").is_err());
+        assert!(parse("check --list 192.0.2.1 r@example.org").is_err());
+    }
+
+    #[test]
+    fn stats_and_mrtg() {
+        assert!(matches!(
+            parse("stats").unwrap(),
+            Command::Stats {
+                clients: false,
+                sources: false,
+                feeds: false,
+                json: false,
+            }
+        ));
+        assert!(matches!(
+            parse("stats --sources --json").unwrap(),
+            Command::Stats {
+                sources: true,
+                json: true,
+                ..
+            }
+        ));
+        assert!(parse("stats --clients --feeds").is_err());
+        assert!(parse("stats extra").is_err());
+        assert!(matches!(
+            parse("mrtg --quarantine").unwrap(),
+            Command::Mrtg {
+                status: Some(ListingStatus::Quarantine)
+            }
+        ));
+        assert!(matches!(
+            parse("mrtg").unwrap(),
+            Command::Mrtg { status: None }
+        ));
+    }
+
+    #[test]
+    fn list() {
+        let Command::List {
+            status,
+            reasons,
+            json,
+            cursor,
+            filter,
+            count,
+        } = parse("list --white --grey --reasons --ip 192.0.2.0/24 --sender Foo --recipient Bar --since 1h --sort count --limit 10").unwrap()
+        else {
+            panic!("Not a list command");
+        };
+        assert_eq!(status, [ListingStatus::White, ListingStatus::Grey]);
+        assert!(reasons && !json && !count);
+        assert_eq!(cursor, None);
+        assert!(matches!(filter.ip, Some(Network { prefix: 24, .. })));
+        assert_eq!(filter.sender.as_deref(), Some("foo"));
+        assert_eq!(filter.recipient.as_deref(), Some("bar"));
+        assert_eq!(filter.since, Some(Duration::from_secs(3600)));
+        assert!(matches!(filter.sort, Some(ListSort::Count)));
+        assert_eq!(filter.limit, Some(10));
+
+        assert!(matches!(
+            parse("list --json --sender x --cursor 1f").unwrap(),
+            Command::List {
+                json: true,
+                cursor: Some(0x1f),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse("list --count").unwrap(),
+            Command::List { count: true, .. }
+        ));
+        for invalid in [
+            "list --cursor 1f --json",
+            "list --cursor xyz",
+            "list --sort count --cursor 0",
+            "list --sort size",
+            "list --limit 0",
+            "list --ip 192.0.2.0/33",
+            "list extra",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert!(matches!(parse("save").unwrap(), Command::Save));
+        assert!(matches!(parse("pause").unwrap(), Command::Pause));
+        assert!(matches!(parse("resume").unwrap(), Command::Resume));
+        assert!(matches!(
+            parse("reload --force").unwrap(),
+            Command::Reload { force: true }
+        ));
+        assert!(matches!(
+            parse("reload").unwrap(),
+            Command::Reload { force: false }
+        ));
+        assert!(matches!(
+            parse("debug conflicts").unwrap(),
+            Command::Debug(DebugCommand::Conflicts)
+        ));
+        assert!(matches!(
+            parse("debug resources").unwrap(),
+            Command::Debug(DebugCommand::Resources)
+        ));
+        for invalid in ["save now", "pause --white", "resume 1", "debug", "debug x"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn maintenance() {
+        assert!(matches!(
+            parse("maintenance --white").unwrap(),
+            Command::Maintenance {
+                status: Some(ListingStatus::White)
+            }
+        ));
+        assert!(matches!(
+            parse("maintenance off").unwrap(),
+            Command::Maintenance { status: None }
+        ));
+        assert!(parse("maintenance").is_err());
+        assert!(parse("maintenance --white --black").is_err());
+        assert!(parse("maintenance --list partner").is_err());
+    }
+
+    #[test]
+    fn dump_and_policy() {
+        assert!(matches!(parse("dump").unwrap(), Command::Dump { to: None }));
+        assert!(matches!(
+            parse("dump --to /tmp/dump").unwrap(),
+            Command::Dump { to: Some(ref to) } if to == Path::new("/tmp/dump")
+        ));
+        assert!(parse("dump --to").is_err());
+        assert!(parse("dump /tmp/dump").is_err());
+        assert!(matches!(
+            parse("policy test --file /tmp/scenarios").unwrap(),
+            Command::PolicyTest { ref file } if file == Path::new("/tmp/scenarios")
+        ));
+        assert!(parse("policy test").is_err());
+        assert!(parse("policy run --file /tmp/scenarios").is_err());
+    }
+
+    #[test]
+    fn clear_and_summarize() {
+        assert!(matches!(
+            parse("clear --grey --black").unwrap(),
+            Command::Clear { ref status } if *status == [ListingStatus::Grey, ListingStatus::Black]
+        ));
+        assert!(parse("clear 192.0.2.1").is_err());
+        assert!(matches!(
+            parse("summarize").unwrap(),
+            Command::Summarize {
+                min_entries: DEFAULT_SUMMARY_MIN_ENTRIES,
+                apply: false,
+            }
+        ));
+        assert!(matches!(
+            parse("summarize --apply 5").unwrap(),
+            Command::Summarize {
+                min_entries: 5,
+                apply: true,
+            }
+        ));
+        assert!(parse("summarize 0").is_err());
+    }
+
+    #[test]
+    fn outbound_and_subnet() {
+        let Command::Outbound { reply } =
+            parse("outbound remote@example.org 192.0.2.1 local@example.com").unwrap()
+        else {
+            panic!("Not an outbound command");
+        };
+        assert_eq!(reply.sender_email.as_deref(), Some("remote@example.org"));
+        assert_eq!(reply.recipient_email, "local@example.com");
+        assert!(parse("outbound remote@example.org 192.0.2.1").is_err());
+        assert!(matches!(
+            parse("subnet --json 2001:db8::/48").unwrap(),
+            Command::Subnet {
+                network: Network { prefix: 48, .. },
+                json: true,
+            }
+        ));
+        assert!(parse("subnet 192.0.2.1 r@example.org").is_err());
+    }
+
+    #[test]
+    fn options_are_bounded() {
+        let many = format!("check {}192.0.2.1", "--detailed ".repeat(MAX_OPTIONS + 1));
+        assert!(parse(&many).is_err());
+        assert!(parse("check --unknown 192.0.2.1").is_err());
+    }
+}
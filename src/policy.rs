@@ -0,0 +1,147 @@
+use crate::config::RolePolicy;
+use crate::connection::Peer;
+use crate::parser::Command;
+use crate::{manual_lifetime, App, CheckResult, Key, ListingStatus, Reason};
+use anyhow::anyhow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Check that the client may have the daemon read the scenario file: it must be a regular file
+/// owned by the client or by root, so the daemon doesn't read files on behalf of users who can't.
+pub(crate) fn check_scenario_owner(file: &Path, peer: &Peer) -> Result<(), anyhow::Error> {
+    let Some((uid, _)) = peer.credentials else {
+        return Err(anyhow!("the client isn't known"));
+    };
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{} is not a regular file", file.display()));
+    }
+    if uid != 0 && metadata.uid() != uid && metadata.uid() != 0 {
+        return Err(anyhow!(
+            "{} must be owned by the client or by root",
+            file.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Evaluate a file of timestamped synthetic events against the current configuration.
+///
+/// Each non-empty line that doesn't start with `#` has the form `<epoch seconds> <command>`,
+/// e.g. `1700000000 update 192.0.2.1 sender@example.org rcpt@example.net`. The events are applied
+/// in order to an empty copy of the state, so the live data is never modified. Supported commands
+/// are update, check, status, add and delete; timestamps must not decrease.
+pub(crate) fn test_scenarios(
+    app: &App,
+    file: &Path,
+    writer: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let mut scratch = app.scratch();
+    let reader = BufReader::new(File::open(file)?);
+    let mut last_time = UNIX_EPOCH;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (time, cmd) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Line {}: missing command", index + 1))?;
+        let now = UNIX_EPOCH
+            + Duration::from_secs(
+                time.parse()
+                    .map_err(|_| anyhow!("Line {}: invalid timestamp {}", index + 1, time))?,
+            );
+        if now < last_time {
+            return Err(anyhow!(
+                "Line {}: timestamp {} is before the previous event",
+                index + 1,
+                time
+            ));
+        }
+        last_time = now;
+
+        scratch.prune_expired_entries(now);
+        let cmd = cmd.trim();
+        let result = match cmd.parse::<Command>() {
+            Ok(Command::Update {
+                triplet,
+                check_status,
+                role,
+                ..
+            }) => {
+                let key = Key::Triplet(triplet.clone());
+                let role = scratch.role_for(&key, role);
+                let status = if scratch.role_policy(role) == RolePolicy::Pass {
+                    scratch.passed_status(&key, now)?
+                } else {
+                    scratch
+                        .add_or_update_triplet(triplet, now, role)?
+                        .listing_status
+                        .clone()
+                };
+                check_result(&status, check_status.as_ref())
+            }
+            Ok(Command::Check {
+                key,
+                check_status,
+                detailed,
+                role,
+                ..
+            }) => {
+                let role = scratch.role_for(&key, role);
+                let result = if scratch.role_policy(role) == RolePolicy::Pass {
+                    CheckResult::Listed(scratch.passed_status(&key, now)?)
+                } else {
+                    scratch.check_key(&key, now, role)?
+                };
+                if detailed && check_status.is_none() {
+                    result.to_string()
+                } else {
+                    check_result(&result.status(), check_status.as_ref())
+                }
+            }
+            Ok(Command::Status { key, .. }) => match scratch.get_entry(&key) {
+                Some(entry) => entry.listing_status.to_string(),
+                None => "unseen".to_string(),
+            },
+            Ok(Command::Add {
+                key,
+                add_status,
+                ttl,
+            }) => {
+                let lifetime = manual_lifetime(ttl, now);
+                scratch.add_entry(key, add_status.clone(), Reason::Manual, lifetime, now);
+                format!("Added to {}", add_status.list_name())
+            }
+            Ok(Command::Outbound { reply }) => outbound_result(scratch.add_outbound(reply, now)),
+            Ok(Command::Delete { key }) => match scratch.triplets.remove(&scratch.entry_id(&key)) {
+                Some(entry) => format!("Removed from {}", entry.listing_status.list_name()),
+                None => "Not found".to_string(),
+            },
+            Ok(_) => "Unsupported in scenarios".to_string(),
+            Err(e) => format!("Invalid command: {}", e),
+        };
+        writeln!(writer, "{} {} -> {}", time, cmd, result)?;
+    }
+    Ok(())
+}
+
+fn outbound_result(added: bool) -> String {
+    if added {
+        "Added to whitelist".to_string()
+    } else {
+        "Not added, the reply is blacklisted".to_string()
+    }
+}
+
+fn check_result(status: &ListingStatus, check_status: Option<&ListingStatus>) -> String {
+    match check_status {
+        Some(check_status) => (status == check_status).to_string(),
+        None => status.to_string(),
+    }
+}
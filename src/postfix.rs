@@ -0,0 +1,157 @@
+//! The Postfix policy delegation protocol, for use with check_policy_service.
+//!
+//! A request is a block of `name=value` lines ended by an empty line, answered with an
+//! `action=...` line and an empty line. See <https://www.postfix.org/SMTPD_POLICY_README.html>.
+
+use crate::codes::ResponseCode;
+use crate::config::Role;
+use crate::connection::{Connection, Peer};
+use crate::parser::{Command, MAX_REQUEST_LENGTH};
+use crate::transcript::Transcribed;
+use crate::{is_timeout, App, ListingStatus, Triplet, IDLE_TIMEOUT};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Maximum number of attributes in a request, Postfix currently sends about 30.
+const MAX_ATTRIBUTES: usize = 100;
+
+/// Whether the first line of a connection is a policy attribute instead of a command.
+pub(crate) fn is_policy_request(line: &[u8]) -> bool {
+    match line.iter().position(|b| *b == b'=') {
+        Some(end) => {
+            end > 0
+                && line[..end]
+                    .iter()
+                    .all(|b| b.is_ascii_lowercase() || *b == b'_')
+        }
+        None => false,
+    }
+}
+
+impl App {
+    /// Answer the policy requests of a connection, starting with the already read first line.
+    pub(crate) fn handle_policy_requests(
+        &mut self,
+        reader: &mut BufReader<Connection>,
+        writer: &mut Transcribed<impl Write>,
+        first_line: &[u8],
+        peer: &Peer,
+        accepted: Instant,
+    ) -> Result<bool, anyhow::Error> {
+        let mut attributes = HashMap::new();
+        let mut line = first_line.to_vec();
+        // The client waited for the first request since connecting, e.g. in the queue
+        let mut received = accepted;
+        let mut answered = false;
+        loop {
+            if line.is_empty() {
+                let action = self.policy_action(&attributes, peer, received)?;
+                write!(writer, "action={}\n\n", action)?;
+                writer.flush()?;
+                attributes.clear();
+                answered = true;
+                reader.get_ref().set_read_timeout(Some(IDLE_TIMEOUT))?;
+            } else {
+                if attributes.len() == MAX_ATTRIBUTES {
+                    return Err(anyhow!("Too many attributes in policy request"));
+                }
+                if attributes.is_empty() && answered {
+                    received = Instant::now();
+                }
+                let line = std::str::from_utf8(&line)?;
+                let (name, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid policy attribute: {}", line.escape_debug()))?;
+                attributes.insert(name.to_string(), value.to_string());
+            }
+
+            line.clear();
+            let limit = MAX_REQUEST_LENGTH as u64 + 1;
+            match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+                Ok(_) => {}
+                // Postfix reconnects if an idle connection was closed
+                Err(e) if attributes.is_empty() && is_timeout(&e) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            writer.request(&line);
+            if !line.ends_with(b"\n") {
+                if line.is_empty() && attributes.is_empty() {
+                    return Ok(false);
+                }
+                return Err(anyhow!("Incomplete or too long policy request"));
+            }
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+    }
+
+    /// Run the request as update command and map its answer to a Postfix action.
+    fn policy_action(
+        &mut self,
+        attributes: &HashMap<String, String>,
+        peer: &Peer,
+        received: Instant,
+    ) -> Result<String, anyhow::Error> {
+        let attribute = |name| attributes.get(name).map(String::as_str).unwrap_or("");
+        if attribute("request") != "smtpd_access_policy" {
+            return Ok("DUNNO".to_string());
+        }
+        let triplet = match policy_triplet(attribute) {
+            Ok(triplet) => triplet,
+            Err(e) => {
+                self.peers.entry(peer.to_string()).or_default().errors += 1;
+                self.throttles
+                    .invalid_command
+                    .log(format_args!("Invalid policy request from {}: {}", peer, e));
+                return Ok(self.config.postfix.invalid_action().to_string());
+            }
+        };
+        let cmd = Command::Update {
+            triplet,
+            check_status: None,
+            retry_hint: false,
+            time: None,
+            // Clients that logged in submit mail instead of delivering it
+            role: (!attribute("sasl_username").is_empty()).then_some(Role::Submission),
+        };
+        let mut answer = Vec::new();
+        let handled = self.run_command(Ok(cmd), &mut answer, peer, received)?;
+        if handled.code != ResponseCode::Ok {
+            // Refused, which has been logged already
+            return Ok(self.config.postfix.invalid_action().to_string());
+        }
+        let status = std::str::from_utf8(&answer)
+            .ok()
+            .and_then(|answer| answer.parse::<ListingStatus>().ok());
+        Ok(match status {
+            Some(status) => self.config.postfix.action(&status).to_string(),
+            // The entry is on a custom list with its own answer
+            None => "DUNNO".to_string(),
+        })
+    }
+}
+
+/// The triplet of a policy request, taken as Postfix passes the attributes. An empty sender, as
+/// used by bounces, is left out, and senders without domain are kept as they are.
+fn policy_triplet<'a>(attribute: impl Fn(&'a str) -> &'a str) -> Result<Triplet, anyhow::Error> {
+    let client_address = attribute("client_address");
+    let sender_ip = client_address
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow!("invalid client_address {}", client_address.escape_debug()))?;
+    let recipient = attribute("recipient");
+    if recipient.is_empty() {
+        return Err(anyhow!("missing recipient"));
+    }
+    Ok(Triplet {
+        sender_ip,
+        sender_email: Some(attribute("sender"))
+            .filter(|sender| !sender.is_empty())
+            .map(str::to_string),
+        recipient_email: recipient.to_string(),
+    })
+}
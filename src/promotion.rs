@@ -0,0 +1,72 @@
+//! Promotion of greylisted triplets after several distinct delivery attempts, instead of the
+//! first retry after retryMin, for stronger evidence of a real MTA.
+
+use crate::{App, GreylistEntry, Reason};
+use std::time::{Duration, SystemTime};
+
+impl App {
+    /// Least time between two attempts that count separately, promotionSpacing or retryMin.
+    fn promotion_spacing(&self) -> Duration {
+        self.config
+            .policy
+            .promotion_spacing
+            .unwrap_or(self.config.timeouts.retry_min)
+    }
+
+    /// Whether an update of the greylisted entry is too close to its last counted attempt to
+    /// count as an attempt of its own. Always false without promotionAttempts.
+    pub(crate) fn repeated_attempt(&self, entry: &GreylistEntry, now: SystemTime) -> bool {
+        self.config.policy.promotion_attempts.is_some()
+            && now
+                .duration_since(entry.triplet_status.last_seen)
+                .map_or(true, |since| since < self.promotion_spacing())
+    }
+
+    /// Whether an update of the greylisted entry completes the attempts required for its
+    /// promotion: any retry, or with promotionAttempts the attempt completing their number.
+    fn completes_attempts(&self, entry: &GreylistEntry, now: SystemTime) -> bool {
+        match self.config.policy.promotion_attempts {
+            Some(attempts) => {
+                !self.repeated_attempt(entry, now) && entry.triplet_status.count + 1 >= attempts
+            }
+            None => true,
+        }
+    }
+
+    /// Whether an update of the greylisted entry of the given age, within retryMax, whitelists
+    /// it: at least retryMin after the first attempt and completing the required attempts, with
+    /// pendingWhite only after an earlier update completed them.
+    pub(crate) fn promotes(
+        &self,
+        entry: &GreylistEntry,
+        now: SystemTime,
+        age: Duration,
+        retry_min: Duration,
+    ) -> bool {
+        if age < retry_min {
+            return false;
+        }
+        if self.config.policy.pending_white {
+            return entry.reason == Reason::PendingWhite;
+        }
+        self.completes_attempts(entry, now)
+    }
+
+    /// Whether an update of the greylisted entry, which doesn't whitelist it, marks it as
+    /// pending white. Only with pendingWhite.
+    pub(crate) fn marks_pending(&self, entry: &GreylistEntry, now: SystemTime) -> bool {
+        self.config.policy.pending_white && self.completes_attempts(entry, now)
+    }
+
+    /// Time until the next attempt at the greylisted entry counts, none without
+    /// promotionAttempts.
+    pub(crate) fn promotion_wait(&self, entry: &GreylistEntry, now: SystemTime) -> Duration {
+        if self.config.policy.promotion_attempts.is_none() {
+            return Duration::ZERO;
+        }
+        let since = now
+            .duration_since(entry.triplet_status.last_seen)
+            .unwrap_or_default();
+        self.promotion_spacing().saturating_sub(since)
+    }
+}
@@ -0,0 +1,106 @@
+//! The PROXY protocol of haproxy and other load balancers, version 1 (text) and 2 (binary), which
+//! passes the address of the actual client in a header before the first request.
+//! See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use anyhow::anyhow;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Signature a version 2 header starts with.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a version 1 header including the line break.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Maximum length of the addresses and extensions of a version 2 header that is accepted.
+const V2_MAX_LENGTH: usize = 1024;
+
+/// Read the PROXY protocol header at the start of a connection and return the address of the
+/// client, or None if the proxy connected on its own behalf, e.g. for a health check.
+///
+/// The header is read without buffering, so the requests after it are left in the stream.
+pub(crate) fn read_header(stream: &mut impl Read) -> Result<Option<IpAddr>, anyhow::Error> {
+    // Both versions are longer than the signature, "PROXY UNKNOWN\r\n" is the shortest header
+    let mut start = [0; 12];
+    stream
+        .read_exact(&mut start)
+        .map_err(|e| anyhow!("Failed to read PROXY protocol header: {}", e))?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start)
+    } else {
+        Err(anyhow!("Missing PROXY protocol header"))
+    }
+}
+
+/// Read the rest of a text header like "PROXY TCP4 192.0.2.1 192.0.2.2 56324 2501\r\n".
+fn read_v1(stream: &mut impl Read, start: &[u8]) -> Result<Option<IpAddr>, anyhow::Error> {
+    let mut header = start.to_vec();
+    while !header.ends_with(b"\r\n") {
+        if header.len() == V1_MAX_LENGTH {
+            return Err(anyhow!("Too long PROXY protocol header"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+    let header = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| anyhow!("Invalid PROXY protocol header"))?;
+    let invalid = || anyhow!("Invalid PROXY protocol header: {}", header.escape_debug());
+    let mut fields = header.split(' ').skip(1);
+    let ip = match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let ip = fields
+                .next()
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .ok_or_else(invalid)?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid());
+            }
+            ip
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Some(ip))
+}
+
+/// Read the rest of a binary header after its signature.
+fn read_v2(stream: &mut impl Read) -> Result<Option<IpAddr>, anyhow::Error> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed)?;
+    let [version_command, family, length @ ..] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(anyhow!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+    let length = usize::from(u16::from_be_bytes(length));
+    if length > V2_MAX_LENGTH {
+        return Err(anyhow!("Too long PROXY protocol header"));
+    }
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses)?;
+    match version_command & 0x0f {
+        // LOCAL, the proxy connected on its own behalf
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        command => return Err(anyhow!("Unknown PROXY protocol command {}", command)),
+    }
+    // The source address comes first, followed by the destination address and the ports
+    let ip = match family >> 4 {
+        1 if length >= 12 => IpAddr::V4(Ipv4Addr::from(
+            <[u8; 4]>::try_from(&addresses[..4]).unwrap(),
+        )),
+        2 if length >= 36 => IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(&addresses[..16]).unwrap(),
+        )),
+        1 | 2 => return Err(anyhow!("Truncated PROXY protocol addresses")),
+        // Unspecified or UNIX domain socket addresses
+        _ => return Ok(None),
+    };
+    Ok(Some(ip))
+}
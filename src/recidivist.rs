@@ -0,0 +1,80 @@
+use crate::config::{Role, RolePolicy};
+use crate::subnets::subnet_network;
+use crate::{subnet_ip, App, Key, Network};
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// Greylisted attempts from a subnet that expired without being retried in time.
+pub(crate) struct ExpiredAttempts {
+    count: u32,
+    last: SystemTime,
+}
+
+/// The subnet attempts are tracked for, /24 for IPv4 and /64 for IPv6.
+pub(crate) fn attempt_subnet(ip: IpAddr) -> IpAddr {
+    subnet_ip(ip, 24, 64)
+}
+
+impl App {
+    /// The delay before a greylisted entry may pass, retryMin or the one of the role's policy,
+    /// which is raised to recidivistRetryMin for subnets with at least recidivistAttempts
+    /// expired attempts.
+    pub(crate) fn retry_min_for(&self, key: &Key, role: Role) -> Duration {
+        let retry_min = match self.role_policy(role) {
+            RolePolicy::RetryMin(retry_min) => retry_min,
+            RolePolicy::Greylist | RolePolicy::Pass => self.config.timeouts.retry_min,
+        };
+        let policy = &self.config.policy;
+        let (Some(penalty), Some(ip)) = (policy.recidivist_retry_min, key.ip()) else {
+            return retry_min;
+        };
+        match self.expired_attempts.get(&attempt_subnet(ip)) {
+            Some(attempts) if attempts.count >= policy.recidivist_attempts => {
+                penalty.max(retry_min)
+            }
+            _ => retry_min,
+        }
+    }
+
+    /// Remember a greylisted attempt of the key that expired, if the penalty is enabled.
+    pub(crate) fn record_expired_attempt(&mut self, key: &Key, now: SystemTime) {
+        let Some(ip) = key.ip() else {
+            return;
+        };
+        // The report shows the subnets with the most expired attempts
+        if self.config.policy.recidivist_retry_min.is_none()
+            && self.config.report.interval.is_none()
+        {
+            return;
+        }
+        let attempts = self
+            .expired_attempts
+            .entry(attempt_subnet(ip))
+            .or_insert(ExpiredAttempts {
+                count: 0,
+                last: now,
+            });
+        attempts.count += 1;
+        attempts.last = now;
+    }
+
+    /// The subnets with the most expired attempts, most first.
+    pub(crate) fn top_recidivists(&self, count: usize) -> Vec<(Network, u32)> {
+        let mut subnets = self
+            .expired_attempts
+            .iter()
+            .map(|(subnet, attempts)| (subnet_network(*subnet), attempts.count))
+            .collect::<Vec<_>>();
+        subnets.sort_by_key(|(subnet, count)| (Reverse(*count), subnet.addr));
+        subnets.truncate(count);
+        subnets
+    }
+
+    /// Forget subnets without expired attempts for the expire time.
+    pub(crate) fn prune_expired_attempts(&mut self, now: SystemTime) {
+        let oldest = now - self.config.timeouts.expire;
+        self.expired_attempts
+            .retain(|_, attempts| attempts.last > oldest);
+    }
+}
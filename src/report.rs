@@ -0,0 +1,201 @@
+//! Periodic report of the database health: its growth, how many greylisted attempts expired
+//! without a retry, the subnets with the most expired attempts, the size of the data files and
+//! how long saves took.
+
+use crate::App;
+use anyhow::anyhow;
+use crossbeam_channel::{never, tick, Receiver};
+use serde_plain::{derive_deserialize_from_fromstr, derive_serialize_from_display};
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::ops::Add;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often it's checked whether a report is due.
+const REPORT_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Number of subnets with the most expired attempts in a report.
+const REPORT_TOP_SUBNETS: usize = 5;
+
+/// The database at the last report, stored with the statistics as
+/// "<time> <entries> <grey> <expired>".
+#[derive(Clone, Debug)]
+pub(crate) struct ReportBaseline {
+    time: SystemTime,
+    entries: usize,
+    grey: u32,
+    expired: u32,
+}
+
+impl FromStr for ReportBaseline {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [time, entries, grey, expired] = s
+            .split(' ')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid report baseline: {}", s))?;
+        Ok(ReportBaseline {
+            time: UNIX_EPOCH.add(Duration::from_secs(time.parse()?)),
+            entries: entries.parse()?,
+            grey: grey.parse()?,
+            expired: expired.parse()?,
+        })
+    }
+}
+derive_deserialize_from_fromstr!(ReportBaseline, "Invalid report baseline");
+
+impl Display for ReportBaseline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.time.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            self.entries,
+            self.grey,
+            self.expired
+        )
+    }
+}
+derive_serialize_from_display!(ReportBaseline);
+
+/// Durations of the saves since the last report or (re)start.
+#[derive(Default)]
+pub(crate) struct SaveTimes {
+    count: u32,
+    total: Duration,
+    longest: Duration,
+}
+
+impl SaveTimes {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.longest = self.longest.max(duration);
+    }
+}
+
+impl App {
+    pub(crate) fn report_timer(&self) -> Receiver<Instant> {
+        if self.config.report.interval.is_some() {
+            tick(REPORT_CHECK_INTERVAL)
+        } else {
+            never()
+        }
+    }
+
+    /// Write the report if the interval passed since the last one. Without a previous report
+    /// only the baseline of the next one is recorded.
+    pub(crate) fn report_if_due(&mut self, now: SystemTime) {
+        let Some(interval) = self.config.report.interval else {
+            return;
+        };
+        if let Some(last) = &self.statistics.lastreport {
+            if now < last.time + interval {
+                return;
+            }
+            let report = self.report(last, now);
+            self.write_report(&report);
+        }
+        self.statistics.lastreport = Some(ReportBaseline {
+            time: now,
+            entries: self.triplets.len(),
+            grey: self.statistics.grey,
+            expired: self.statistics.expired,
+        });
+        self.save_times = SaveTimes::default();
+        self.dirty = true;
+    }
+
+    fn report(&self, last: &ReportBaseline, now: SystemTime) -> Vec<String> {
+        let days = now
+            .duration_since(last.time)
+            .unwrap_or_default()
+            .as_secs_f64()
+            / 86400.0;
+        let growth = self.triplets.len() as i64 - last.entries as i64;
+        let grey = self.statistics.grey.saturating_sub(last.grey);
+        let expired = self.statistics.expired.saturating_sub(last.expired);
+        let expired_ratio = if grey == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.1}%", f64::from(expired) * 100.0 / f64::from(grey))
+        };
+        let subnets = self
+            .top_recidivists(REPORT_TOP_SUBNETS)
+            .iter()
+            .map(|(subnet, count)| format!("{} ({})", subnet, count))
+            .collect::<Vec<_>>();
+        let data = &self.config.data;
+        let files = [
+            ("statefile", Some(&data.statefile)),
+            ("tripletfile", Some(&data.tripletfile)),
+            ("subnetfile", data.subnetfile.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| Some((name, path?.metadata().ok()?.len())))
+        .collect::<Vec<_>>();
+        let saves = match self.save_times.count {
+            0 => "none".to_string(),
+            count => format!(
+                "{}, average {} ms, longest {} ms",
+                count,
+                self.save_times.total.as_millis() / u128::from(count),
+                self.save_times.longest.as_millis()
+            ),
+        };
+
+        vec![
+            format!("Database report since {}:", self.format_time(last.time)),
+            format!(
+                "entries: {} ({:+}, {:+.1} per day)",
+                self.triplets.len(),
+                growth,
+                growth as f64 / days
+            ),
+            format!(
+                "greylisted attempts: {}, expired without retry: {} ({})",
+                grey, expired, expired_ratio
+            ),
+            format!(
+                "subnets with the most expired attempts: {}",
+                if subnets.is_empty() {
+                    "none".to_string()
+                } else {
+                    subnets.join(", ")
+                }
+            ),
+            format!(
+                "size on disk: {} bytes ({})",
+                files.iter().map(|(_, size)| size).sum::<u64>(),
+                files
+                    .iter()
+                    .map(|(name, size)| format!("{} {} bytes", name, size))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            format!("saves since the last report or restart: {}", saves),
+        ]
+    }
+
+    /// Append the report to the configured file, or log it.
+    fn write_report(&self, report: &[String]) {
+        let Some(path) = &self.config.report.path else {
+            for line in report {
+                log::info!("{}", line);
+            }
+            return;
+        };
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}\n", report.join("\n")));
+        if let Err(e) = result {
+            log::warn!("Failed to write report to {}: {}", path.display(), e);
+        }
+    }
+}
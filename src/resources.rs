@@ -0,0 +1,182 @@
+//! Self-monitoring of the open file descriptors, threads and the backlog of accepted connections,
+//! so operators are warned before accepting connections starts failing with EMFILE.
+
+use crate::App;
+use crossbeam_channel::{tick, Receiver};
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often the resource usage is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Share of the open file limit in percent at which a warning is logged.
+const FD_WARN_PERCENT: usize = 80;
+
+/// Threads at which a warning is logged, the daemon itself only needs a handful, more are
+/// left waiting for hook commands.
+const THREADS_WARN: usize = 64;
+
+/// Accepted connections waiting to be handled at which a warning is logged.
+const BACKLOG_WARN: usize = 100;
+
+/// A snapshot of the resource usage, the counts are missing where /proc isn't available.
+#[derive(Serialize)]
+pub(crate) struct ResourceUsage {
+    open_fds: Option<usize>,
+    /// The soft limit of open files, missing if unlimited
+    fd_limit: Option<usize>,
+    threads: Option<usize>,
+    /// Accepted connections waiting to be handled when the last connection was received
+    backlog: usize,
+    /// The largest backlog since the last (re)start
+    max_backlog: usize,
+}
+
+impl ResourceUsage {
+    /// The resources nearing their limit, by name with a description.
+    fn warnings(&self, recent_backlog: usize) -> Vec<(&'static str, String)> {
+        let mut warnings = Vec::new();
+        if let (Some(open_fds), Some(fd_limit)) = (self.open_fds, self.fd_limit) {
+            if open_fds * 100 >= fd_limit * FD_WARN_PERCENT {
+                warnings.push((
+                    "fds",
+                    format!("{} of {} file descriptors are open", open_fds, fd_limit),
+                ));
+            }
+        }
+        if let Some(threads) = self.threads.filter(|threads| *threads >= THREADS_WARN) {
+            warnings.push(("threads", format!("{} threads are running", threads)));
+        }
+        if recent_backlog >= BACKLOG_WARN {
+            warnings.push((
+                "backlog",
+                format!("up to {} accepted connections waited", recent_backlog),
+            ));
+        }
+        warnings
+    }
+}
+
+/// The backlog seen by the request handling and the resources currently warned about.
+pub(crate) struct ResourceMonitor {
+    backlog: usize,
+    max_backlog: usize,
+    /// The largest backlog since the last check
+    recent_backlog: usize,
+    warned: Vec<&'static str>,
+}
+
+impl ResourceMonitor {
+    pub(crate) fn new() -> ResourceMonitor {
+        ResourceMonitor {
+            backlog: 0,
+            max_backlog: 0,
+            recent_backlog: 0,
+            warned: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record_backlog(&mut self, backlog: usize) {
+        self.backlog = backlog;
+        self.max_backlog = self.max_backlog.max(backlog);
+        self.recent_backlog = self.recent_backlog.max(backlog);
+    }
+
+    fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            open_fds: open_fds(),
+            fd_limit: fd_limit(),
+            threads: threads(),
+            backlog: self.backlog,
+            max_backlog: self.max_backlog,
+        }
+    }
+
+    /// Log a warning when a resource nears its limit and when it's back to normal, once each.
+    fn check(&mut self) {
+        let warnings = self.usage().warnings(self.recent_backlog);
+        self.recent_backlog = self.backlog;
+        for (name, warning) in &warnings {
+            if !self.warned.contains(name) {
+                log::warn!("Resources nearing their limit: {}", warning);
+            }
+        }
+        for name in &self.warned {
+            if !warnings.iter().any(|(warned, _)| warned == name) {
+                log::info!("Resource usage is back to normal: {}", name);
+            }
+        }
+        self.warned = warnings.into_iter().map(|(name, _)| name).collect();
+    }
+}
+
+/// The number of open file descriptors, not counting the one used to list them.
+fn open_fds() -> Option<usize> {
+    let entries = fs::read_dir("/proc/self/fd").ok()?;
+    Some(entries.count().saturating_sub(1))
+}
+
+fn fd_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is valid for writes.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    usize::try_from(limit.rlim_cur).ok()
+}
+
+fn threads() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|threads| threads.trim().parse().ok())
+}
+
+impl App {
+    /// Fires every resource check interval.
+    pub(crate) fn resource_timer(&self) -> Receiver<Instant> {
+        tick(CHECK_INTERVAL)
+    }
+
+    pub(crate) fn check_resources(&mut self) {
+        self.resources.check();
+    }
+
+    pub(crate) fn resource_usage(&self) -> ResourceUsage {
+        self.resources.usage()
+    }
+
+    pub(crate) fn write_resources(&self, writer: &mut impl Write) -> Result<(), anyhow::Error> {
+        let usage = self.resource_usage();
+        let unknown = || "unknown".to_string();
+        writeln!(
+            writer,
+            "Open file descriptors: {} of {}",
+            usage.open_fds.map_or_else(unknown, |fds| fds.to_string()),
+            usage
+                .fd_limit
+                .map_or_else(|| "unlimited".to_string(), |limit| limit.to_string())
+        )?;
+        writeln!(
+            writer,
+            "Threads: {}",
+            usage
+                .threads
+                .map_or_else(unknown, |threads| threads.to_string())
+        )?;
+        writeln!(
+            writer,
+            "Connection backlog: {} (max {} since last (re)start)",
+            usage.backlog, usage.max_backlog
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Roles of the receiving mail server, like a backup MX whose mail is greylisted again by the
+//! primary MX, so each can be greylisted with its own policy.
+
+use crate::config::{Role, RolePolicy};
+use crate::{App, CheckResult, Key, ListingStatus};
+use std::fmt::{Display, Formatter};
+use std::time::SystemTime;
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Primary => "primary",
+            Role::Backup => "backup",
+            Role::Submission => "submission",
+        })
+    }
+}
+
+impl App {
+    /// The role a request for the key is handled with, the tagged one or else the one of the
+    /// most specific configured domain the recipient is in.
+    pub(crate) fn role_for(&self, key: &Key, tag: Option<Role>) -> Role {
+        if let Some(role) = tag {
+            return role;
+        }
+        let Key::Triplet(triplet) = key else {
+            return Role::Primary;
+        };
+        let Some((_, domain)) = triplet.recipient_email.rsplit_once('@') else {
+            return Role::Primary;
+        };
+        let domain = domain.to_lowercase();
+        self.config
+            .roles
+            .domains
+            .iter()
+            .filter(|(name, _)| {
+                domain
+                    .strip_suffix(name.as_str())
+                    .is_some_and(|sub| sub.is_empty() || sub.ends_with('.'))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(Role::Primary, |(_, role)| *role)
+    }
+
+    pub(crate) fn role_policy(&self, role: Role) -> RolePolicy {
+        let roles = &self.config.roles;
+        match role {
+            Role::Primary => roles.primary,
+            Role::Backup => roles.backup,
+            Role::Submission => roles.submission,
+        }
+    }
+
+    /// The answer of the pass policy, which doesn't record anything: listed entries keep their
+    /// status, unseen and greylisted keys are white.
+    pub(crate) fn passed_status(
+        &self,
+        key: &Key,
+        now: SystemTime,
+    ) -> Result<ListingStatus, anyhow::Error> {
+        Ok(match self.check_key(key, now, Role::Primary)? {
+            CheckResult::Listed(ListingStatus::Grey) | CheckResult::WouldPass => {
+                ListingStatus::White
+            }
+            CheckResult::Listed(status) => status,
+        })
+    }
+}
@@ -18,13 +18,67 @@ where
     serializer.serialize_u64(value.duration_since(UNIX_EPOCH).unwrap().as_secs())
 }
 
+/// Deserialize a duration given in seconds or with units, see [parse_duration].
 pub fn deserialize_duration_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: de::Deserializer<'de>,
 {
-    let s: u64 = de::Deserialize::deserialize(deserializer)?;
+    let s: String = de::Deserialize::deserialize(deserializer)?;
+
+    parse_duration(&s).map_err(de::Error::custom)
+}
+
+/// Longest duration accepted in the configuration, about 136 years. Longer ones would overflow
+/// when subtracted from the current time.
+const MAX_DURATION_SECONDS: u64 = u32::MAX as u64;
+
+/// Parse a duration given as plain seconds or as numbers with the units s, m, h, d and w,
+/// e.g. "600", "10m" or "1h 30m".
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("Empty duration".to_string());
+    }
+    let too_long = || format!("Duration too long: {}", s);
+    let mut seconds = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("Invalid duration: {}", s));
+        }
+        let value = rest[..digits].parse::<u64>().map_err(|_| too_long())?;
+        let mut units = rest[digits..].chars();
+        let unit = match units.next() {
+            // Plain seconds, as in older configurations
+            None if rest.len() == s.len() => 1,
+            None => return Err(format!("Missing unit in duration: {}", s)),
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            Some('w') => 7 * 24 * 60 * 60,
+            Some(_) => return Err(format!("Invalid unit in duration: {}", s)),
+        };
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .filter(|seconds| *seconds <= MAX_DURATION_SECONDS)
+            .ok_or_else(too_long)?;
+        rest = units.as_str().trim_start();
+    }
+    Ok(Duration::from_secs(seconds))
+}
 
-    Ok(Duration::from_secs(s))
+pub fn deserialize_optional_duration_seconds<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    deserialize_duration_seconds(deserializer).map(Some)
 }
 
 pub fn deserialize_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
@@ -35,3 +89,57 @@ where
 
     bool::from_str(&s).map_err(|_| de::Error::unknown_variant(&s, &["true", "false"]))
 }
+
+pub fn deserialize_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let s: String = de::Deserialize::deserialize(deserializer)?;
+
+    Ok(Some(
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(s: &str) -> u64 {
+        parse_duration(s)
+            .unwrap_or_else(|e| panic!("{:?}: {}", s, e))
+            .as_secs()
+    }
+
+    #[test]
+    fn parses_seconds_and_units() {
+        assert_eq!(seconds("600"), 600);
+        assert_eq!(seconds("10m"), 600);
+        assert_eq!(seconds("1h30m"), 5400);
+        assert_eq!(seconds("1h 30m"), 5400);
+        assert_eq!(seconds(" 5m "), 300);
+        assert_eq!(seconds("2d1w"), 9 * 24 * 60 * 60);
+        assert_eq!(seconds("0"), 0);
+        assert_eq!(seconds("0s"), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for invalid in ["", "  ", "m", "10x", "10 m", "1h30", "-5", "1.5h"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse_duration("18446744073709551616").is_err());
+        assert!(parse_duration("18446744073709551615w").is_err());
+        assert_eq!(seconds("4294967295"), u64::from(u32::MAX));
+        assert!(parse_duration("4294967296").is_err());
+        assert!(parse_duration("4294967295s 1s").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Entries split into several files by their hash, for very large datasets: the shards are
+//! written and loaded in parallel, and a damaged shard only loses its own entries.
+
+use crate::config::{Config, Data, MAX_SHARDS};
+use crate::{backup_path, load_triplet_states, write_data_file, GreylistEntry, Key, StoredStates};
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{exists, remove_file, rename};
+use std::path::{Path, PathBuf};
+
+/// Path of a shard of a data file, like "states.3".
+fn shard_path(path: &Path, shard: usize) -> PathBuf {
+    let mut shard_path = path.as_os_str().to_owned();
+    shard_path.push(format!(".{}", shard));
+    PathBuf::from(shard_path)
+}
+
+/// Whether the shard or its backup exists.
+fn shard_exists(path: &Path, shard: usize) -> bool {
+    let shard_path = shard_path(path, shard);
+    exists(&shard_path).unwrap_or(false) || exists(backup_path(&shard_path)).unwrap_or(false)
+}
+
+/// Number of shards of the data file up to the last existing one, including the gaps of
+/// shards that were moved aside.
+fn shard_count(path: &Path) -> usize {
+    (0..MAX_SHARDS)
+        .rev()
+        .find(|shard| shard_exists(path, *shard))
+        .map_or(0, |shard| shard + 1)
+}
+
+/// The shard an entry is stored in, by the hash it's stored with.
+fn shard_of(hash: &str, shards: usize) -> usize {
+    hash.parse::<u64>()
+        .map_or(0, |hash| (hash % shards as u64) as usize)
+}
+
+fn split<V>(map: HashMap<String, V>, shards: usize) -> Vec<HashMap<String, V>> {
+    let mut split = (0..shards).map(|_| HashMap::new()).collect::<Vec<_>>();
+    for (hash, value) in map {
+        split[shard_of(&hash, shards)].insert(hash, value);
+    }
+    split
+}
+
+/// Split the states into shards, each with a copy of the statistics.
+fn split_states(states: StoredStates, shards: usize) -> Vec<StoredStates> {
+    let mut white = split(states.white, shards).into_iter();
+    let mut grey = split(states.grey, shards).into_iter();
+    let mut black = split(states.black, shards).into_iter();
+    let mut quarantine = split(states.quarantine, shards).into_iter();
+    let mut custom = split(states.custom, shards).into_iter();
+    let mut reasons = split(states.reasons, shards).into_iter();
+    let mut lifetimes = split(states.lifetimes, shards).into_iter();
+    (0..shards)
+        .map(|_| StoredStates {
+            white: white.next().unwrap_or_default(),
+            grey: grey.next().unwrap_or_default(),
+            black: black.next().unwrap_or_default(),
+            quarantine: quarantine.next().unwrap_or_default(),
+            custom: custom.next().unwrap_or_default(),
+            reasons: reasons.next().unwrap_or_default(),
+            lifetimes: lifetimes.next().unwrap_or_default(),
+            statistics: states.statistics.clone(),
+        })
+        .collect()
+}
+
+/// Remove the shards of the data file from the given one on, with their backups.
+fn remove_shards(path: &Path, from: usize) -> Result<(), anyhow::Error> {
+    for shard in from..shard_count(path) {
+        let shard_path = shard_path(path, shard);
+        for file in [backup_path(&shard_path), shard_path] {
+            if exists(&file)? {
+                remove_file(&file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the entries to the shard files if the data is sharded, and return what's left for
+/// the statefile and tripletfile: all entries without sharding, otherwise only the statistics.
+///
+/// Every shard writes its triplets before its states, like the unsharded files.
+pub(crate) fn write_shards<'a>(
+    data: &Data,
+    triplets: HashMap<String, Cow<'a, Key>>,
+    states: StoredStates,
+) -> Result<(HashMap<String, Cow<'a, Key>>, StoredStates), anyhow::Error> {
+    if data.shards == 1 {
+        remove_shards(&data.statefile, 0)?;
+        remove_shards(&data.tripletfile, 0)?;
+        return Ok((triplets, states));
+    }
+    let statistics = states.statistics.clone();
+    let shards = split(triplets, data.shards)
+        .into_iter()
+        .zip(split_states(states, data.shards))
+        .enumerate()
+        .collect::<Vec<_>>();
+    std::thread::scope(|s| {
+        let writers = shards
+            .iter()
+            .map(|(shard, (triplets, states))| {
+                s.spawn(move || {
+                    if data.savetriplets {
+                        write_data_file(&shard_path(&data.tripletfile, *shard), triplets)?;
+                    }
+                    write_data_file(&shard_path(&data.statefile, *shard), states)
+                })
+            })
+            .collect::<Vec<_>>();
+        writers.into_iter().try_for_each(|writer| {
+            writer
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Writing a shard panicked")))
+        })
+    })?;
+    remove_shards(&data.statefile, data.shards)?;
+    let triplet_shards = if data.savetriplets { data.shards } else { 0 };
+    remove_shards(&data.tripletfile, triplet_shards)?;
+    Ok((
+        HashMap::new(),
+        StoredStates {
+            statistics,
+            ..StoredStates::default()
+        },
+    ))
+}
+
+/// Load the entries of all shards found next to the statefile, whatever the configured number
+/// of shards is, so changing it doesn't lose entries.
+///
+/// A shard that can't be read, not even from its backup, is logged and moved aside with the
+/// suffix ".damaged", so the next save doesn't replace it and the other shards still load.
+/// Read-only loads leave it in place.
+pub(crate) fn load_shards(config: &Config) -> Vec<GreylistEntry> {
+    let data = &config.data;
+    let count = shard_count(&data.statefile);
+    let loaded = std::thread::scope(|s| {
+        let loaders = (0..count)
+            .map(|shard| {
+                s.spawn(move || {
+                    load_triplet_states(
+                        shard_path(&data.tripletfile, shard),
+                        shard_path(&data.statefile, shard),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        loaders
+            .into_iter()
+            .map(|loader| {
+                loader
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("Loading the shard panicked")))
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut entries = Vec::new();
+    for (shard, result) in loaded.into_iter().enumerate() {
+        match result {
+            Ok((shard_entries, _)) => entries.extend(shard_entries),
+            Err(e) => {
+                log::error!(
+                    "Failed to load shard {} of the data, its entries are dropped: {:#}",
+                    shard,
+                    e
+                );
+                if data.read_only {
+                    continue;
+                }
+                for path in [&data.statefile, &data.tripletfile] {
+                    set_aside(&shard_path(path, shard));
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// Move a damaged file and its backup aside.
+fn set_aside(path: &Path) {
+    for file in [backup_path(path), path.to_path_buf()] {
+        if !exists(&file).unwrap_or(false) {
+            continue;
+        }
+        let mut damaged = file.as_os_str().to_owned();
+        damaged.push(".damaged");
+        if let Err(e) = rename(&file, &damaged) {
+            log::error!("Failed to move {} aside: {}", file.display(), e);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{load_triplet_states, ListingStatus};
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Report the entries added, removed and changed in status between two snapshots.
+///
+/// A snapshot is a copy of the data directory, containing the files `states` and `triplets`.
+/// Entries are matched by their triplet or network, so snapshots saved with different onlysubnet
+/// settings can be compared as well.
+pub fn diff(old: &Path, new: &Path, writer: &mut impl Write) -> Result<(), anyhow::Error> {
+    let old = load_snapshot(old)?;
+    let mut new = load_snapshot(new)?;
+
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    let mut lines = BTreeMap::new();
+    for (key, old_status) in old {
+        match new.remove(&key) {
+            None => {
+                removed += 1;
+                lines.insert(key.clone(), format!("removed {} {}", old_status, key));
+            }
+            Some(new_status) if new_status != old_status => {
+                changed += 1;
+                lines.insert(
+                    key.clone(),
+                    format!("changed {} -> {} {}", old_status, new_status, key),
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, new_status) in new {
+        added += 1;
+        lines.insert(key.clone(), format!("added {} {}", new_status, key));
+    }
+
+    for line in lines.values() {
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(
+        writer,
+        "{} added, {} removed, {} changed status",
+        added, removed, changed
+    )?;
+    Ok(())
+}
+
+fn load_snapshot(dir: &Path) -> Result<BTreeMap<String, ListingStatus>, anyhow::Error> {
+    let states = dir.join("states");
+    let triplets = dir.join("triplets");
+    for file in [&states, &triplets] {
+        if !file.is_file() {
+            return Err(anyhow!("Snapshot file {} not found", file.display()));
+        }
+    }
+    let (entries, _) = load_triplet_states(&triplets, &states)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.key.to_string(), entry.listing_status))
+        .collect())
+}
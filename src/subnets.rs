@@ -0,0 +1,232 @@
+//! Retry history of each subnet, kept separately from the triplets so it survives their
+//! expiry, e.g. for middleware that judges the reputation of a sender.
+
+use crate::recidivist::attempt_subnet;
+use crate::{read_data_file, write_data_file, App, Key, Network};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_plain::{derive_deserialize_from_fromstr, derive_serialize_from_display};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::net::IpAddr;
+use std::ops::Add;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How the last greylisted attempt from a subnet ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The attempt is greylisted and may still be retried
+    Greylisted,
+    /// A retry whitelisted the triplet
+    Passed,
+    /// The attempt wasn't retried before retryMax
+    Expired,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Outcome::Greylisted => "greylisted",
+            Outcome::Passed => "passed",
+            Outcome::Expired => "expired",
+        })
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greylisted" => Ok(Outcome::Greylisted),
+            "passed" => Ok(Outcome::Passed),
+            "expired" => Ok(Outcome::Expired),
+            _ => Err(anyhow!("Invalid outcome: {}", s)),
+        }
+    }
+}
+derive_serialize_from_display!(Outcome);
+
+/// Greylisted attempts from a subnet and how many of them were retried successfully, stored as
+/// "<attempts> <successes> <last outcome> <last seen>".
+#[derive(Clone, Copy, Debug)]
+pub struct SubnetHistory {
+    attempts: u32,
+    successes: u32,
+    last_outcome: Outcome,
+    last_seen: SystemTime,
+}
+
+impl SubnetHistory {
+    /// Greylisted first attempts, including retries after the previous attempt expired
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Attempts whitelisted by a retry
+    pub fn successes(&self) -> u32 {
+        self.successes
+    }
+
+    pub fn last_outcome(&self) -> Outcome {
+        self.last_outcome
+    }
+
+    pub fn last_seen(&self) -> SystemTime {
+        self.last_seen
+    }
+}
+
+impl FromStr for SubnetHistory {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [attempts, successes, last_outcome, last_seen] = s
+            .split(' ')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid subnet history: {}", s))?;
+        Ok(SubnetHistory {
+            attempts: attempts.parse()?,
+            successes: successes.parse()?,
+            last_outcome: last_outcome.parse()?,
+            last_seen: UNIX_EPOCH.add(Duration::from_secs(last_seen.parse()?)),
+        })
+    }
+}
+derive_deserialize_from_fromstr!(SubnetHistory, "Invalid subnet history");
+
+impl Display for SubnetHistory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.attempts,
+            self.successes,
+            self.last_outcome,
+            self.last_seen.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        )
+    }
+}
+derive_serialize_from_display!(SubnetHistory);
+
+#[derive(Default, Deserialize, Serialize)]
+struct StoredSubnets {
+    subnets: HashMap<String, SubnetHistory>,
+}
+
+/// Read the subnet history file, a missing file is read as empty.
+pub(crate) fn load_subnets(path: &Path) -> Result<HashMap<IpAddr, SubnetHistory>, anyhow::Error> {
+    read_data_file::<StoredSubnets>(path)?
+        .subnets
+        .into_iter()
+        .map(|(subnet, history)| {
+            let subnet = subnet
+                .parse()
+                .map_err(|_| anyhow!("Invalid subnet in {}: {}", path.display(), subnet))?;
+            Ok((subnet, history))
+        })
+        .collect()
+}
+
+/// The subnet of a tracked address as network, /24 for IPv4 and /64 for IPv6.
+pub(crate) fn subnet_network(subnet: IpAddr) -> Network {
+    Network {
+        addr: subnet,
+        prefix: if subnet.is_ipv4() { 24 } else { 64 },
+    }
+}
+
+impl App {
+    /// The history of the subnet of the key, if it's tracked.
+    pub(crate) fn subnet_history(&self, key: &Key) -> Option<SubnetHistory> {
+        self.subnets.get(&attempt_subnet(key.ip()?)).copied()
+    }
+
+    /// Record how an attempt from the subnet of the key went, if a subnetfile is configured.
+    pub(crate) fn record_subnet_outcome(&mut self, key: &Key, outcome: Outcome, now: SystemTime) {
+        let Some(ip) = key.ip() else {
+            return;
+        };
+        if self.config.data.subnetfile.is_none() {
+            return;
+        }
+        let history = self
+            .subnets
+            .entry(attempt_subnet(ip))
+            .or_insert(SubnetHistory {
+                attempts: 0,
+                successes: 0,
+                last_outcome: outcome,
+                last_seen: now,
+            });
+        match outcome {
+            Outcome::Greylisted => history.attempts += 1,
+            Outcome::Passed => history.successes += 1,
+            Outcome::Expired => {}
+        }
+        history.last_outcome = outcome;
+        history.last_seen = now;
+    }
+
+    /// Forget subnets that haven't been seen for subnetExpire.
+    pub(crate) fn prune_subnets(&mut self, now: SystemTime) {
+        let oldest = now - self.config.timeouts.subnet_expire();
+        self.subnets.retain(|_, history| history.last_seen > oldest);
+    }
+
+    /// Write the subnet history to the subnetfile, if one is configured.
+    pub(crate) fn save_subnets(&self) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.config.data.subnetfile else {
+            return Ok(());
+        };
+        let subnets = StoredSubnets {
+            subnets: self
+                .subnets
+                .iter()
+                .map(|(subnet, history)| (subnet.to_string(), *history))
+                .collect(),
+        };
+        write_data_file(path, &subnets)
+    }
+
+    /// The tracked subnets within the network, or the one containing it, sorted by address.
+    pub(crate) fn subnets_in(&self, network: &Network) -> Vec<(Network, SubnetHistory)> {
+        let mut subnets = self
+            .subnets
+            .iter()
+            .map(|(subnet, history)| (subnet_network(*subnet), *history))
+            .filter(|(subnet, _)| network.contains(subnet.addr) || subnet.contains(network.addr))
+            .collect::<Vec<_>>();
+        subnets.sort_by_key(|(subnet, _)| subnet.addr);
+        subnets
+    }
+
+    /// Answer of the subnet command, returns whether any subnet was found.
+    pub(crate) fn write_subnets(
+        &self,
+        network: &Network,
+        writer: &mut impl Write,
+    ) -> Result<bool, anyhow::Error> {
+        let subnets = self.subnets_in(network);
+        if subnets.is_empty() {
+            write!(writer, "unseen")?;
+            return Ok(false);
+        }
+        for (subnet, history) in subnets {
+            writeln!(
+                writer,
+                "{}: {} attempts, {} successes, last {} at {}",
+                subnet,
+                history.attempts,
+                history.successes,
+                history.last_outcome,
+                self.format_time(history.last_seen)
+            )?;
+        }
+        Ok(true)
+    }
+}
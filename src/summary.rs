@@ -0,0 +1,84 @@
+use crate::{mask_ip, App, Key, Lifetime, ListingStatus, Network, Reason};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+impl App {
+    /// Propose network whitelist entries for subnets (/24 for IPv4 and /64 for IPv6) with at
+    /// least `min_entries` whitelisted triplets, and add them to the whitelist with `apply`.
+    ///
+    /// The triplets are kept, as network entries are only consulted for every check with
+    /// singlecheck enabled.
+    pub(crate) fn summarize_networks(
+        &mut self,
+        min_entries: usize,
+        apply: bool,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
+        let mut counts = HashMap::<Network, usize>::new();
+        for entry in self.triplets.values() {
+            let Key::Triplet(triplet) = &entry.key else {
+                continue;
+            };
+            if entry.listing_status != ListingStatus::White {
+                continue;
+            }
+            let prefix = match triplet.sender_ip {
+                IpAddr::V4(_) => 24,
+                IpAddr::V6(_) => 64,
+            };
+            let network = Network {
+                addr: mask_ip(triplet.sender_ip, prefix),
+                prefix,
+            };
+            *counts.entry(network).or_default() += 1;
+        }
+
+        let mut proposals = counts
+            .into_iter()
+            .filter(|(network, count)| {
+                *count >= min_entries
+                    && self
+                        .get_entry(&Key::Network(*network))
+                        .map_or(true, |entry| entry.listing_status != ListingStatus::White)
+            })
+            .collect::<Vec<_>>();
+        proposals.sort_by(|(a_network, a), (b_network, b)| {
+            b.cmp(a)
+                .then_with(|| a_network.to_string().cmp(&b_network.to_string()))
+        });
+
+        if proposals.is_empty() {
+            writeln!(
+                writer,
+                "No subnet has {} or more whitelisted triplets",
+                min_entries
+            )?;
+        }
+        let now = SystemTime::now();
+        for (network, count) in proposals {
+            if apply {
+                self.add_entry(
+                    Key::Network(network),
+                    ListingStatus::White,
+                    Reason::Manual,
+                    Lifetime::Timeout,
+                    now,
+                );
+                writeln!(
+                    writer,
+                    "{} white triplets fall in {}, added it to the whitelist",
+                    count, network
+                )?;
+            } else {
+                writeln!(
+                    writer,
+                    "{} white triplets fall in {}, consider a subnet whitelist",
+                    count, network
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
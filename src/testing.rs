@@ -0,0 +1,310 @@
+//! Simulation of the greylisting state machine for checking its invariants.
+//!
+//! A [Simulation] applies a sequence of [Event]s with its own clock to an app that starts with
+//! empty data, and verifies after every event that
+//! - no greylisted entry older than retryMax survives a prune,
+//! - the statistics counters never decrease and
+//! - saved data loads back unchanged.
+//!
+//! The events are meant to be generated randomly by a property testing framework, see
+//! `tests/simulation.rs`.
+//!
+//! For exercising the protocol end to end, [spawn_daemon] serves a fresh daemon on a temporary
+//! socket and returns a [Client] for it. [scratch_config] provides the configuration of a daemon
+//! or simulation with its files in a temporary directory.
+
+use crate::config::{Config, Role};
+use crate::{load_data, App, ExitReason, Listener, ListingStatus, Stop, Triplet};
+use anyhow::anyhow;
+use crossbeam_channel::{bounded, Sender};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// Advance the clock by the given number of seconds
+    Advance(u64),
+    /// Update a triplet, given as `<ip> [<sender>] <recipient>`
+    Update(String),
+    /// Check a triplet or network without changing it
+    Check(String),
+    Prune,
+    /// Save the data to the configured files
+    Save,
+    /// Save the data and replace it with what is loaded back from the files, like a restart
+    Reload,
+}
+
+pub struct Simulation {
+    app: App,
+    now: SystemTime,
+    /// Time of the last prune, after which no older greylisted entries may exist
+    last_prune: Option<SystemTime>,
+    counters: [u32; 6],
+}
+
+impl Simulation {
+    /// Start a simulation at the given time in seconds since the epoch.
+    ///
+    /// The data files of the configuration are overwritten when saving, so they should point to
+    /// a scratch directory.
+    pub fn new(config: Config, start: u64) -> Simulation {
+        let mut app = App::with_config(config);
+        let now = UNIX_EPOCH + Duration::from_secs(start);
+        app.statistics.start = now;
+        app.statistics.lastsave = now;
+        let counters = counters(&app);
+        Simulation {
+            app,
+            now,
+            last_prune: None,
+            counters,
+        }
+    }
+
+    /// Apply an event and verify the invariants afterwards.
+    pub fn apply(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        match event {
+            Event::Advance(seconds) => self.now += Duration::from_secs(*seconds),
+            Event::Update(triplet) => {
+                let triplet = triplet.parse::<Triplet>()?;
+                self.app
+                    .add_or_update_triplet(triplet, self.now, Role::Primary)?;
+            }
+            Event::Check(key) => {
+                self.app.check_key(&key.parse()?, self.now, Role::Primary)?;
+            }
+            Event::Prune => {
+                self.app.prune_expired_entries(self.now);
+                self.last_prune = Some(self.now);
+            }
+            Event::Save => {
+                self.save()?;
+            }
+            Event::Reload => {
+                self.save()?;
+                let data = load_data(&self.app.config)?;
+                self.app.install(data);
+            }
+        }
+        self.check_invariants()
+    }
+
+    /// Save the data and verify that it loads back unchanged.
+    fn save(&mut self) -> Result<(), anyhow::Error> {
+        self.app.save_at(self.now)?;
+        self.last_prune = Some(self.now);
+        let mut loaded = App::with_config(self.app.config.clone());
+        loaded.install(load_data(&self.app.config)?);
+        if snapshot(&loaded) != snapshot(&self.app) {
+            return Err(anyhow!("Saved entries don't load back unchanged"));
+        }
+        if counters(&loaded) != counters(&self.app) {
+            return Err(anyhow!("Saved statistics don't load back unchanged"));
+        }
+        Ok(())
+    }
+
+    fn check_invariants(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(last_prune) = self.last_prune {
+            let oldest_retry = last_prune - self.app.config.timeouts.retry_max;
+            if let Some(entry) = self.app.triplets.values().find(|entry| {
+                entry.listing_status == ListingStatus::Grey
+                    && entry.triplet_status.first_seen <= oldest_retry
+            }) {
+                return Err(anyhow!(
+                    "Greylisted entry {} older than retryMax survived a prune",
+                    entry.key
+                ));
+            }
+        }
+        if let Some(entry) = self.app.triplets.values().find(|entry| {
+            entry.triplet_status.first_seen > entry.triplet_status.last_seen
+                || entry.triplet_status.last_seen > self.now
+        }) {
+            return Err(anyhow!("Entry {} has inconsistent times", entry.key));
+        }
+        let counters = counters(&self.app);
+        if counters
+            .iter()
+            .zip(&self.counters)
+            .any(|(new, old)| new < old)
+        {
+            return Err(anyhow!(
+                "Statistics counters decreased from {:?} to {:?}",
+                self.counters,
+                counters
+            ));
+        }
+        self.counters = counters;
+        Ok(())
+    }
+}
+
+fn counters(app: &App) -> [u32; 6] {
+    let statistics = &app.statistics;
+    [
+        statistics.white,
+        statistics.grey,
+        statistics.black,
+        statistics.expired,
+        statistics.would_pass,
+        statistics.quarantine,
+    ]
+}
+
+/// All entries in their stored representation, which has a precision of seconds and only
+/// contains the hash without savetriplets.
+fn snapshot(app: &App) -> BTreeMap<u64, String> {
+    let savetriplets = app.config.data.savetriplets;
+    app.triplets
+        .iter()
+        .map(|(id, entry)| {
+            let hash = id.stable_hash();
+            let key = if savetriplets {
+                entry.key.to_string()
+            } else {
+                hash.to_string()
+            };
+            (
+                hash,
+                format!(
+                    "{} {} {} {}",
+                    key, entry.listing_status, entry.reason, entry.triplet_status
+                ),
+            )
+        })
+        .collect()
+}
+
+/// A temporary directory, removed with its contents when dropped.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    /// Create a new empty directory, named after the process so parallel tests don't collide.
+    pub fn new() -> Result<ScratchDir, anyhow::Error> {
+        static DIRS: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "greylistd-test-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&dir)?;
+        Ok(ScratchDir(dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A configuration with the socket `socket` and the data files `states` and `triplets` in a new
+/// scratch directory, loaded from the file `config` there like the daemon does.
+///
+/// `extra_ini` continues the `[data]` section and may add further sections, an empty
+/// `[timeouts]` section is added unless it has one.
+pub fn scratch_config(extra_ini: &str) -> Result<(Config, ScratchDir), anyhow::Error> {
+    let dir = ScratchDir::new()?;
+    let mut ini = format!(
+        "[socket]\npath = {}\nmode = 0600\n\n[data]\nstatefile = {}\ntripletfile = {}\n{}\n",
+        dir.path().join("socket").display(),
+        dir.path().join("states").display(),
+        dir.path().join("triplets").display(),
+        extra_ini
+    );
+    if !ini.lines().any(|line| line.trim() == "[timeouts]") {
+        ini.push_str("[timeouts]\n");
+    }
+    let path = dir.path().join("config");
+    fs::write(&path, ini)?;
+    Ok((Config::load(&path)?, dir))
+}
+
+/// A daemon running in a background thread, stopped when the client is dropped.
+pub struct Client {
+    socket: PathBuf,
+    stop: Sender<Stop>,
+    daemon: Option<JoinHandle<Result<Option<ExitReason>, anyhow::Error>>>,
+    /// Removed after the daemon was stopped in drop
+    _dir: ScratchDir,
+}
+
+/// Start a daemon with the default configuration and empty data on a socket in a new temporary
+/// directory.
+///
+/// The socket is bound before the daemon starts, like one passed by systemd socket activation.
+/// The data files are kept in the same directory, which is removed with the client.
+pub fn spawn_daemon() -> Result<Client, anyhow::Error> {
+    let (config, dir) = scratch_config("")?;
+    let socket = dir.path().join("socket");
+    let app = App::new(config)?;
+    let listener = UnixListener::bind(&socket)?;
+    let (stop, stop_signal) = bounded(1);
+    let daemon = std::thread::spawn(move || app.run(&[Listener::Unix(&listener)], stop_signal));
+    Ok(Client {
+        socket,
+        stop,
+        daemon: Some(daemon),
+        _dir: dir,
+    })
+}
+
+impl Client {
+    /// Send a request on a new connection and return the answer, like the greylist tool does.
+    ///
+    /// The client runs as the same user as the daemon, so `time=` can be used to step through
+    /// the retry delays.
+    pub fn request(&self, request: &str) -> Result<String, anyhow::Error> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.write_all(request.as_bytes())?;
+        stream.shutdown(Shutdown::Write)?;
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer)?;
+        Ok(answer)
+    }
+
+    /// The socket the daemon listens on, for clients speaking the protocol themselves.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Stop the daemon and return the error it failed with, if any.
+    ///
+    /// After a reload command the daemon has already stopped, as reloading is up to the caller.
+    pub fn stop(mut self) -> Result<(), anyhow::Error> {
+        self.stop_daemon()
+    }
+
+    fn stop_daemon(&mut self) -> Result<(), anyhow::Error> {
+        let Some(daemon) = self.daemon.take() else {
+            return Ok(());
+        };
+        // The daemon is gone already if it failed or handled a reload command
+        let _ = self.stop.send(Stop::Shutdown);
+        match daemon.join() {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(anyhow!("Daemon thread panicked")),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_daemon() {
+            eprintln!("Test daemon failed: {:?}", e);
+        }
+    }
+}
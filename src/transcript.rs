@@ -0,0 +1,137 @@
+//! Transcripts of the requests and answers of selected connections, written to a file when the
+//! connection is closed, to debug clients like Exim's readsocket.
+
+use crate::connection::Peer;
+use crate::App;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Most bytes recorded per connection, further requests and answers of a long connection are
+/// left out.
+const MAX_TRANSCRIPT_LENGTH: usize = 1024 * 1024;
+
+/// The recorded lines of a connection, requests prefixed with "> " and answers with "< ".
+pub(crate) struct Transcript {
+    path: PathBuf,
+    text: Vec<u8>,
+    /// Answer bytes written since the last request
+    answer: Vec<u8>,
+    /// Whether lines were left out as the transcript reached MAX_TRANSCRIPT_LENGTH
+    truncated: bool,
+}
+
+impl Transcript {
+    /// Record an escaped line with the prefix, unless the transcript is full.
+    fn record(&mut self, prefix: &[u8], line: &[u8]) {
+        if self.truncated {
+            return;
+        }
+        if self.text.len() + prefix.len() + line.len() * 4 + 1 > MAX_TRANSCRIPT_LENGTH {
+            self.text.extend_from_slice(b"... transcript truncated\n");
+            self.truncated = true;
+            return;
+        }
+        self.text.extend_from_slice(prefix);
+        self.text.extend(line.escape_ascii());
+        self.text.push(b'\n');
+    }
+
+    /// Record the answer written since the last request, line by line.
+    fn flush_answer(&mut self) {
+        let answer = std::mem::take(&mut self.answer);
+        for line in answer.split_inclusive(|b| *b == b'\n') {
+            self.record(b"< ", line);
+        }
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        self.flush_answer();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&self.text));
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to write transcript to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A writer that records what's written to it, with the requests it answers, if the
+/// connection was selected for a transcript.
+pub(crate) struct Transcribed<W: Write> {
+    inner: W,
+    transcript: Option<Transcript>,
+}
+
+impl<W: Write> Transcribed<W> {
+    pub(crate) fn new(inner: W, transcript: Option<Transcript>) -> Transcribed<W> {
+        Transcribed { inner, transcript }
+    }
+
+    /// Record a request as it was read, including its line terminator, so "\r" and missing
+    /// newlines are visible.
+    pub(crate) fn request(&mut self, line: &[u8]) {
+        let Some(transcript) = &mut self.transcript else {
+            return;
+        };
+        transcript.flush_answer();
+        transcript.record(b"> ", line);
+    }
+}
+
+impl<W: Write> Write for Transcribed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(transcript) = &mut self.transcript {
+            let room = MAX_TRANSCRIPT_LENGTH.saturating_sub(transcript.answer.len());
+            transcript
+                .answer
+                .extend_from_slice(&buf[..written.min(room)]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl App {
+    /// A transcript for the connection if transcripts are enabled and the client is selected,
+    /// counting the selected connections for sampling.
+    pub(crate) fn transcript(&mut self, peer: &Peer) -> Option<Transcript> {
+        let config = &self.config.transcript;
+        let path = config.path.clone()?;
+        let identity = peer.to_string();
+        if config
+            .peers
+            .as_ref()
+            .is_some_and(|peers| !peers.contains(&identity))
+        {
+            return None;
+        }
+        self.transcribed_connections += 1;
+        let sample = u64::from(config.sample.unwrap_or(1));
+        if (self.transcribed_connections - 1) % sample != 0 {
+            return None;
+        }
+        let header = format!("--- {} {}\n", self.format_time(SystemTime::now()), identity);
+        Some(Transcript {
+            path,
+            text: header.into_bytes(),
+            answer: Vec::new(),
+            truncated: false,
+        })
+    }
+}
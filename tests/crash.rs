@@ -0,0 +1,139 @@
+//! A daemon killed with SIGKILL at any point of a save loop restarts from its data files.
+
+use greylistd::testing::scratch_config;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUNDS: u64 = 20;
+
+const DAEMON: &str = env!("CARGO_BIN_EXE_greylistd");
+
+/// The daemon process, killed when dropped so a failed test doesn't leave it running.
+struct Daemon(Child);
+
+impl Daemon {
+    fn kill(&mut self) {
+        self.0.kill().unwrap();
+        self.0.wait().unwrap();
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn request(socket: &Path, request: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+fn save(socket: &Path) -> bool {
+    request(socket, "save\n")
+        .is_ok_and(|answer| answer.trim_end() == "greylistd data has been saved")
+}
+
+/// The number of entries `greylistd --check-config` loads, as the daemon does when it starts.
+fn check_config(config: &Path) -> usize {
+    let output = Command::new(DAEMON)
+        .arg("--config")
+        .arg(config)
+        .arg("--check-config")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Loaded "))
+        .and_then(|line| line.split(' ').next())
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_else(|| panic!("No entry count in {}", stdout))
+}
+
+#[test]
+fn restarts_after_kill_during_saves() {
+    let (_, dir) = scratch_config("").unwrap();
+    let config = dir.path().join("config");
+    let socket = Arc::new(dir.path().join("socket"));
+    // Number of the next new triplet, and of the entries the daemon starts with
+    let (mut next, mut loaded) = (0, 0);
+    for round in 0..ROUNDS {
+        // The killed daemon leaves its socket behind
+        let _ = fs::remove_file(&*socket);
+        let mut daemon = Daemon(
+            Command::new(DAEMON)
+                .arg("--config")
+                .arg(&config)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        // Saving is refused until the data is loaded
+        let started = Instant::now();
+        while !save(&socket) {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "Daemon didn't start"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Add new triplets and save after every 50, until the daemon is gone. Returns the
+        // number of new triplets and of those a save confirmed.
+        let worker = {
+            let socket = socket.clone();
+            thread::spawn(move || {
+                let (mut updated, mut saved) = (0, 0);
+                loop {
+                    for _ in 0..50 {
+                        let triplet =
+                            format!("192.0.2.1 s@example.com r{}@example.org\n", next + updated);
+                        match request(&socket, &triplet) {
+                            Ok(answer) if answer.starts_with("grey") => updated += 1,
+                            _ => return (updated, saved),
+                        }
+                    }
+                    if !save(&socket) {
+                        return (updated, saved);
+                    }
+                    saved = updated;
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(20 + round * 37 % 150));
+        daemon.kill();
+        let (updated, saved) = worker.join().unwrap();
+        next += updated;
+
+        let previous = loaded;
+        loaded = check_config(&config);
+        assert!(
+            loaded >= previous + saved,
+            "Round {}: loaded {} entries, {} were saved",
+            round,
+            loaded,
+            previous + saved
+        );
+    }
+    assert!(loaded > 0);
+}
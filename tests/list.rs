@@ -0,0 +1,140 @@
+use greylistd::testing::{spawn_daemon, Client};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The data column of the listed entries, in the order they are listed.
+fn listed(client: &Client, request: &str) -> Vec<String> {
+    client
+        .request(request)
+        .unwrap()
+        .lines()
+        .filter(|line| line.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|line| {
+            line.split_whitespace()
+                .skip(2)
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+/// Like [listed], for lists without sort order.
+fn listed_sorted(client: &Client, request: &str) -> Vec<String> {
+    let mut entries = listed(client, request);
+    entries.sort();
+    entries
+}
+
+fn add(client: &Client, request: &str) {
+    let answer = client.request(request).unwrap();
+    assert!(answer.starts_with("Added to"), "{}: {}", request, answer);
+}
+
+#[test]
+fn filters_ipv4_networks() {
+    let client = spawn_daemon().unwrap();
+    add(&client, "add 192.0.2.1 s@example.com a@example.org");
+    add(&client, "add 192.0.2.200 s@example.com b@example.org");
+    add(&client, "add 198.51.100.1 s@example.com c@example.org");
+    add(&client, "add 192.0.2.0/28");
+    add(&client, "add 192.0.0.0/16");
+
+    assert_eq!(
+        listed_sorted(&client, "list --white --ip 192.0.2.0/24"),
+        [
+            "192.0.2.0/28",
+            "192.0.2.1 s@example.com a@example.org",
+            "192.0.2.200 s@example.com b@example.org",
+        ]
+    );
+    assert_eq!(
+        listed(&client, "list --white --ip 192.0.2.128/25"),
+        ["192.0.2.200 s@example.com b@example.org"]
+    );
+    assert_eq!(
+        listed(&client, "list --white --ip 198.51.100.1"),
+        ["198.51.100.1 s@example.com c@example.org"]
+    );
+    assert!(listed(&client, "list --white --ip 203.0.113.0/24").is_empty());
+}
+
+#[test]
+fn filters_ipv6_networks() {
+    let client = spawn_daemon().unwrap();
+    add(&client, "add 2001:db8:1::1 s@example.com a@example.org");
+    add(&client, "add 2001:db8:1:ff::1 s@example.com b@example.org");
+    add(&client, "add 2001:db8:2::1 s@example.com c@example.org");
+    add(&client, "add --black 2001:db8:1:ff::/64");
+    add(&client, "add 192.0.2.1 s@example.com d@example.org");
+
+    assert_eq!(
+        listed_sorted(&client, "list --ip 2001:db8:1::/48"),
+        [
+            "2001:db8:1::1 s@example.com a@example.org",
+            "2001:db8:1:ff::/64",
+            "2001:db8:1:ff::1 s@example.com b@example.org",
+        ]
+    );
+    assert_eq!(
+        listed(&client, "list --black --ip 2001:db8::/32"),
+        ["2001:db8:1:ff::/64"]
+    );
+    assert_eq!(
+        listed(&client, "list --white --ip 2001:db8:2::1"),
+        ["2001:db8:2::1 s@example.com c@example.org"]
+    );
+    assert!(listed(&client, "list --ip 2001:db8:3::/48").is_empty());
+}
+
+#[test]
+fn sorts_and_limits() {
+    let client = spawn_daemon().unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let update = |time: u64, triplet: &str| {
+        let answer = client
+            .request(&format!("update time={} {}", time, triplet))
+            .unwrap();
+        assert_eq!(answer, "grey", "{}", triplet);
+    };
+    // Seen most often, but longest ago
+    for time in [now - 30, now - 29, now - 28] {
+        update(time, "192.0.2.1 s@example.com a@example.org");
+    }
+    update(now - 10, "198.51.100.1 s@example.com b@example.org");
+    update(now - 20, "203.0.113.1 s@example.com c@example.org");
+    update(now - 19, "203.0.113.1 s@example.com c@example.org");
+
+    assert_eq!(
+        listed(&client, "list --grey --sort count"),
+        [
+            "192.0.2.1 s@example.com a@example.org",
+            "203.0.113.1 s@example.com c@example.org",
+            "198.51.100.1 s@example.com b@example.org",
+        ]
+    );
+    assert_eq!(
+        listed(&client, "list --grey --sort last_seen"),
+        [
+            "198.51.100.1 s@example.com b@example.org",
+            "203.0.113.1 s@example.com c@example.org",
+            "192.0.2.1 s@example.com a@example.org",
+        ]
+    );
+    assert_eq!(
+        listed(&client, "list --grey --sort count --limit 2"),
+        [
+            "192.0.2.1 s@example.com a@example.org",
+            "203.0.113.1 s@example.com c@example.org",
+        ]
+    );
+    assert_eq!(
+        listed(&client, "list --grey --sort last_seen --limit 1"),
+        ["198.51.100.1 s@example.com b@example.org"]
+    );
+    assert_eq!(
+        listed(&client, "list --grey --ip 203.0.113.0/24 --limit 5"),
+        ["203.0.113.1 s@example.com c@example.org"]
+    );
+}
@@ -0,0 +1,41 @@
+use greylistd::testing::spawn_daemon;
+
+fn policy_request(client_address: &str, sender: &str) -> String {
+    format!(
+        "request=smtpd_access_policy\nclient_address={}\nsender={}\nrecipient=user@example.org\n\n",
+        client_address, sender
+    )
+}
+
+#[test]
+fn greylists_new_triplet() {
+    let client = spawn_daemon().unwrap();
+    let answer = client
+        .request(&policy_request("192.0.2.1", "sender@example.com"))
+        .unwrap();
+    assert_eq!(
+        answer,
+        "action=DEFER_IF_PERMIT Greylisted, try again later\n\n"
+    );
+}
+
+#[test]
+fn greylists_sender_without_domain() {
+    let client = spawn_daemon().unwrap();
+    let answer = client.request(&policy_request("192.0.2.1", "foo")).unwrap();
+    assert!(answer.starts_with("action=DEFER_IF_PERMIT"), "{}", answer);
+}
+
+#[test]
+fn invalid_request_is_not_accepted() {
+    let client = spawn_daemon().unwrap();
+    for request in [
+        policy_request("not-an-ip", "sender@example.com"),
+        policy_request("192.0.2.1", "with space@example.com"),
+        "request=smtpd_access_policy\nclient_address=192.0.2.1\nsender=s@example.com\n\n"
+            .to_string(),
+    ] {
+        let answer = client.request(&request).unwrap();
+        assert!(answer.starts_with("action=DEFER_IF_PERMIT"), "{}", answer);
+    }
+}
@@ -0,0 +1,67 @@
+use greylistd::config::Config;
+use greylistd::testing::{scratch_config, Event, ScratchDir, Simulation};
+use proptest::prelude::*;
+
+/// A configuration with short timeouts, so the events reach them.
+fn config(savetriplets: bool) -> (Config, ScratchDir) {
+    scratch_config(&format!(
+        "savetriplets = {}\n\n[timeouts]\nretryMin = 60\nretryMax = 3600\nexpire = 86400\n",
+        savetriplets
+    ))
+    .unwrap()
+}
+
+/// Triplets from a few senders and recipients, so updates hit existing entries.
+fn triplet() -> impl Strategy<Value = String> {
+    (
+        prop::sample::select(vec![
+            "192.0.2.1",
+            "192.0.2.2",
+            "198.51.100.7",
+            "2001:db8::1",
+        ]),
+        prop::sample::select(vec!["", "sender@example.com ", "foo "]),
+        prop::sample::select(vec!["a@example.org", "b@example.org"]),
+    )
+        .prop_map(|(ip, sender, recipient)| format!("{} {}{}", ip, sender, recipient))
+}
+
+fn event() -> impl Strategy<Value = Event> {
+    prop_oneof![
+        4 => (0u64..7200).prop_map(Event::Advance),
+        1 => (0u64..200_000).prop_map(Event::Advance),
+        6 => triplet().prop_map(Event::Update),
+        2 => triplet().prop_map(Event::Check),
+        2 => Just(Event::Prune),
+        1 => Just(Event::Save),
+        1 => Just(Event::Reload),
+    ]
+}
+
+fn run(savetriplets: bool, events: &[Event]) -> Result<(), TestCaseError> {
+    let (config, _dir) = config(savetriplets);
+    let mut simulation = Simulation::new(config, 1_700_000_000);
+    events.iter().try_for_each(|event| {
+        simulation
+            .apply(event)
+            .map_err(|e| TestCaseError::fail(format!("{:?}: {}", event, e)))
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        cases: 64,
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn invariants_hold(events in prop::collection::vec(event(), 1..60)) {
+        run(true, &events)?;
+    }
+
+    #[test]
+    fn invariants_hold_without_triplets(events in prop::collection::vec(event(), 1..60)) {
+        run(false, &events)?;
+    }
+}